@@ -0,0 +1,60 @@
+//! Times a single large simulation: the SEIR test config with `n` susceptibles per ageclass,
+//! or, if `n_areas` is given, with each ageclass split across a ring of `n_areas` small areas
+//! that each have contacts only with themselves and their two neighbours.
+//!
+//! ```sh
+//! cargo bench --bench simulate -- [<n> [<rng_seed> [<n_areas>]]]
+//! ```
+
+use sirtools::config::*;
+use sirtools::strata::*;
+use sirtools::util::*;
+
+use std::time::Instant;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).filter(|arg| !arg.starts_with("--")).collect();
+    let n: usize = args.first().map(|n| n.parse().unwrap()).unwrap_or(1_000_000);
+    let rng_seed: u64 = args.get(1).map(|seed| seed.parse().unwrap()).unwrap_or(1);
+    let n_areas: Option<usize> = args.get(2).map(|n_areas| n_areas.parse().unwrap());
+    
+    let mut config = Config::from_json(&read_data_from_file("tests/sirsim-seir.json").unwrap()).unwrap();
+    config.rng_seed = Some(rng_seed);
+    config.t_final = None;
+    config.contact_parameters.truncate(1);
+    config.contact_parameters[0].t_end = None;
+    config.initial_counts.insert("S".into(), vec![n; config.n_ageclasses]);
+    config.initial_counts.insert("E".into(), vec![10; config.n_ageclasses]);
+    
+    if let Some(n_areas) = n_areas {
+        let n_ages = config.n_ageclasses;
+        let area_contacts: Vec<Vec<f64>> = (0..n_areas).map(|a| {
+            (0..n_areas).map(|b| {
+                if a == b { 1.0 } else if (a + 1) % n_areas == b || (b + 1) % n_areas == a { 0.25 } else { 0.0 }
+            }).collect()
+        }).collect();
+        config.strata = Some(vec![
+            StratumDimension { name: "age".into(), labels: (0..n_ages).map(|a| format!("age{}", a)).collect() },
+            StratumDimension { name: "area".into(), labels: (0..n_areas).map(|a| format!("area{}", a)).collect() },
+        ]);
+        config.n_ageclasses = n_ages * n_areas;
+        let cp = &mut config.contact_parameters[0];
+        cp.C_factors = Some(vec![cp.C.take().unwrap(), area_contacts]);
+        config.initial_counts.insert("S".into(), vec![n / n_areas; config.n_ageclasses]);
+        config.initial_counts.insert("E".into(), (0..config.n_ageclasses).map(
+            |group| if group % n_areas == 0 { 10 } else { 0 }
+        ).collect());
+        config.infected_states[1].probabilities = Some(
+            (0..config.n_ageclasses).map(|group| vec![0.99 - 0.09 * (group / n_areas) as f64, 0.01 + 0.09 * (group / n_areas) as f64]).collect()
+        );
+    }
+    
+    let start = Instant::now();
+    let mut sim = SimulationBuilder::new(config).build().unwrap();
+    while !sim.simulate(sim.t + 1.0) {}
+    let elapsed = start.elapsed().as_secs_f64();
+    
+    println!("n = {}, rng_seed = {}, n_areas = {:?}", n, rng_seed, n_areas);
+    println!("{} infections, {} transitions, t = {}", sim.n_infections(), sim.n_transitions(), sim.t);
+    println!("elapsed time: {:.3} s ({:.0} events/s)", elapsed, (sim.n_infections() + sim.n_transitions()) as f64 / elapsed);
+}
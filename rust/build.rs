@@ -0,0 +1,21 @@
+use std::process::Command;
+
+/// Makes the git revision available as `SIRTOOLS_GIT_REVISION` for provenance records.
+fn main() {
+    let output = Command::new("git").args(["rev-parse", "HEAD"]).output();
+    if let Ok(output) = output {
+        if output.status.success() {
+            let revision = String::from_utf8_lossy(&output.stdout);
+            println!("cargo:rustc-env=SIRTOOLS_GIT_REVISION={}", revision.trim());
+        }
+    }
+    
+    if let Ok(output) = Command::new("git").args(["rev-parse", "--git-dir"]).output() {
+        let git_dir = String::from_utf8_lossy(&output.stdout);
+        let git_dir = git_dir.trim();
+        if !git_dir.is_empty() {
+            println!("cargo:rerun-if-changed={}/HEAD", git_dir);
+            println!("cargo:rerun-if-changed={}/refs", git_dir);
+        }
+    }
+}
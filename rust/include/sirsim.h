@@ -0,0 +1,64 @@
+/*
+ * C interface to the sirtools individual-based simulator (libsirtools).
+ *
+ * Functions returning int32_t return 0 on success, or an error code:
+ *   1  simulation failed (e.g. no seed established an outbreak)
+ *   2  invalid argument (including null pointers and unknown states)
+ *   3  invalid input (e.g. invalid config)
+ *   4  output error
+ *   5  internal error (a panic caught at the boundary)
+ * The error itself is then available as JSON, as printed by sirsim, from
+ * sirsim_last_error().
+ *
+ * Threads: a SirsimSimulation is not thread-safe and must be used and freed
+ * only on the thread that created it (checkpoints included). Different
+ * threads may each run their own simulations. The last error is stored per
+ * thread, so sirsim_last_error() must be called on the thread whose call
+ * failed.
+ */
+
+#ifndef SIRSIM_H
+#define SIRSIM_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct SirsimSimulation SirsimSimulation;
+
+/* JSON for the last error on the calling thread; valid until that thread's next
+ * failing call. */
+const char *sirsim_last_error(void);
+
+const char *sirsim_version(void);
+
+/* Creates a simulation from a JSON config; free with sirsim_simulation_free. */
+int32_t sirsim_simulation_new(const char *config_json, SirsimSimulation **out);
+
+/* Advances to time t; sets *done (if done is not null) to 1 if no events remain. */
+int32_t sirsim_simulation_advance(SirsimSimulation *sim, double t, int32_t *done);
+
+int32_t sirsim_simulation_time(const SirsimSimulation *sim, double *t);
+
+/* Current count in a state, in one ageclass (from 0), or summed if ageclass < 0. */
+int32_t sirsim_simulation_count(
+    const SirsimSimulation *sim, const char *state, int64_t ageclass, uint64_t *count
+);
+
+/* Records accumulated so far as JSON; free with sirsim_string_free. */
+int32_t sirsim_simulation_records(const SirsimSimulation *sim, char **json);
+
+/* Exact copy of a simulation, which continues as the original would. */
+int32_t sirsim_simulation_checkpoint(const SirsimSimulation *sim, SirsimSimulation **out);
+
+void sirsim_simulation_free(SirsimSimulation *sim);
+
+void sirsim_string_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
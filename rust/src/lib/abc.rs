@@ -0,0 +1,532 @@
+use crate::config::*;
+use crate::errors::*;
+use crate::ibm::Simulation;
+
+use rand::Rng;
+use rand_distr::{Distribution, Normal};
+use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use rayon::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_TOLERANCE_QUANTILE: f64 = 0.5;
+const DEFAULT_MAX_SIMULATIONS_FACTOR: usize = 100;
+
+/// Specification of an ABC-SMC fit: priors over config fields (by JSON path, as in
+/// `Config::set`), observed time series, and the sequence of tolerances.
+///
+/// Generation 0 samples from the prior with tolerance `initial_tolerance` (default: accept
+/// everything); each later generation's tolerance is the `tolerance_quantile` quantile of
+/// the previous generation's distances.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct AbcSpec {
+    pub priors: Vec<Prior>,
+    pub observations: Vec<ObservedSeries>,
+    pub distance: Option<Distance>,
+    
+    pub n_particles: usize,
+    pub n_generations: usize,
+    pub initial_tolerance: Option<f64>,
+    pub tolerance_quantile: Option<f64>,
+    
+    /// Simulations allowed per generation before giving up (default 100 × `n_particles`).
+    pub max_simulations_per_generation: Option<usize>,
+    pub seed: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Prior {
+    pub path: String,
+    pub distribution: PriorDistribution,
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type")]
+pub enum PriorDistribution {
+    Uniform { min: f64, max: f64 },
+    Normal { mean: f64, sd: f64 },
+    LogNormal { meanlog: f64, sdlog: f64 },
+}
+
+/// Observed values of a summary statistic at a set of times.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ObservedSeries {
+    pub statistic: SummaryStatistic,
+    pub times: Vec<f64>,
+    pub values: Vec<f64>,
+    
+    /// Multiplies this series' contribution to the distance (default 1).
+    pub weight: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type")]
+pub enum SummaryStatistic {
+    /// Number of individuals in any of `states` at each time, in one ageclass
+    /// (numbered from 1, as in output DBs) or summed over all of them.
+    Count { states: Vec<String>, ageclass: Option<usize> },
+    
+    /// New infections in the unit time interval ending at each time.
+    Incidence,
+    
+    /// Infections (excluding initial infecteds) up to each time.
+    CumulativeInfections,
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, JsonSchema)]
+pub enum Distance {
+    Euclidean,
+    Manhattan,
+}
+
+impl PriorDistribution {
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> f64 {
+        match *self {
+            PriorDistribution::Uniform { min, max } => rng.gen_range(min, max),
+            PriorDistribution::Normal { mean, sd } => Normal::new(mean, sd).unwrap().sample(rng),
+            PriorDistribution::LogNormal { meanlog, sdlog } => {
+                Normal::new(meanlog, sdlog).unwrap().sample(rng).exp()
+            },
+        }
+    }
+    
+    pub fn density(&self, x: f64) -> f64 {
+        match *self {
+            PriorDistribution::Uniform { min, max } => {
+                if x >= min && x <= max { 1.0 / (max - min) } else { 0.0 }
+            },
+            PriorDistribution::Normal { mean, sd } => normal_density(x, mean, sd),
+            PriorDistribution::LogNormal { meanlog, sdlog } => {
+                if x > 0.0 { normal_density(x.ln(), meanlog, sdlog) / x } else { 0.0 }
+            },
+        }
+    }
+    
+    fn check(&self) -> Result<(), String> {
+        let valid = match *self {
+            PriorDistribution::Uniform { min, max } => min < max,
+            PriorDistribution::Normal { sd, .. } => sd > 0.0,
+            PriorDistribution::LogNormal { sdlog, .. } => sdlog > 0.0,
+        };
+        if valid { Ok(()) } else { Err(format!("invalid prior distribution: {:?}", self)) }
+    }
+}
+
+fn normal_density(x: f64, mean: f64, sd: f64) -> f64 {
+    let z = (x - mean) / sd;
+    (-0.5 * z * z).exp() / (sd * (2.0 * std::f64::consts::PI).sqrt())
+}
+
+impl SummaryStatistic {
+    /// Evaluates the statistic at `times` from the output of a simulation run.
+    ///
+    /// Runs that end early (no events remaining) keep their final state.
+    pub fn evaluate(&self, output: &SimulationOutput, times: &[f64]) -> Vec<f64> {
+        match self {
+            SummaryStatistic::Count { states, ageclass } => {
+                // Totals at each recorded time
+                let mut totals: Vec<(f64, f64)> = Vec::new();
+                for record in &output.counts {
+                    if totals.last().map(|(t, _)| *t != record.time).unwrap_or(true) {
+                        totals.push((record.time, 0.0));
+                    }
+                    let in_ageclass = ageclass.map(|a| a == record.ageclass + 1).unwrap_or(true);
+                    if in_ageclass && states.contains(&record.state) {
+                        totals.last_mut().unwrap().1 += record.count as f64;
+                    }
+                }
+                times.iter().map(|&t| {
+                    let i = totals.iter().take_while(|(time, _)| *time <= t).count();
+                    if i == 0 { 0.0 } else { totals[i - 1].1 }
+                }).collect()
+            },
+            SummaryStatistic::Incidence => {
+                times.iter().map(|&t| {
+                    output.rt.iter().filter(|r| r.time_discrete as f64 == t.ceil()).map(
+                        |r| r.n_primary as f64
+                    ).sum()
+                }).collect()
+            },
+            SummaryStatistic::CumulativeInfections => {
+                times.iter().map(|&t| {
+                    output.rt.iter().filter(|r| r.time_discrete as f64 <= t).map(
+                        |r| r.n_primary as f64
+                    ).sum()
+                }).collect()
+            },
+        }
+    }
+    
+    /// Checks that the states and ageclass referred to exist in `config`.
+    pub(crate) fn check(&self, config: &Config) -> Result<(), String> {
+        if let SummaryStatistic::Count { states, ageclass } = self {
+            for state in states {
+                let known = *state == config.susceptible_state || config.final_states.contains(state) ||
+                    config.infected_states.iter().any(|s| s.name == *state);
+                if !known {
+                    return Err(format!("unknown state in observations: {}", state));
+                }
+            }
+            if let Some(ageclass) = ageclass {
+                if *ageclass == 0 || *ageclass > config.n_ageclasses {
+                    return Err(format!("ageclass out of range in observations: {}", ageclass));
+                }
+            }
+        }
+        Ok(())
+    }
+    
+    /// Evaluates the statistic at the current time of a running simulation.
+    pub fn evaluate_current(&self, sim: &Simulation) -> f64 {
+        match self {
+            SummaryStatistic::Count { states, ageclass } => {
+                sim.states().iter().filter(|state| states.contains(&state.name)).map(|state| {
+                    match ageclass {
+                        Some(ageclass) => sim.counts().get(state.id, ageclass - 1),
+                        None => sim.counts().total_for_state(state.id),
+                    }
+                }).sum::<usize>() as f64
+            },
+            SummaryStatistic::Incidence => {
+                let t_discrete = sim.t.ceil() as i64;
+                sim.rt_records().iter().filter(|r| r.time_discrete == t_discrete).map(
+                    |r| r.n_primary as f64
+                ).sum()
+            },
+            SummaryStatistic::CumulativeInfections => sim.n_infections() as f64,
+        }
+    }
+}
+
+/// A weighted sample from the approximate posterior.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Particle {
+    pub parameters: Vec<f64>,
+    pub weight: f64,
+    pub distance: f64,
+    pub rng_seed: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenerationDiagnostics {
+    pub generation: usize,
+    pub tolerance: f64,
+    pub n_simulations: usize,
+    pub acceptance_rate: f64,
+    pub effective_sample_size: f64,
+    pub parameter_means: Vec<f64>,
+    pub parameter_sds: Vec<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AbcResult {
+    pub parameter_paths: Vec<String>,
+    
+    /// Particles from the last completed generation.
+    pub particles: Vec<Particle>,
+    pub generations: Vec<GenerationDiagnostics>,
+    
+    /// Set if a generation failed to accept `n_particles` within its simulation budget;
+    /// `particles` are then those of the previous generation.
+    pub budget_exhausted: bool,
+}
+
+/// Runs ABC-SMC (Beaumont et al. 2009) with adaptive tolerances, simulating in memory
+/// on `n_threads` threads (default: one per CPU).
+///
+/// Particles are perturbed with a Gaussian kernel with twice the weighted variance of the
+/// previous generation. Proposals are seeded in a fixed order and accepted in that order,
+/// so results do not depend on the number of threads. `progress` is called with the
+/// diagnostics of each generation as it completes.
+pub fn run_abc(
+    config: &Config, spec: &AbcSpec, n_threads: Option<usize>,
+    progress: &mut dyn FnMut(&GenerationDiagnostics)
+) -> Result<AbcResult, Error> {
+    check_spec(config, spec)?;
+    
+    // Only simulate as far as the last observation
+    let t_max = spec.observations.iter().flat_map(|o| o.times.iter().copied()).fold(0.0, f64::max);
+    let mut config = config.clone();
+    config.t_final = Some(config.t_final.map(|t| t.min(t_max)).unwrap_or(t_max));
+    config.record_all_events = false;
+    
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(n_threads.unwrap_or(0)).build().map_err(
+        |e| Error::InvalidArgument(format!("{}", e))
+    )?;
+    let batch_size = 4 * pool.current_num_threads();
+    let max_simulations = spec.max_simulations_per_generation.unwrap_or(
+        DEFAULT_MAX_SIMULATIONS_FACTOR * spec.n_particles
+    );
+    let mut master_rng = Xoshiro256PlusPlus::seed_from_u64(spec.seed.unwrap_or(0));
+    
+    let mut particles: Vec<Particle> = Vec::new();
+    let mut generations = Vec::new();
+    let mut tolerance = spec.initial_tolerance.unwrap_or(f64::INFINITY);
+    
+    for generation in 0..spec.n_generations {
+        let kernel_sds = if generation == 0 { Vec::new() } else {
+            weighted_sds(&particles).iter().map(|sd| sd * 2f64.sqrt()).collect()
+        };
+        
+        // Each generation has its own seed stream, since batches may overshoot
+        let mut seed_rng = Xoshiro256PlusPlus::seed_from_u64(master_rng.gen());
+        let mut accepted = Vec::new();
+        let mut n_simulations = 0;
+        while accepted.len() < spec.n_particles && n_simulations < max_simulations {
+            let seeds: Vec<u64> = (0..batch_size.min(max_simulations - n_simulations)).map(
+                |_| seed_rng.gen()
+            ).collect();
+            let proposals = pool.install(|| seeds.par_iter().map(|&seed| {
+                let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed);
+                let parameters = if generation == 0 {
+                    spec.priors.iter().map(|prior| prior.distribution.sample(&mut rng)).collect()
+                } else {
+                    propose(&particles, &kernel_sds, &mut rng)
+                };
+                if prior_density(spec, &parameters) == 0.0 {
+                    return Ok(None);
+                }
+                let rng_seed: u64 = rng.gen();
+                let distance = simulate_distance(&config, spec, &parameters, rng_seed)?;
+                Ok(Some(Particle { parameters, weight: 0.0, distance, rng_seed }))
+            }).collect::<Result<Vec<_>, Error>>())?;
+            
+            for proposal in proposals {
+                if accepted.len() == spec.n_particles {
+                    break;
+                }
+                n_simulations += 1;
+                if let Some(particle) = proposal {
+                    if particle.distance <= tolerance {
+                        accepted.push(particle);
+                    }
+                }
+            }
+        }
+        if accepted.len() < spec.n_particles {
+            return Ok(AbcResult {
+                parameter_paths: spec.priors.iter().map(|p| p.path.clone()).collect(),
+                particles, generations, budget_exhausted: true,
+            });
+        }
+        
+        // Importance weights relative to the previous generation's proposal distribution
+        for particle in &mut accepted {
+            let prior = prior_density(spec, &particle.parameters);
+            particle.weight = if generation == 0 { 1.0 } else {
+                prior / particles.iter().map(
+                    |p| p.weight * kernel_density(&p.parameters, &particle.parameters, &kernel_sds)
+                ).sum::<f64>()
+            };
+        }
+        let total_weight: f64 = accepted.iter().map(|p| p.weight).sum();
+        for particle in &mut accepted {
+            particle.weight /= total_weight;
+        }
+        particles = accepted;
+        
+        generations.push(GenerationDiagnostics {
+            generation,
+            tolerance,
+            n_simulations,
+            acceptance_rate: particles.len() as f64 / n_simulations as f64,
+            effective_sample_size: 1.0 / particles.iter().map(|p| p.weight * p.weight).sum::<f64>(),
+            parameter_means: weighted_means(&particles),
+            parameter_sds: weighted_sds(&particles),
+        });
+        progress(generations.last().unwrap());
+        
+        let mut distances: Vec<f64> = particles.iter().map(|p| p.distance).collect();
+        distances.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let quantile = spec.tolerance_quantile.unwrap_or(DEFAULT_TOLERANCE_QUANTILE);
+        tolerance = distances[((distances.len() - 1) as f64 * quantile).round() as usize];
+    }
+    
+    Ok(AbcResult {
+        parameter_paths: spec.priors.iter().map(|p| p.path.clone()).collect(),
+        particles, generations, budget_exhausted: false,
+    })
+}
+
+fn check_spec(config: &Config, spec: &AbcSpec) -> Result<(), Error> {
+    let invalid = |message: String| Err(Error::InvalidArgument(message));
+    if spec.n_particles == 0 || spec.n_generations == 0 {
+        return invalid("n_particles and n_generations must be positive".into());
+    }
+    if spec.priors.is_empty() {
+        return invalid("no priors given".into());
+    }
+    if spec.observations.is_empty() {
+        return invalid("no observations given".into());
+    }
+    if let Some(q) = spec.tolerance_quantile {
+        if !(q > 0.0 && q <= 1.0) {
+            return invalid(format!("tolerance_quantile must be in (0, 1]: {}", q));
+        }
+    }
+    for prior in &spec.priors {
+        prior.distribution.check().or_else(invalid)?;
+    }
+    for observation in &spec.observations {
+        if observation.times.len() != observation.values.len() {
+            return invalid("observation times and values differ in length".into());
+        }
+        observation.statistic.check(config).or_else(invalid)?;
+    }
+    
+    // Check that prior paths can be set, using a sample from each prior
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+    let mut config = config.clone();
+    for prior in &spec.priors {
+        config.set(&prior.path, prior.distribution.sample(&mut rng).into())?;
+    }
+    Ok(())
+}
+
+fn prior_density(spec: &AbcSpec, parameters: &[f64]) -> f64 {
+    spec.priors.iter().zip(parameters).map(|(prior, &x)| prior.distribution.density(x)).product()
+}
+
+fn propose<R: Rng>(particles: &[Particle], kernel_sds: &[f64], rng: &mut R) -> Vec<f64> {
+    // Choose a particle by weight, then perturb it
+    let u: f64 = rng.gen();
+    let mut cumulative = 0.0;
+    let mut chosen = &particles[particles.len() - 1];
+    for particle in particles {
+        cumulative += particle.weight;
+        if u < cumulative {
+            chosen = particle;
+            break;
+        }
+    }
+    chosen.parameters.iter().zip(kernel_sds).map(|(&x, &sd)| {
+        if sd > 0.0 { Normal::new(x, sd).unwrap().sample(rng) } else { x }
+    }).collect()
+}
+
+fn kernel_density(from: &[f64], to: &[f64], kernel_sds: &[f64]) -> f64 {
+    from.iter().zip(to).zip(kernel_sds).map(|((&x, &y), &sd)| {
+        if sd > 0.0 { normal_density(y, x, sd) } else if x == y { 1.0 } else { 0.0 }
+    }).product()
+}
+
+/// Runs the simulation for one set of parameters and computes its distance to the observations.
+fn simulate_distance(
+    config: &Config, spec: &AbcSpec, parameters: &[f64], rng_seed: u64
+) -> Result<f64, Error> {
+    let mut config = config.clone();
+    for (prior, &x) in spec.priors.iter().zip(parameters) {
+        config.set(&prior.path, x.into())?;
+    }
+    let output = SimulationBuilder::new(config).rng_seed(rng_seed).run()?;
+    
+    let distance = spec.distance.unwrap_or(Distance::Euclidean);
+    let total: f64 = spec.observations.iter().map(|observation| {
+        let simulated = observation.statistic.evaluate(&output, &observation.times);
+        let weight = observation.weight.unwrap_or(1.0);
+        simulated.iter().zip(&observation.values).map(|(x, y)| {
+            weight * match distance {
+                Distance::Euclidean => (x - y) * (x - y),
+                Distance::Manhattan => (x - y).abs(),
+            }
+        }).sum::<f64>()
+    }).sum();
+    Ok(match distance {
+        Distance::Euclidean => total.sqrt(),
+        Distance::Manhattan => total,
+    })
+}
+
+fn weighted_means(particles: &[Particle]) -> Vec<f64> {
+    let n_parameters = particles[0].parameters.len();
+    (0..n_parameters).map(|k| particles.iter().map(|p| p.weight * p.parameters[k]).sum()).collect()
+}
+
+fn weighted_sds(particles: &[Particle]) -> Vec<f64> {
+    weighted_means(particles).iter().enumerate().map(|(k, mean)| {
+        particles.iter().map(|p| p.weight * (p.parameters[k] - mean).powi(2)).sum::<f64>().sqrt()
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::abc::*;
+    use crate::fixtures::*;
+    
+    #[test]
+    fn test_prior_density() {
+        let uniform = PriorDistribution::Uniform { min: 1.0, max: 3.0 };
+        assert_eq!(uniform.density(2.0), 0.5);
+        assert_eq!(uniform.density(4.0), 0.0);
+        let lognormal = PriorDistribution::LogNormal { meanlog: 0.0, sdlog: 1.0 };
+        assert!((lognormal.density(1.0) - normal_density(0.0, 0.0, 1.0)).abs() < 1e-12);
+        assert_eq!(lognormal.density(-1.0), 0.0);
+    }
+    
+    #[test]
+    fn test_abc_recovers_beta() {
+        let config = read_test_config();
+        let times: Vec<f64> = (1..=30).map(|t| t as f64).collect();
+        let spec_for_seed = |rng_seed: u64| {
+            let observed = SimulationBuilder::new(config.clone()).rng_seed(rng_seed).t_final(30.0).run().unwrap();
+            let statistic = SummaryStatistic::CumulativeInfections;
+            let values = statistic.evaluate(&observed, &times);
+            AbcSpec {
+                priors: vec![Prior {
+                    path: "contact_parameters[0].beta".into(),
+                    distribution: PriorDistribution::Uniform { min: 0.05, max: 1.0 },
+                }],
+                observations: vec![ObservedSeries { statistic, times: times.clone(), values, weight: None }],
+                distance: None,
+                n_particles: 50,
+                n_generations: 3,
+                initial_tolerance: None,
+                tolerance_quantile: None,
+                max_simulations_per_generation: None,
+                seed: Some(rng_seed),
+            }
+        };
+        
+        // Central 95% of the posterior for beta
+        let credible_interval = |particles: &[Particle]| {
+            let mut particles = particles.to_vec();
+            particles.sort_by(|a, b| a.parameters[0].partial_cmp(&b.parameters[0]).unwrap());
+            let quantile = |q: f64| {
+                let mut cumulative_weight = 0.0;
+                particles.iter().find(|p| {
+                    cumulative_weight += p.weight;
+                    cumulative_weight >= q
+                }).unwrap_or_else(|| particles.last().unwrap()).parameters[0]
+            };
+            (quantile(0.025), quantile(0.975))
+        };
+        
+        // Observed data from the config's seed and from others, since any one outbreak
+        // may be unusually small or large: the true beta (0.3) should be credible for
+        // nearly all of them, with posteriors much narrower than the prior
+        let mut n_covered = 0;
+        for rng_seed in config.rng_seed.unwrap()..config.rng_seed.unwrap() + 4 {
+            let spec = spec_for_seed(rng_seed);
+            let result = run_abc(&config, &spec, Some(2), &mut |_| {}).unwrap();
+            assert!(!result.budget_exhausted);
+            assert_eq!(result.generations.len(), 3);
+            assert!(result.generations[2].tolerance < result.generations[1].tolerance);
+            
+            let (lower, upper) = credible_interval(&result.particles);
+            assert!(upper - lower < 0.5, "95% interval for beta = ({}, {})", lower, upper);
+            if lower < 0.3 && 0.3 < upper {
+                n_covered += 1;
+            }
+            
+            // Independent of the number of threads
+            if rng_seed == config.rng_seed.unwrap() {
+                assert_eq!(
+                    run_abc(&config, &spec, Some(1), &mut |_| {}).unwrap().particles[0].parameters,
+                    result.particles[0].parameters
+                );
+            }
+        }
+        assert!(n_covered >= 3, "true beta credible for {} of 4 observed series", n_covered);
+    }
+}
@@ -0,0 +1,230 @@
+use crate::config::*;
+use crate::errors::*;
+
+/// Labels for ageclasses with the given lower bounds, with upper bounds exclusive:
+/// for integer ages, `0-4`, `5-14`, ..., `75+`.
+pub fn labels_from_lower_bounds(lower_bounds: &[f64]) -> Vec<String> {
+    (0..lower_bounds.len()).map(|i| {
+        let lower = lower_bounds[i];
+        match lower_bounds.get(i + 1) {
+            None => format!("{}+", lower),
+            Some(&upper) if lower.fract() == 0.0 && upper.fract() == 0.0 => format!("{}-{}", lower, upper - 1.0),
+            Some(&upper) => format!("{}-{}", lower, upper),
+        }
+    }).collect()
+}
+
+impl Config {
+    /// Labels of the ageclasses: `ageclass_labels` if given, or else labels derived
+    /// from `ageclass_lower_bounds`.
+    pub fn ageclass_labels(&self) -> Option<Vec<String>> {
+        match (&self.ageclass_labels, &self.ageclass_lower_bounds) {
+            (Some(labels), _) => Some(labels.clone()),
+            (None, Some(lower_bounds)) => Some(labels_from_lower_bounds(lower_bounds)),
+            (None, None) => None,
+        }
+    }
+    
+    /// Lower and upper bounds of the age range of each ageclass, if `ageclass_lower_bounds`
+    /// is given; the last ageclass has no upper bound.
+    pub fn ageclass_bounds(&self) -> Option<Vec<(f64, Option<f64>)>> {
+        self.ageclass_lower_bounds.as_ref().map(|lower_bounds| {
+            lower_bounds.iter().enumerate().map(
+                |(i, lower)| (*lower, lower_bounds.get(i + 1).copied())
+            ).collect()
+        })
+    }
+}
+
+/// Replaces arrays given as objects keyed by ageclass label (see `Config::ageclass_labels`)
+/// with arrays in ageclass order: entries of `initial_counts`, where ageclasses not listed
+/// have no individuals; contact matrices `C` of contact parameters and settings, whose rows
+/// and entries may each be keyed, with no contacts for ageclasses not listed; and
+/// `probabilities` of infected states (including those by attribute and by period) and
+/// per-ageclass `setting_multipliers`, which must list every ageclass.
+pub(crate) fn resolve_ageclass_labels(json: &mut serde_json::Value) -> Result<(), Error> {
+    let labels = json_ageclass_labels(json);
+    let mut errors = Vec::new();
+    
+    if let Some(initial_counts) = json.get_mut("initial_counts").and_then(|v| v.as_object_mut()) {
+        for (state, counts) in initial_counts.iter_mut() {
+            let path = format!("$.initial_counts.{}", state);
+            resolve(&path, counts, labels.as_deref(), Some(&0.into()), &mut errors);
+        }
+    }
+    for key in &["contact_settings", "contact_parameters"] {
+        if let Some(items) = json.get_mut(*key).and_then(|v| v.as_array_mut()) {
+            for (i, item) in items.iter_mut().enumerate() {
+                if let Some(matrix) = item.get_mut("C") {
+                    let path = format!("$.{}[{}].C", key, i);
+                    resolve_matrix(&path, matrix, labels.as_deref(), &mut errors);
+                }
+                if let Some(multipliers) = item.get_mut("setting_multipliers").and_then(|v| v.as_object_mut()) {
+                    for (setting, multiplier) in multipliers.iter_mut() {
+                        let path = format!("$.{}[{}].setting_multipliers.{}", key, i, setting);
+                        resolve(&path, multiplier, labels.as_deref(), None, &mut errors);
+                    }
+                }
+            }
+        }
+    }
+    if let Some(states) = json.get_mut("infected_states").and_then(|v| v.as_array_mut()) {
+        for (i, state) in states.iter_mut().enumerate() {
+            if let Some(probabilities) = state.get_mut("probabilities") {
+                let path = format!("$.infected_states[{}].probabilities", i);
+                resolve(&path, probabilities, labels.as_deref(), None, &mut errors);
+            }
+            let by_attribute = state.get_mut("probabilities_by_attribute").and_then(|p| p.get_mut("probabilities"));
+            if let Some(by_value) = by_attribute.and_then(|p| p.as_object_mut()) {
+                for (value, probabilities) in by_value.iter_mut() {
+                    let path = format!("$.infected_states[{}].probabilities_by_attribute.probabilities.{}", i, value);
+                    resolve(&path, probabilities, labels.as_deref(), None, &mut errors);
+                }
+            }
+            if let Some(periods) = state.get_mut("periods").and_then(|p| p.as_array_mut()) {
+                for (j, period) in periods.iter_mut().enumerate() {
+                    if let Some(probabilities) = period.get_mut("probabilities") {
+                        let path = format!("$.infected_states[{}].periods[{}].probabilities", i, j);
+                        resolve(&path, probabilities, labels.as_deref(), None, &mut errors);
+                    }
+                }
+            }
+        }
+    }
+    
+    if errors.is_empty() {
+        Ok(())
+    }
+    else {
+        Err(Error::InvalidConfig(errors))
+    }
+}
+
+/// Ageclass labels from a config that has not yet been deserialized.
+pub(crate) fn json_ageclass_labels(json: &serde_json::Value) -> Option<Vec<String>> {
+    if let Some(labels) = json.get("ageclass_labels").and_then(|v| v.as_array()) {
+        return labels.iter().map(|label| label.as_str().map(String::from)).collect();
+    }
+    let lower_bounds = json.get("ageclass_lower_bounds").and_then(|v| v.as_array())?;
+    let lower_bounds: Option<Vec<f64>> = lower_bounds.iter().map(|x| x.as_f64()).collect();
+    lower_bounds.map(|lower_bounds| labels_from_lower_bounds(&lower_bounds))
+}
+
+/// Resolves a contact matrix whose rows, entries, or both may be keyed by label, with zeros
+/// for ageclasses not listed.
+fn resolve_matrix(
+    path: &str, matrix: &mut serde_json::Value, labels: Option<&[String]>, errors: &mut Vec<ConfigError>
+) {
+    if let Some(rows) = matrix.as_object_mut() {
+        for (label, row) in rows.iter_mut() {
+            resolve(&format!("{}.{}", path, label), row, labels, Some(&0.0.into()), errors);
+        }
+    }
+    else if let Some(rows) = matrix.as_array_mut() {
+        for (i, row) in rows.iter_mut().enumerate() {
+            resolve(&format!("{}[{}]", path, i), row, labels, Some(&0.0.into()), errors);
+        }
+    }
+    let zeros = labels.map(|labels| serde_json::Value::from(vec![0.0; labels.len()]));
+    resolve(path, matrix, labels, zeros.as_ref(), errors);
+}
+
+/// Replaces `value`, if it is an object keyed by label, with an array in ageclass order,
+/// using `default` for ageclasses not listed.
+fn resolve(
+    path: &str, value: &mut serde_json::Value, labels: Option<&[String]>,
+    default: Option<&serde_json::Value>, errors: &mut Vec<ConfigError>
+) {
+    let map = match value.as_object() {
+        Some(map) => map,
+        None => return,
+    };
+    let labels = match labels {
+        Some(labels) => labels,
+        None => {
+            errors.push(ConfigError { path: path.into(), problem: ConfigProblem::MissingValue {
+                message: "ageclass_labels or ageclass_lower_bounds are required to key values by ageclass".into()
+            }});
+            return;
+        },
+    };
+    for key in map.keys() {
+        if !labels.contains(key) {
+            errors.push(ConfigError { path: format!("{}.{}", path, key), problem: ConfigProblem::InvalidValue {
+                message: format!("unknown ageclass label: {}", key)
+            }});
+        }
+    }
+    let array = labels.iter().map(|label| match map.get(label).or(default) {
+        Some(x) => x.clone(),
+        None => {
+            errors.push(ConfigError { path: path.into(), problem: ConfigProblem::MissingValue {
+                message: format!("no value for ageclass {}", label)
+            }});
+            serde_json::Value::Null
+        },
+    }).collect();
+    *value = serde_json::Value::Array(array);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ageclasses::*;
+    use crate::db::*;
+    use crate::fixtures::*;
+    
+    #[test]
+    fn test_labels_from_lower_bounds() {
+        assert_eq!(labels_from_lower_bounds(&[0.0, 5.0, 75.0]), vec!["0-4", "5-74", "75+"]);
+        assert_eq!(labels_from_lower_bounds(&[0.0, 0.5]), vec!["0-0.5", "0.5+"]);
+    }
+    
+    #[test]
+    fn test_keyed_by_label() {
+        let config = read_test_config();
+        
+        let mut json = read_test_config_json();
+        json["ageclass_lower_bounds"] = serde_json::json!([0, 20]);
+        json["initial_counts"]["S"] = serde_json::json!({ "0-19": 1000, "20+": 1000 });
+        json["initial_counts"]["E"] = serde_json::json!({ "20+": 5 });
+        json["infected_states"][1]["probabilities"] = serde_json::json!({
+            "20+": [0.9, 0.1], "0-19": [0.99, 0.01]
+        });
+        json["contact_parameters"][0]["C"] = serde_json::json!({
+            "0-19": { "0-19": 1.0, "20+": 0.5 }, "20+": [0.5, 1.0]
+        });
+        json["contact_parameters"][1]["C"] = serde_json::json!([{ "0-19": 2.0 }, { "20+": 3.0 }]);
+        json["contact_parameters"][1]["setting_multipliers"] = serde_json::json!({
+            "school": { "20+": 1.0, "0-19": 0.5 }, "work": 0.5
+        });
+        let labelled_config = Config::from_json(&json.to_string()).unwrap();
+        assert_eq!(labelled_config.initial_counts["E"], vec![0, 5]);
+        assert_eq!(labelled_config.initial_counts["S"], config.initial_counts["S"]);
+        assert_eq!(labelled_config.infected_states[1].probabilities, config.infected_states[1].probabilities);
+        assert_eq!(labelled_config.contact_parameters[0].C, config.contact_parameters[0].C);
+        assert_eq!(labelled_config.contact_parameters[1].C, Some(vec![vec![2.0, 0.0], vec![0.0, 3.0]]));
+        let multipliers = labelled_config.contact_parameters[1].setting_multipliers.as_ref().unwrap();
+        assert_eq!(multipliers["school"], SettingMultiplier::ByAgeclass(vec![0.5, 1.0]));
+        assert_eq!(multipliers["work"], SettingMultiplier::Uniform(0.5));
+        assert_eq!(labelled_config.ageclass_bounds(), Some(vec![(0.0, Some(20.0)), (20.0, None)]));
+        
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        create_tables(&conn, None);
+        write_ageclasses(&conn, &labelled_config);
+        let labels: Vec<(i64, String)> = conn.prepare("SELECT ageclass, label FROM Ageclasses;").unwrap().query_map(
+            rusqlite::params![], |row| Ok((row.get(0)?, row.get(1)?))
+        ).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(labels, vec![(1, "0-19".to_string()), (2, "20+".to_string())]);
+        
+        json["infected_states"][1]["probabilities"] = serde_json::json!({ "0-19": [0.99, 0.01], "65+": [0.9, 0.1] });
+        json["contact_parameters"][1]["setting_multipliers"]["school"] = serde_json::json!({ "0-19": 0.5 });
+        let paths: Vec<_> = match Config::from_json(&json.to_string()) {
+            Err(Error::InvalidConfig(errors)) => errors.into_iter().map(|e| e.path).collect(),
+            _ => panic!("expected invalid config"),
+        };
+        assert_eq!(paths, vec![
+            "$.contact_parameters[1].setting_multipliers.school",
+            "$.infected_states[1].probabilities.65+", "$.infected_states[1].probabilities"
+        ]);
+    }
+}
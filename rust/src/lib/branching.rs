@@ -0,0 +1,333 @@
+#![allow(non_snake_case)]
+
+use crate::config::*;
+use crate::errors::*;
+use crate::ibm::*;
+
+use serde::{Deserialize, Serialize};
+
+const MAX_ITERATIONS: usize = 100000;
+const TOLERANCE: f64 = 1e-12;
+
+/// Multi-type branching-process approximation to the early phase of an IBM run.
+///
+/// Types are (infected state, ageclass) pairs. Each individual of a type produces
+/// one offspring of the type it transitions to (none if it moves to a final state),
+/// plus new infections during its gamma-distributed sojourn, at rates fixed by the
+/// contact parameters and the initial susceptible fractions.
+#[derive(Debug, Clone)]
+pub struct BranchingProcess {
+    states: Vec<State>,
+    n_ageclasses: usize,
+    initial_infected_state_id: usize,
+    
+    // Start of the contact period, at which natural-history parameters are taken
+    t_start: f64,
+    
+    // Type index for each infected state ID and ageclass
+    type_index: Vec<Option<usize>>,
+    types: Vec<(usize, usize)>,
+    
+    // rates[a][b]: rate at which an infectious individual in ageclass a infects ageclass b
+    rates: Vec<Vec<f64>>,
+    
+    initial_type_counts: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BranchingProcessResult {
+    pub R0: f64,
+    pub types: Vec<TypeResult>,
+    pub probability_of_major_outbreak: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TypeResult {
+    pub state: String,
+    pub ageclass: usize,
+    pub extinction_probability: f64,
+    pub initial_count: usize,
+}
+
+impl BranchingProcess {
+    /// Forms the branching process using the contact parameters in effect during
+    /// period `contact_period` (0 for the start of the simulation), and the natural-history
+    /// parameters in effect when it starts (see `StatePeriod`).
+    ///
+    /// Configs with a `population` are rejected, since the process is formed from ageclass
+    /// counts and can't account for susceptibility, group contacts or transition probabilities
+    /// by attribute.
+    pub fn from_config(config: &Config, contact_period: usize) -> Result<Self, Error> {
+        config.validate()?;
+        if config.population.is_some() {
+            return Err(Error::InvalidArgument(
+                "branching process approximations are not supported in full-agent mode (population)".into()
+            ));
+        }
+        if contact_period >= config.contact_parameters.len() {
+            return Err(Error::InvalidArgument(format!("invalid contact period: {}", contact_period)));
+        }
+        
+        let (
+            states,
+            susceptible_state_id,
+            initial_infected_state_id,
+            _,
+            initial_counts
+        ) = parse_states(config);
+        let n_ageclasses = config.n_ageclasses;
+        let beta = config.contact_parameters[contact_period].beta;
+        let C = &config.contact_matrices()[contact_period];
+        let t_start = match contact_period {
+            0 => 0.0,
+            i => config.contact_parameters[i - 1].t_end.unwrap(),
+        };
+        
+        let mut type_index = vec![None; states.len() * n_ageclasses];
+        let mut types = Vec::new();
+        let mut initial_type_counts = Vec::new();
+        for state in &states {
+            if state.is_infected() {
+                for ageclass in 0..n_ageclasses {
+                    type_index[state.id * n_ageclasses + ageclass] = Some(types.len());
+                    types.push((state.id, ageclass));
+                    initial_type_counts.push(initial_counts.get(state.id, ageclass));
+                }
+            }
+        }
+        
+        // Force of infection on ageclass b is beta * S_b * sum_a C[b][a] * I_a / N_a
+        let rates = (0..n_ageclasses).map(|a| {
+            let N_a = initial_counts.total_for_ageclass(a) as f64;
+            (0..n_ageclasses).map(|b| {
+                let S_b = initial_counts.get(susceptible_state_id, b) as f64;
+                if N_a > 0.0 { beta * C[b][a] * S_b / N_a } else { 0.0 }
+            }).collect()
+        }).collect();
+        
+        Ok(Self {
+            states, n_ageclasses, initial_infected_state_id, t_start,
+            type_index, types, rates, initial_type_counts,
+        })
+    }
+    
+    fn type_of(&self, state_id: usize, ageclass: usize) -> Option<usize> {
+        self.type_index[state_id * self.n_ageclasses + ageclass]
+    }
+    
+    fn infected_state(&self, state_id: usize) -> &InfectedState {
+        match &self.states[state_id].detail {
+            StateDetail::Infected(Some(infected_state)) => infected_state,
+            _ => panic!(),
+        }
+    }
+    
+    /// Mean duration, gamma shape and transition CDFs of an infected state at the start
+    /// of the contact period.
+    fn natural_history(&self, state_id: usize) -> (f64, f64, &[Vec<f64>]) {
+        self.infected_state(state_id).natural_history_at(self.t_start)
+    }
+    
+    /// Probabilities of each next state, by ageclass, recovered from the transition CDFs.
+    fn transition_probabilities(&self, state_id: usize, ageclass: usize) -> Vec<f64> {
+        let n = self.infected_state(state_id).next_state_ids.len();
+        if n <= 1 {
+            return vec![1.0; n];
+        }
+        let cdf = &self.natural_history(state_id).2[ageclass];
+        (0..n).map(|k| {
+            let upper = if k < n - 1 { cdf[k] } else { 1.0 };
+            let lower = if k == 0 { 0.0 } else { cdf[k - 1] };
+            upper - lower
+        }).collect()
+    }
+    
+    /// Probability generating function of the offspring distribution of each type, at `x`.
+    fn pgf(&self, x: &[f64]) -> Vec<f64> {
+        self.types.iter().map(|&(state_id, ageclass)| {
+            let infected_state = self.infected_state(state_id);
+            
+            // Infections during sojourn: Poisson given duration, mixed over the gamma
+            // duration, giving the gamma Laplace transform at sum_b rate_b (1 - x_b)
+            let infections_factor = if infected_state.infectious {
+                let Lambda: f64 = (0..self.n_ageclasses).map(|b| {
+                    let x_b = self.type_of(self.initial_infected_state_id, b).map(|i| x[i]).unwrap_or(1.0);
+                    self.rates[ageclass][b] * (1.0 - x_b)
+                }).sum();
+                let (mean_duration, gamma_shape, _) = self.natural_history(state_id);
+                (1.0 + mean_duration / gamma_shape * Lambda).powf(-gamma_shape)
+            }
+            else {
+                1.0
+            };
+            
+            let transition_factor: f64 = infected_state.next_state_ids.iter().zip(
+                self.transition_probabilities(state_id, ageclass)
+            ).map(|(&next_state_id, p)| {
+                p * self.type_of(next_state_id, ageclass).map(|i| x[i]).unwrap_or(1.0)
+            }).sum();
+            
+            infections_factor * transition_factor
+        }).collect()
+    }
+    
+    /// Extinction probability for a single individual of each type: the smallest
+    /// fixed point of the offspring PGF, found by iterating from zero.
+    pub fn extinction_probabilities(&self) -> Vec<f64> {
+        let mut q = vec![0.0; self.types.len()];
+        for _ in 0..MAX_ITERATIONS {
+            let q_next = self.pgf(&q);
+            let max_diff = q.iter().zip(&q_next).map(|(a, b)| (a - b).abs()).fold(0.0, f64::max);
+            q = q_next;
+            if max_diff < TOLERANCE {
+                break;
+            }
+        }
+        q
+    }
+    
+    /// Next-generation matrix: K[a][b] is the expected number of infections in ageclass b
+    /// caused by someone newly infected in ageclass a.
+    pub fn next_generation_matrix(&self) -> Vec<Vec<f64>> {
+        (0..self.n_ageclasses).map(|a| {
+            let infectious_time = self.expected_infectious_time(a);
+            (0..self.n_ageclasses).map(|b| infectious_time * self.rates[a][b]).collect()
+        }).collect()
+    }
+    
+    /// Expected total time spent in infectious states by a newly infected individual.
+    fn expected_infectious_time(&self, ageclass: usize) -> f64 {
+        // Probability of visiting each state, propagated through transitions
+        let mut visit = vec![0.0; self.states.len()];
+        let mut pending = vec![0.0; self.states.len()];
+        pending[self.initial_infected_state_id] = 1.0;
+        for _ in 0..MAX_ITERATIONS {
+            let mut next = vec![0.0; self.states.len()];
+            let mut total = 0.0;
+            for (state_id, &p) in pending.iter().enumerate() {
+                if p > 0.0 && self.states[state_id].is_infected() {
+                    visit[state_id] += p;
+                    let next_state_ids = &self.infected_state(state_id).next_state_ids;
+                    for (&next_state_id, tp) in next_state_ids.iter().zip(
+                        self.transition_probabilities(state_id, ageclass)
+                    ) {
+                        next[next_state_id] += p * tp;
+                        total += p * tp;
+                    }
+                }
+            }
+            pending = next;
+            if total < TOLERANCE {
+                break;
+            }
+        }
+        
+        self.states.iter().filter(|state| state.is_infectious()).map(|state| {
+            visit[state.id] * self.natural_history(state.id).0
+        }).sum()
+    }
+    
+    /// Basic reproduction number: the dominant eigenvalue of the next-generation matrix.
+    pub fn R0(&self) -> f64 {
+        dominant_eigenvalue(&self.next_generation_matrix())
+    }
+    
+    /// Probability that at least one of the initial infecteds starts a major outbreak.
+    pub fn probability_of_major_outbreak(&self, extinction_probabilities: &[f64]) -> f64 {
+        1.0 - extinction_probabilities.iter().zip(&self.initial_type_counts).map(
+            |(q, &n)| q.powi(n as i32)
+        ).product::<f64>()
+    }
+    
+    pub fn solve(&self) -> BranchingProcessResult {
+        let q = self.extinction_probabilities();
+        BranchingProcessResult {
+            R0: self.R0(),
+            types: self.types.iter().zip(&q).zip(&self.initial_type_counts).map(
+                |((&(state_id, ageclass), &extinction_probability), &initial_count)| {
+                    TypeResult {
+                        state: self.states[state_id].name.clone(),
+                        ageclass,
+                        extinction_probability,
+                        initial_count,
+                    }
+                }
+            ).collect(),
+            probability_of_major_outbreak: self.probability_of_major_outbreak(&q),
+        }
+    }
+}
+
+/// Dominant eigenvalue of a nonnegative matrix, by power iteration.
+pub fn dominant_eigenvalue(M: &[Vec<f64>]) -> f64 {
+    let n = M.len();
+    if n == 0 {
+        return 0.0;
+    }
+    let mut v = vec![1.0 / n as f64; n];
+    let mut lambda = 0.0;
+    for _ in 0..MAX_ITERATIONS {
+        let w: Vec<f64> = (0..n).map(|j| (0..n).map(|i| v[i] * M[i][j]).sum()).collect();
+        let norm: f64 = w.iter().sum();
+        if norm == 0.0 {
+            return 0.0;
+        }
+        let v_next: Vec<f64> = w.iter().map(|x| x / norm).collect();
+        let converged = v.iter().zip(&v_next).all(|(a, b)| (a - b).abs() < TOLERANCE);
+        v = v_next;
+        lambda = norm;
+        if converged {
+            break;
+        }
+    }
+    lambda
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::branching::*;
+    use crate::fixtures::*;
+    
+    #[test]
+    fn test_single_type_sir() {
+        // SIR with exponential infectious period: R0 = beta * D, q = 1 / R0
+        let mut config = read_test_config();
+        config.n_ageclasses = 1;
+        config.initial_infected_state = "I".into();
+        config.infected_states.remove(0);
+        config.infected_states[0].gamma_shape = 1.0;
+        config.infected_states[0].probabilities = Some(vec![vec![0.9, 0.1]]);
+        config.contact_parameters.truncate(1);
+        config.contact_parameters[0].C = Some(vec![vec![1.0]]);
+        config.contact_parameters[0].t_end = None;
+        config.initial_counts.clear();
+        config.initial_counts.insert("S".into(), vec![1000000]);
+        config.initial_counts.insert("I".into(), vec![2]);
+        
+        let result = BranchingProcess::from_config(&config, 0).unwrap().solve();
+        let R0 = 0.3 * 5.0 * 1000000.0 / 1000002.0;
+        assert!((result.R0 - R0).abs() < 1e-8);
+        assert!((result.types[0].extinction_probability - 1.0 / R0).abs() < 1e-8);
+        assert!((result.probability_of_major_outbreak - (1.0 - 1.0 / (R0 * R0))).abs() < 1e-8);
+    }
+    
+    #[test]
+    fn test_state_periods() {
+        // Doubling the infectious period from the second contact period on doubles R0 then
+        let mut config = read_test_config();
+        let R0: Vec<f64> = (0..2).map(|i| BranchingProcess::from_config(&config, i).unwrap().R0()).collect();
+        config.set("infected_states[1].periods", serde_json::json!([
+            { "contact_period": 1, "mean_duration": 10.0 }
+        ])).unwrap();
+        
+        assert_eq!(BranchingProcess::from_config(&config, 0).unwrap().R0(), R0[0]);
+        assert!((BranchingProcess::from_config(&config, 1).unwrap().R0() - 2.0 * R0[1]).abs() < 1e-8);
+    }
+    
+    #[test]
+    fn test_population_rejected() {
+        let config = read_population_config();
+        assert!(matches!(BranchingProcess::from_config(&config, 0), Err(Error::InvalidArgument(_))));
+        assert!(matches!(config.contact_matrix_reports(Some(2.0)), Err(Error::InvalidArgument(_))));
+    }
+}
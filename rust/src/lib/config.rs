@@ -0,0 +1,992 @@
+#![allow(non_snake_case)]
+
+use crate::ageclasses::*;
+use crate::contacts::*;
+use crate::ibm::*;
+use crate::errors::*;
+use crate::observer::*;
+use crate::population::*;
+use crate::strata::*;
+use crate::util::*;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::Path;
+use std::f64::INFINITY;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use rand::Rng;
+use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+
+/// Configuration for an individual-based simulation, as read by `sirsim`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Config {
+    pub rng_seed: Option<u64>,
+    pub rng_streams: Option<RngStreams>,
+    pub output_path: Option<String>,
+    pub write_to_stdout: Option<bool>,
+    
+    pub record_all_events: bool,
+    
+    pub t_initial: Option<f64>,
+    pub t_final: Option<f64>,
+    
+    pub n_ageclasses: usize,
+    
+    /// Labels of the ageclasses, by which `initial_counts` entries, contact matrices `C`,
+    /// per-ageclass `setting_multipliers` and state `probabilities` may be given as objects
+    /// instead of arrays. Other per-ageclass arrays, such as `C_factors`, must be given in
+    /// ageclass order.
+    pub ageclass_labels: Option<Vec<String>>,
+    pub ageclass_lower_bounds: Option<Vec<f64>>,
+    pub strata: Option<Vec<StratumDimension>>,
+    
+    pub susceptible_state: String,
+    pub initial_infected_state: String,
+    pub final_states: Vec<String>,
+    pub onset_state: Option<String>,
+    pub interval_bin_width: Option<f64>,
+    
+    pub infected_states: Vec<StateConfig>,
+    
+    pub contact_settings: Option<Vec<ContactSetting>>,
+    pub contact_parameters: Vec<ContactParameters>,
+    pub contact_reciprocity: Option<ContactReciprocity>,
+    
+    #[serde(default)]
+    #[schemars(with = "ArrayOrCsv<HashMap<String, ArrayOrCsv<ByAgeclass<usize>>>>")]
+    pub initial_counts: HashMap<String, Vec<usize>>,
+    pub population: Option<PopulationConfig>,
+    /// The population read from `population.path` when the config was read
+    #[serde(skip)]
+    pub population_data: Option<Arc<Population>>,
+    
+    pub establishment: Option<EstablishmentConfig>,
+}
+
+/// Criterion for conditioning runs on a major outbreak: a run is established if
+/// cumulative infections reach `min_cumulative_infections` by time `by_time`.
+///
+/// Runs that fail are discarded and rerun with a new seed, up to `max_attempts` times.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct EstablishmentConfig {
+    pub min_cumulative_infections: usize,
+    pub by_time: f64,
+    pub max_attempts: Option<usize>,
+}
+
+const DEFAULT_MAX_ESTABLISHMENT_ATTEMPTS: usize = 1000;
+
+/// Contact parameters in effect until `t_end` (or the end of the simulation, for the last set).
+///
+/// The contact matrix is either given directly, as `C` or (with `strata`) as `C_factors`,
+/// or, if `contact_settings` are configured, is the sum of the setting matrices with the
+/// rows (contacts of individuals in each ageclass) of each multiplied by
+/// `setting_multipliers`; settings without a multiplier are included as they are.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ContactParameters {
+    pub beta: f64,
+    #[schemars(with = "Option<ArrayOrCsv<ByAgeclass<ByAgeclass<f64>>>>")]
+    pub C: Option<Vec<Vec<f64>>>,
+    pub C_factors: Option<Vec<Vec<Vec<f64>>>>,
+    pub setting_multipliers: Option<HashMap<String, SettingMultiplier>>,
+    pub t_end: Option<f64>,
+}
+
+/// A named setting where contacts happen (e.g. home, school, work), with its own contact
+/// matrix, given as `C` or (with `strata`) as `C_factors`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ContactSetting {
+    pub name: String,
+    #[schemars(with = "Option<ArrayOrCsv<ByAgeclass<ByAgeclass<f64>>>>")]
+    pub C: Option<Vec<Vec<f64>>>,
+    pub C_factors: Option<Vec<Vec<Vec<f64>>>>,
+}
+
+/// The contact matrix given as `C`, or as the Kronecker product of `C_factors`, one
+/// matrix per stratum dimension (see `StratumDimension`).
+fn contact_matrix(C: &Option<Vec<Vec<f64>>>, C_factors: &Option<Vec<Vec<Vec<f64>>>>) -> Option<Vec<Vec<f64>>> {
+    match (C, C_factors) {
+        (Some(C), _) => Some(C.clone()),
+        (None, Some(C_factors)) => Some(kronecker_product(C_factors)),
+        (None, None) => None,
+    }
+}
+
+impl ContactParameters {
+    pub fn contact_matrix(&self) -> Option<Vec<Vec<f64>>> {
+        contact_matrix(&self.C, &self.C_factors)
+    }
+}
+
+impl ContactSetting {
+    pub fn contact_matrix(&self) -> Option<Vec<Vec<f64>>> {
+        contact_matrix(&self.C, &self.C_factors)
+    }
+}
+
+/// Multiplier for a setting's contacts during a contact period: a single value,
+/// or one per ageclass.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum SettingMultiplier {
+    Uniform(f64),
+    ByAgeclass(#[schemars(with = "ByAgeclass<f64>")] Vec<f64>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct StateConfig {
+    pub name: String,
+    pub infectious: bool,
+    pub mean_duration: f64,
+    pub gamma_shape: f64,
+    pub next_states: Vec<String>,
+    #[schemars(with = "Option<ArrayOrCsv<ByAgeclass<Vec<f64>>>>")]
+    pub probabilities: Option<Vec<Vec<f64>>>,
+    pub probabilities_by_attribute: Option<AttributeProbabilities>,
+    pub periods: Option<Vec<StatePeriod>>,
+}
+
+/// Natural-history parameters of an infected state from `t_start` on, or, aligned with
+/// contact changepoints, from when `contact_parameters[contact_period]` takes effect.
+///
+/// Parameters not given carry over from the previous period (or from the state itself).
+/// Durations are drawn with the parameters in effect when the state is entered, and next
+/// states with the probabilities in effect when it is left. States with
+/// `probabilities_by_attribute` cannot have probabilities by period.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct StatePeriod {
+    pub t_start: Option<f64>,
+    pub contact_period: Option<usize>,
+    pub mean_duration: Option<f64>,
+    pub gamma_shape: Option<f64>,
+    #[schemars(with = "Option<ArrayOrCsv<ByAgeclass<Vec<f64>>>>")]
+    pub probabilities: Option<Vec<Vec<f64>>>,
+}
+
+impl StatePeriod {
+    /// Start time of the period: `t_start`, or the `t_end` of the contact parameters
+    /// before `contact_parameters[contact_period]`.
+    pub fn start_time(&self, contact_parameters: &[ContactParameters]) -> Option<f64> {
+        match (self.t_start, self.contact_period) {
+            (Some(t_start), None) => Some(t_start),
+            (None, Some(i)) if i >= 1 => contact_parameters.get(i - 1).and_then(|cp| cp.t_end),
+            _ => None,
+        }
+    }
+}
+
+/// Schema for arrays that may be given as the path of a CSV file instead
+/// (see `Config::read`).
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+enum ArrayOrCsv<T> {
+    Array(T),
+    Csv(String),
+}
+
+/// Schema for arrays indexed by ageclass that may be given as an object keyed by
+/// ageclass label instead (see `Config::ageclass_labels`).
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+pub(crate) enum ByAgeclass<T> {
+    Array(Vec<T>),
+    ByLabel(HashMap<String, T>),
+}
+
+impl Config {
+    /// Parses a JSON config, in which `initial_counts` entries, contact matrices, setting
+    /// multipliers and `probabilities` may be keyed by ageclass label (see `Config::ageclass_labels`), reading any population file
+    /// (see `PopulationConfig`) relative to the working directory.
+    pub fn from_json(json_data: &str) -> Result<Self, Error> {
+        let mut json: serde_json::Value = serde_json::from_str(json_data)?;
+        resolve_ageclass_labels(&mut json)?;
+        let population_data = resolve_population(&mut json, Path::new(""))?;
+        Ok(Config { population_data, ..serde_json::from_value(json)? })
+    }
+    
+    /// Reads a config in any supported format from a file, or from stdin if no path is given.
+    ///
+    /// `contact_settings[i].C`, `contact_parameters[i].C`, `infected_states[i].probabilities`,
+    /// `infected_states[i].periods[j].probabilities`, `initial_counts`, and each entry of `initial_counts` may be given as the path of a CSV
+    /// file, relative to the directory of the config file, which is read in place of the array:
+    /// matrices have one row per ageclass (and may have a header row), entries of
+    /// `initial_counts` are a single row or column, and `initial_counts` itself has
+    /// a header row of state names followed by one row per ageclass. As in `Config::from_json`,
+    /// arrays may also be keyed by ageclass label. A population file is likewise read relative
+    /// to the directory of the config file.
+    pub fn read(path_str: Option<&str>, format: Option<InputFormat>) -> Result<Self, Error> {
+        let mut json: serde_json::Value = read_input(path_str, format)?;
+        let base_dir = path_str.and_then(|p| Path::new(p).parent()).unwrap_or_else(|| Path::new(""));
+        resolve_csv_references(&mut json, base_dir)?;
+        resolve_ageclass_labels(&mut json)?;
+        let population_data = resolve_population(&mut json, base_dir)?;
+        Ok(Config { population_data, ..serde_json::from_value(json)? })
+    }
+    
+    /// Overrides a single field by JSON path, e.g. `contact_parameters[1].beta`.
+    pub fn set(&mut self, path: &str, value: serde_json::Value) -> Result<(), Error> {
+        let mut json = serde_json::to_value(&*self)?;
+        set_json_path(&mut json, path, value)?;
+        *self = Config { population_data: self.population_data.take(), ..serde_json::from_value(json)? };
+        Ok(())
+    }
+    
+    /// The config as JSON with object keys sorted, so that equal configs serialize identically.
+    pub fn to_canonical_json(&self) -> String {
+        let mut json = serde_json::to_value(self).unwrap();
+        sort_json_keys(&mut json);
+        serde_json::to_string(&json).unwrap()
+    }
+    
+    /// Hash (64-bit FNV-1a, in hex) of the canonical JSON of the config without its output
+    /// settings, so that runs of the same model with the same seed have the same hash.
+    pub fn hash(&self) -> String {
+        let config = Config { output_path: None, write_to_stdout: None, ..self.clone() };
+        format!("{:016x}", fnv1a_64(config.to_canonical_json().as_bytes()))
+    }
+    
+    /// JSON Schema describing `sirsim` configs, as printed by `sirsim schema`.
+    pub fn json_schema() -> schemars::schema::RootSchema {
+        schemars::schema_for!(Config)
+    }
+}
+
+/// Replaces CSV file references in a config with the arrays they contain, checking
+/// their dimensions against `n_ageclasses` (see `Config::read`).
+fn resolve_csv_references(json: &mut serde_json::Value, base_dir: &Path) -> Result<(), Error> {
+    let n_ageclasses = json.get("n_ageclasses").and_then(|n| n.as_u64()).map(|n| n as usize);
+    let read_table = |path: &str| -> Result<CsvTable, Error> {
+        let path = base_dir.join(path).to_string_lossy().into_owned();
+        Ok(CsvTable { rows: read_csv(&path)?, path })
+    };
+    
+    for key in &["contact_settings", "contact_parameters"] {
+        if let Some(items) = json.get_mut(*key).and_then(|v| v.as_array_mut()) {
+            for item in items {
+                if let Some(C) = item.get_mut("C") {
+                    if let Some(path) = C.as_str() {
+                        *C = read_table(path)?.matrix(n_ageclasses, n_ageclasses)?;
+                    }
+                }
+            }
+        }
+    }
+    
+    if let Some(states) = json.get_mut("infected_states").and_then(|v| v.as_array_mut()) {
+        for state in states {
+            let n_next = state.get("next_states").and_then(|v| v.as_array()).map(|v| v.len());
+            if let Some(probabilities) = state.get_mut("probabilities") {
+                if let Some(path) = probabilities.as_str() {
+                    *probabilities = read_table(path)?.matrix(n_ageclasses, n_next)?;
+                }
+            }
+            if let Some(periods) = state.get_mut("periods").and_then(|v| v.as_array_mut()) {
+                for period in periods {
+                    if let Some(probabilities) = period.get_mut("probabilities") {
+                        if let Some(path) = probabilities.as_str() {
+                            *probabilities = read_table(path)?.matrix(n_ageclasses, n_next)?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    
+    if let Some(initial_counts) = json.get_mut("initial_counts") {
+        if let Some(path) = initial_counts.as_str() {
+            *initial_counts = read_table(path)?.columns_by_header(n_ageclasses)?;
+        }
+        else if let Some(map) = initial_counts.as_object_mut() {
+            for counts in map.values_mut() {
+                if let Some(path) = counts.as_str() {
+                    *counts = read_table(path)?.vector(n_ageclasses)?;
+                }
+            }
+        }
+    }
+    
+    Ok(())
+}
+
+/// Rows of a CSV file referenced by a config, with its path for error messages.
+struct CsvTable {
+    path: String,
+    rows: Vec<Vec<String>>,
+}
+
+impl CsvTable {
+    fn error(&self, message: String) -> Error {
+        Error::InvalidCsv { path: self.path.clone(), message }
+    }
+    
+    /// Parses a row of numbers, keeping integers as integers.
+    fn parse_row(&self, row: &[String]) -> Result<Vec<serde_json::Value>, Error> {
+        row.iter().map(|field| {
+            field.parse::<serde_json::Number>().map(serde_json::Value::Number).map_err(
+                |_| self.error(format!("not a number: {}", field))
+            )
+        }).collect()
+    }
+    
+    fn check_length(&self, what: &str, expected: Option<usize>, found: usize) -> Result<(), Error> {
+        match expected {
+            Some(expected) if expected != found => Err(self.error(
+                format!("expected {} {}, found {}", expected, what, found)
+            )),
+            _ => Ok(()),
+        }
+    }
+    
+    /// Rows of numbers, skipping a header row if the first row is not numeric.
+    fn numeric_rows(&self) -> Result<Vec<Vec<serde_json::Value>>, Error> {
+        let has_header = self.rows.first().map(|row| self.parse_row(row).is_err()).unwrap_or(false);
+        self.rows.iter().skip(if has_header { 1 } else { 0 }).map(|row| self.parse_row(row)).collect()
+    }
+    
+    fn matrix(&self, n_rows: Option<usize>, n_cols: Option<usize>) -> Result<serde_json::Value, Error> {
+        let rows = self.numeric_rows()?;
+        self.check_length("rows", n_rows, rows.len())?;
+        for row in &rows {
+            self.check_length("columns", n_cols, row.len())?;
+        }
+        Ok(rows.into_iter().map(serde_json::Value::Array).collect())
+    }
+    
+    /// A single row or column of numbers.
+    fn vector(&self, n: Option<usize>) -> Result<serde_json::Value, Error> {
+        let rows = self.numeric_rows()?;
+        let values: Vec<_> = if rows.len() == 1 {
+            rows.into_iter().next().unwrap()
+        }
+        else if rows.iter().all(|row| row.len() == 1) {
+            rows.into_iter().flatten().collect()
+        }
+        else {
+            return Err(self.error("expected a single row or column".into()));
+        };
+        self.check_length("values", n, values.len())?;
+        Ok(serde_json::Value::Array(values))
+    }
+    
+    /// An object mapping each name in the header row to the column below it.
+    fn columns_by_header(&self, n_rows: Option<usize>) -> Result<serde_json::Value, Error> {
+        let header = self.rows.first().ok_or_else(|| self.error("missing header row".into()))?;
+        let rows = self.rows[1..].iter().map(|row| self.parse_row(row)).collect::<Result<Vec<_>, _>>()?;
+        self.check_length("rows", n_rows, rows.len())?;
+        let mut columns = serde_json::Map::new();
+        for (j, name) in header.iter().enumerate() {
+            let column = rows.iter().map(|row| row.get(j).cloned().ok_or_else(
+                || self.error(format!("missing value for {}", name))
+            )).collect::<Result<Vec<_>, _>>()?;
+            columns.insert(name.clone(), serde_json::Value::Array(column));
+        }
+        Ok(serde_json::Value::Object(columns))
+    }
+}
+
+/// Constructs and runs a `Simulation` from a `Config`, without any database output.
+///
+/// ```no_run
+/// # use sirtools::config::*;
+/// # let json_data = String::new();
+/// let config = Config::from_json(&json_data).unwrap();
+/// let output = SimulationBuilder::new(config).rng_seed(42).run().unwrap();
+/// println!("{} infections", output.rt.iter().map(|r| r.n_primary).sum::<usize>());
+/// ```
+#[derive(Clone)]
+pub struct SimulationBuilder {
+    config: Config,
+    observers: Vec<ObserverRef>,
+}
+
+impl SimulationBuilder {
+    pub fn new(config: Config) -> Self {
+        Self { config, observers: Vec::new() }
+    }
+    
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+    
+    pub fn rng_seed(mut self, rng_seed: u64) -> Self {
+        self.config.rng_seed = Some(rng_seed);
+        self
+    }
+    
+    pub fn record_all_events(mut self, record_all_events: bool) -> Self {
+        self.config.record_all_events = record_all_events;
+        self
+    }
+    
+    pub fn t_final(mut self, t_final: f64) -> Self {
+        self.config.t_final = Some(t_final);
+        self
+    }
+    
+    /// Adds an observer to every simulation built.
+    pub fn observer(mut self, observer: ObserverRef) -> Self {
+        self.observers.push(observer);
+        self
+    }
+    
+    /// Searches for a seed whose run meets the establishment criterion.
+    ///
+    /// The first attempt uses the configured seed (or a random one); subsequent seeds
+    /// are derived from it (see `establishment_seed_sequence`). The fraction of attempts that established estimates the
+    /// probability of establishment.
+    pub fn find_established_seed(
+        &self, establishment: &EstablishmentConfig
+    ) -> Result<EstablishmentResult, Error> {
+        self.config.validate()?;
+        let master_rng_seed = self.master_rng_seed();
+        let max_attempts = establishment.max_attempts.unwrap_or(DEFAULT_MAX_ESTABLISHMENT_ATTEMPTS);
+        
+        let mut seeds = Vec::new();
+        for rng_seed in establishment_seed_sequence(master_rng_seed).take(max_attempts) {
+            seeds.push(rng_seed);
+            
+            let mut sim = self.clone_with(rng_seed).build_with_observers(Vec::new());
+            if is_established(&mut sim, establishment) {
+                return Ok(EstablishmentResult { master_rng_seed, seeds, established: true });
+            }
+        }
+        Ok(EstablishmentResult { master_rng_seed, seeds, established: false })
+    }
+    
+    /// Seeds for `n_replicates` independent runs, derived from the configured seed
+    /// (or a random one).
+    pub fn replicate_rng_seeds(&self, n_replicates: usize) -> (u64, Vec<u64>) {
+        let master_rng_seed = self.master_rng_seed();
+        (master_rng_seed, rng_seed_sequence(master_rng_seed).take(n_replicates).collect())
+    }
+    
+    fn master_rng_seed(&self) -> u64 {
+        self.config.rng_seed.unwrap_or_else(|| rand::thread_rng().gen())
+    }
+    
+    /// A copy of this builder with the given seed and without observers.
+    pub fn clone_with(&self, rng_seed: u64) -> Self {
+        Self {
+            config: Config { rng_seed: Some(rng_seed), ..self.config.clone() },
+            observers: Vec::new(),
+        }
+    }
+    
+    /// Validates the config and constructs a simulation; fails with
+    /// `Error::InvalidConfig` listing every problem found.
+    pub fn build(&self) -> Result<Simulation, Error> {
+        self.config.validate()?;
+        Ok(self.build_with_observers(self.observers.clone()))
+    }
+    
+    fn build_with_observers(&self, observers: Vec<ObserverRef>) -> Simulation {
+        let config = &self.config;
+        let (
+            states,
+            susceptible_state_id,
+            initial_infected_state_id,
+            onset_state_id,
+            initial_counts
+        ) = parse_states(config);
+        let (t_change, beta_t) = parse_contact_parameters(&config.contact_parameters);
+        let C_t = config.contact_matrices();
+        let population = config.population_data.as_ref().map(|population| population.model(config, &states));
+        
+        let mut sim = Simulation::new(
+            config.n_ageclasses,
+            states,
+            susceptible_state_id,
+            initial_infected_state_id,
+            ContactSchedule { t_change, beta: beta_t, C: C_t },
+            initial_counts,
+            SimulationOptions {
+                onset_state_id,
+                interval_bin_width: config.interval_bin_width.unwrap_or(1.0),
+                population,
+                rng_seed: config.rng_seed,
+                rng_streams: config.rng_streams.unwrap_or_default(),
+                observers,
+            },
+        );
+        if let Some(settings) = &config.contact_settings {
+            sim.set_contact_settings(
+                settings.iter().map(|setting| setting.name.clone()).collect(),
+                config.setting_contact_matrices()
+            );
+        }
+        sim
+    }
+    
+    /// Runs a simulation to `t_final` (or until no events remain) in unit timesteps,
+    /// collecting counts at the end of each timestep, and individual-level events
+    /// if `record_all_events` is set.
+    ///
+    /// If `establishment` is configured, the run uses the first seed that establishes;
+    /// if none does, the output is that of the last attempt.
+    pub fn run(&self) -> Result<SimulationOutput, Error> {
+        self.config.validate()?;
+        let establishment = match &self.config.establishment {
+            Some(establishment) => Some(self.find_established_seed(establishment)?),
+            None => None,
+        };
+        let builder = match &establishment {
+            Some(result) => Self {
+                observers: self.observers.clone(),
+                ..self.clone_with(*result.seeds.last().unwrap())
+            },
+            None => self.clone(),
+        };
+        
+        let recorder = Rc::new(RefCell::new(EventRecorder::new()));
+        let mut observers = builder.observers.clone();
+        if builder.config.record_all_events {
+            observers.push(recorder.clone());
+        }
+        
+        let mut sim = builder.build_with_observers(observers);
+        let mut output = SimulationOutput {
+            rng_seed: sim.rng_seed(),
+            establishment,
+            counts: sim.count_records(),
+            attribute_counts: sim.attribute_count_records(),
+            events: EventRecords::default(),
+            rt: Vec::new(),
+            generation_intervals: Vec::new(),
+            serial_intervals: Vec::new(),
+            n_infections: 0,
+            n_transitions: 0,
+        };
+        
+        let t_final = self.config.t_final.unwrap_or(INFINITY);
+        let mut done = false;
+        while sim.t < t_final && !done {
+            done = sim.simulate(sim.t + 1.0);
+            output.counts.extend(sim.count_records());
+            output.attribute_counts.extend(sim.attribute_count_records());
+        }
+        
+        output.events = recorder.borrow_mut().take_records();
+        output.rt = sim.rt_records();
+        output.generation_intervals = sim.generation_intervals().records();
+        output.serial_intervals = sim.serial_intervals().records();
+        output.n_infections = sim.n_infections();
+        output.n_transitions = sim.n_transitions();
+        Ok(output)
+    }
+}
+
+/// The master seed itself, followed by seeds drawn from a generator seeded by it.
+fn rng_seed_sequence(master_rng_seed: u64) -> impl Iterator<Item = u64> {
+    let mut seed_rng = Xoshiro256PlusPlus::seed_from_u64(master_rng_seed);
+    std::iter::once(master_rng_seed).chain(std::iter::repeat_with(move || seed_rng.gen()))
+}
+
+/// Seeds for establishment attempts of a run with seed `rng_seed`: the seed itself, followed
+/// by seeds from a generator seeded by it and jumped ahead, so that retries of one replicate
+/// never repeat the seeds of other replicates (see `rng_seed_sequence`).
+fn establishment_seed_sequence(rng_seed: u64) -> impl Iterator<Item = u64> {
+    let mut seed_rng = Xoshiro256PlusPlus::seed_from_u64(rng_seed);
+    seed_rng.jump();
+    std::iter::once(rng_seed).chain(std::iter::repeat_with(move || seed_rng.gen()))
+}
+
+/// Everything produced by a simulation run, corresponding to the tables `sirsim` writes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SimulationOutput {
+    pub rng_seed: u64,
+    pub establishment: Option<EstablishmentResult>,
+    pub counts: Vec<CountRecord>,
+    pub attribute_counts: Vec<AttributeCountRecord>,
+    pub events: EventRecords,
+    pub rt: Vec<RtRecord>,
+    pub generation_intervals: Vec<IntervalRecord>,
+    pub serial_intervals: Vec<IntervalRecord>,
+    pub n_infections: usize,
+    pub n_transitions: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EstablishmentResult {
+    pub master_rng_seed: u64,
+    /// Seeds of all attempts, in order; the last is the one that established, if any did.
+    pub seeds: Vec<u64>,
+    pub established: bool,
+}
+
+impl EstablishmentResult {
+    pub fn n_attempts(&self) -> usize {
+        self.seeds.len()
+    }
+}
+
+fn is_established(sim: &mut Simulation, establishment: &EstablishmentConfig) -> bool {
+    let mut done = false;
+    while !done && sim.n_infections() < establishment.min_cumulative_infections {
+        if sim.t >= establishment.by_time {
+            return false;
+        }
+        done = sim.simulate((sim.t + 1.0).min(establishment.by_time));
+    }
+    sim.n_infections() >= establishment.min_cumulative_infections
+}
+
+pub fn parse_states(config: &Config) -> (Vec<State>, usize, usize, Option<usize>, Counts) {
+    let mut states = Vec::new();
+    let mut n_states: usize = 0;
+    let mut name_id_map = HashMap::new();
+    
+    // Susceptible state
+    let susceptible_state_id = n_states;
+    name_id_map.insert(config.susceptible_state.clone(), susceptible_state_id);
+    states.push(State::new_susceptible(susceptible_state_id, config.susceptible_state.clone()));
+    n_states += 1;
+    
+    // Final states
+    for name in &config.final_states {
+        name_id_map.insert(name.clone(), n_states);
+        states.push(State::new_final(n_states, name.clone()));
+        n_states += 1;
+    }
+    
+    // Infected states, without transitions
+    for state_config in &config.infected_states {
+        name_id_map.insert(state_config.name.clone(), n_states);
+        states.push(State::new_infected(
+            n_states, state_config.name.clone(),
+        ));
+        n_states += 1;
+    }
+    
+    // Add transitions, resolving to state IDs
+    for state_config in &config.infected_states {
+        let id = name_id_map[&state_config.name];
+        
+        let next_state_ids = state_config.next_states.iter().map(
+            |name| name_id_map[name]
+        ).collect();
+        
+        let transition_probabilities = match &state_config.probabilities {
+            Some(probabilities) => {
+                probabilities.clone()
+            },
+            None => {
+                std::iter::repeat(Vec::new()).take(config.n_ageclasses).collect()
+            }
+        };
+        
+        let transition_cdfs: Vec<Vec<f64>> = transition_probabilities.iter().map(|tprobs| {
+            cumulative_sum(tprobs)
+        }).collect();
+        
+        // Periods, each inheriting unspecified parameters from the one before
+        let mut previous = InfectedStatePeriod {
+            t_start: f64::NEG_INFINITY,
+            mean_duration: state_config.mean_duration,
+            gamma_shape: state_config.gamma_shape,
+            transition_cdfs: transition_cdfs.clone(),
+        };
+        let periods = state_config.periods.iter().flatten().map(|period| {
+            previous = InfectedStatePeriod {
+                t_start: period.start_time(&config.contact_parameters).unwrap(),
+                mean_duration: period.mean_duration.unwrap_or(previous.mean_duration),
+                gamma_shape: period.gamma_shape.unwrap_or(previous.gamma_shape),
+                transition_cdfs: match &period.probabilities {
+                    Some(probabilities) => probabilities.iter().map(cumulative_sum).collect(),
+                    None => previous.transition_cdfs.clone(),
+                },
+            };
+            previous.clone()
+        }).collect();
+        
+        states[id].detail = StateDetail::Infected(Some(InfectedState {
+            infectious: state_config.infectious,
+            mean_duration: state_config.mean_duration,
+            gamma_shape: state_config.gamma_shape,
+            next_state_ids: next_state_ids,
+            transition_cdfs: transition_cdfs,
+            periods,
+        }))
+    }
+    
+    let initial_infected_state_id = name_id_map[&config.initial_infected_state];
+    let onset_state_id = config.onset_state.as_ref().map(|name| name_id_map[name]);
+    
+    let initial_counts = parse_initial_counts(
+        states.len(), config.n_ageclasses,
+        &name_id_map, &config.initial_counts
+    );
+    
+    (states, susceptible_state_id, initial_infected_state_id, onset_state_id, initial_counts)
+}
+
+pub fn parse_initial_counts(
+    n_states: usize, n_ageclasses: usize,
+    name_id_map: &HashMap<String, usize>, counts_raw: &HashMap<String, Vec<usize>>
+) -> Counts {
+    let mut counts = Counts::new(n_states, n_ageclasses);
+    for (state_name, counts_for_state) in counts_raw {
+        for i in 0..n_ageclasses {
+            counts.increment(name_id_map[state_name], i, counts_for_state[i]);
+        }
+    }
+    counts
+}
+
+/// Changepoints and beta for each contact period; contact matrices are given by
+/// `Config::contact_matrices`.
+pub fn parse_contact_parameters(cp_vec: &[ContactParameters]) -> (Vec<f64>, Vec<f64>) {
+    let mut t_change = Vec::new();
+    let mut beta_t = Vec::new();
+    
+    for i in 0..cp_vec.len() {
+        beta_t.push(cp_vec[i].beta);
+        
+        if let Some(t_end) = cp_vec[i].t_end {
+            assert!(i < cp_vec.len() - 1);
+            t_change.push(t_end);
+        }
+        else {
+            assert!(i == cp_vec.len() - 1);
+        }
+    }
+    
+    (t_change, beta_t)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::*;
+    use crate::fixtures::*;
+    
+    #[test]
+    fn test_run_conserves_population() {
+        let builder = SimulationBuilder::new(read_test_config()).record_all_events(true);
+        let output = builder.run().unwrap();
+        
+        let n_states = 5;
+        let n_total: usize = output.counts[0..(n_states * 2)].iter().map(|r| r.count).sum();
+        assert_eq!(n_total, 2010);
+        for chunk in output.counts.chunks(n_states * 2) {
+            assert_eq!(chunk.iter().map(|r| r.count).sum::<usize>(), n_total);
+        }
+        
+        let n_infections: usize = output.rt.iter().map(|r| r.n_primary).sum();
+        assert_eq!(n_infections, output.events.infections.len());
+        assert_eq!(n_infections + 10, output.events.individuals.len());
+        
+        // Same seed, same output
+        assert_eq!(builder.run().unwrap().counts, output.counts);
+    }
+    
+    #[test]
+    fn test_yaml_and_toml_configs() {
+        let config = serde_json::to_value(read_test_config()).unwrap();
+        for path in &["tests/sirsim-seir.yaml", "tests/sirsim-seir.toml"] {
+            let other: Config = read_input(Some(path), None).unwrap();
+            assert_eq!(serde_json::to_value(other).unwrap(), config, "{}", path);
+        }
+    }
+    
+    #[test]
+    fn test_csv_arrays() {
+        let config = serde_json::to_value(read_test_config()).unwrap();
+        let csv_config = Config::read(Some("tests/csv/sirsim-seir.yaml"), None).unwrap();
+        assert_eq!(serde_json::to_value(csv_config).unwrap(), config);
+        
+        let rows = |rows: &[&[&str]]| rows.iter().map(
+            |row| row.iter().map(|field| field.to_string()).collect()
+        ).collect();
+        let table = CsvTable { path: "counts.csv".into(), rows: rows(&[&["S", "E"], &["1000", "5"], &["1000", "5"]]) };
+        assert_eq!(table.columns_by_header(Some(2)).unwrap(), serde_json::json!({ "S": [1000, 1000], "E": [5, 5] }));
+        assert!(table.columns_by_header(Some(3)).is_err());
+        assert!(table.matrix(Some(3), Some(2)).is_err());
+        
+        let column = CsvTable { path: "column.csv".into(), rows: rows(&[&["0.5"], &["0.25"]]) };
+        assert_eq!(column.vector(Some(2)).unwrap(), serde_json::json!([0.5, 0.25]));
+        assert!(column.matrix(Some(2), Some(2)).is_err());
+    }
+    
+    #[test]
+    fn test_set_by_path() {
+        let mut config = read_test_config();
+        config.set("$.contact_parameters[1].beta", 0.5.into()).unwrap();
+        config.set("infected_states[1].probabilities[0]", serde_json::json!([0.5, 0.5])).unwrap();
+        config.set("t_final", 10.into()).unwrap();
+        assert_eq!(config.contact_parameters[1].beta, 0.5);
+        assert_eq!(config.infected_states[1].probabilities.as_ref().unwrap()[0], vec![0.5, 0.5]);
+        assert_eq!(config.t_final, Some(10.0));
+        
+        assert!(config.set("contact_parameters[2].beta", 0.5.into()).is_err());
+        assert!(config.set("n_ageclasses", "two".into()).is_err());
+    }
+    
+    #[test]
+    fn test_separate_rng_streams() {
+        // Transitions of initial infecteds (ids 1 to 10) with two different values of beta
+        let initial_transitions = |rng_streams: RngStreams| {
+            let mut config = read_test_config();
+            config.rng_streams = Some(rng_streams);
+            let mut runs = Vec::new();
+            for beta in &[0.3, 0.5] {
+                config.contact_parameters[0].beta = *beta;
+                let output = SimulationBuilder::new(config.clone()).record_all_events(true).run().unwrap();
+                runs.push(output.events.transitions.into_iter().filter(
+                    |t| t.id <= 10 && t.start_state != "S"
+                ).collect::<Vec<_>>());
+            }
+            runs
+        };
+        
+        let runs = initial_transitions(RngStreams::Separate);
+        assert!(!runs[0].is_empty());
+        assert_eq!(runs[0], runs[1]);
+        
+        let runs = initial_transitions(RngStreams::Shared);
+        assert_ne!(runs[0], runs[1]);
+    }
+    
+    #[test]
+    fn test_separate_rng_streams_natural_history() {
+        // Durations and next states by id (order of infection) with two different values of beta;
+        // next-state probabilities differ by ageclass, so ids are paired only within an ageclass
+        let mut config = read_test_config();
+        config.rng_streams = Some(RngStreams::Separate);
+        let mut runs = Vec::new();
+        for beta in &[0.3, 0.5] {
+            config.contact_parameters[0].beta = *beta;
+            let output = SimulationBuilder::new(config.clone()).record_all_events(true).run().unwrap();
+            let ageclasses: HashMap<usize, usize> = output.events.individuals.iter().map(
+                |individual| (individual.id, individual.ageclass)
+            ).collect();
+            let mut histories: HashMap<usize, Vec<(f64, String, String)>> = HashMap::new();
+            for t in output.events.transitions {
+                histories.entry(t.id).or_default().push((t.time, t.start_state, t.end_state));
+            }
+            runs.push((ageclasses, histories));
+        }
+        
+        let mut n_compared = 0;
+        for (id, history) in &runs[0].1 {
+            if let Some(other_history) = runs[1].1.get(id).filter(|_| runs[0].0[id] == runs[1].0[id]) {
+                // Runs may end partway through a history, so compare only the common part
+                let steps = |history: &Vec<(f64, String, String)>| {
+                    history.windows(2).map(
+                        |w| (w[1].0 - w[0].0, w[1].1.clone(), w[1].2.clone())
+                    ).collect::<Vec<_>>()
+                };
+                for (step, other_step) in steps(history).iter().zip(steps(other_history).iter()) {
+                    assert!((step.0 - other_step.0).abs() < 1e-9);
+                    assert_eq!((&step.1, &step.2), (&other_step.1, &other_step.2));
+                    n_compared += 1;
+                }
+            }
+        }
+        assert!(n_compared > 100);
+    }
+    
+    #[test]
+    fn test_config_hash() {
+        let config = read_test_config();
+        let yaml_config: Config = read_input(Some("tests/sirsim-seir.yaml"), None).unwrap();
+        assert_eq!(config.hash(), yaml_config.hash());
+        assert_eq!(config.hash(), Config { output_path: Some("other.sqlite".into()), ..config.clone() }.hash());
+        assert_ne!(config.hash(), Config { rng_seed: Some(2), ..config.clone() }.hash());
+        
+        // Object keys (including those of initial_counts, a HashMap) are sorted
+        let json: serde_json::Value = serde_json::from_str(&config.to_canonical_json()).unwrap();
+        let keys: Vec<_> = json["initial_counts"].as_object().unwrap().keys().cloned().collect();
+        assert_eq!(keys, vec!["E", "S"]);
+    }
+    
+    #[test]
+    fn test_json_schema_covers_config() {
+        let schema = serde_json::to_value(Config::json_schema()).unwrap();
+        let properties = schema["properties"].as_object().unwrap();
+        let config = serde_json::to_value(read_test_config()).unwrap();
+        for key in config.as_object().unwrap().keys() {
+            assert!(properties.contains_key(key), "{} missing from schema", key);
+        }
+    }
+    
+    #[test]
+    fn test_state_periods() {
+        // From the second contact period on, everyone leaving I dies
+        let mut config = read_test_config();
+        config.set("infected_states[1].periods", serde_json::json!([
+            { "contact_period": 1, "probabilities": [[0.0, 1.0], [0.0, 1.0]] }
+        ])).unwrap();
+        assert!(config.validate().is_ok());
+        
+        let output = SimulationBuilder::new(config.clone()).record_all_events(true).run().unwrap();
+        let from_I: Vec<_> = output.events.transitions.iter().filter(|r| r.start_state == "I").collect();
+        assert!(from_I.iter().any(|r| r.time < 40.0 && r.end_state == "R"));
+        assert!(from_I.iter().any(|r| r.time > 40.0));
+        assert!(from_I.iter().all(|r| r.time < 40.0 || r.end_state == "D"));
+        
+        // Later periods inherit unspecified parameters
+        let (states, ..) = parse_states(&config);
+        match &states[4].detail {
+            StateDetail::Infected(Some(infected_state)) => {
+                assert_eq!(infected_state.natural_history_at(10.0).0, 5.0);
+                assert_eq!(infected_state.natural_history_at(50.0).0, 5.0);
+                assert_eq!(infected_state.natural_history_at(50.0).2[0], vec![0.0, 1.0]);
+            },
+            _ => panic!("expected infected state"),
+        }
+        
+        config.set("infected_states[1].periods", serde_json::json!([
+            { "t_start": 30.0, "mean_duration": 0.0 },
+            { "t_start": 20.0, "contact_period": 1 },
+            { "contact_period": 2 },
+        ])).unwrap();
+        let paths: Vec<_> = match config.validate() {
+            Err(Error::InvalidConfig(errors)) => errors.into_iter().map(|e| e.path).collect(),
+            _ => panic!("expected invalid config"),
+        };
+        assert_eq!(paths, vec![
+            "$.infected_states[1].periods[0].mean_duration",
+            "$.infected_states[1].periods[1]",
+            "$.infected_states[1].periods[2].contact_period",
+        ]);
+    }
+    
+    #[test]
+    fn test_establishment_seeds_differ_from_replicate_seeds() {
+        let builder = SimulationBuilder::new(read_test_config());
+        let (_, rng_seeds) = builder.replicate_rng_seeds(100);
+        let replicate_seeds: std::collections::HashSet<u64> = rng_seeds.iter().copied().collect();
+        for rng_seed in &rng_seeds {
+            let mut seeds = establishment_seed_sequence(*rng_seed);
+            assert_eq!(seeds.next(), Some(*rng_seed));
+            assert!(seeds.take(100).all(|seed| !replicate_seeds.contains(&seed)));
+        }
+    }
+    
+    #[test]
+    fn test_establishment_conditioning() {
+        let mut config = read_test_config();
+        config.initial_counts.insert("E".into(), vec![1, 0]);
+        config.establishment = Some(EstablishmentConfig {
+            min_cumulative_infections: 50,
+            by_time: 30.0,
+            max_attempts: None,
+        });
+        
+        let output = SimulationBuilder::new(config).run().unwrap();
+        let establishment = output.establishment.unwrap();
+        assert!(establishment.established);
+        assert_eq!(*establishment.seeds.last().unwrap(), output.rng_seed);
+        
+        let n_infections_by_time: usize = output.rt.iter().filter(
+            |r| r.time_discrete <= 30
+        ).map(|r| r.n_primary).sum();
+        assert!(n_infections_by_time >= 50);
+    }
+}
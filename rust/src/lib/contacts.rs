@@ -0,0 +1,254 @@
+#![allow(non_snake_case)]
+
+use crate::branching::*;
+use crate::config::*;
+use crate::errors::*;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Relative difference between `N_a C[a][b]` and `N_b C[b][a]` above which
+/// a contact matrix is reported as non-reciprocal.
+pub const RECIPROCITY_TOLERANCE: f64 = 1e-6;
+
+/// How contact matrices that are not reciprocal with respect to the initial
+/// ageclass totals are handled.
+///
+/// `C[a][b]` is the contact rate of an individual in ageclass `a` with ageclass `b`,
+/// so the total contact rate between the two ageclasses is `N_a C[a][b]` counted from
+/// one side and `N_b C[b][a]` from the other. With `Ignore` (the default), matrices are
+/// used as given; with `Check`, validation fails if the two differ; with `Symmetrize`,
+/// both are replaced by their average before the matrices are used.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum ContactReciprocity {
+    #[default]
+    Ignore,
+    Check,
+    Symmetrize,
+}
+
+/// A pair of ageclasses (1-based) whose contact rates are not reciprocal.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReciprocityViolation {
+    pub ageclasses: (usize, usize),
+    pub relative_difference: f64,
+}
+
+/// Summary of one set of contact parameters, for rescaling `beta` to a target R0.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContactMatrixReport {
+    pub contact_period: usize,
+    pub beta: f64,
+    
+    /// Dominant eigenvalue of `C` as used in the simulation (after any symmetrization)
+    pub dominant_eigenvalue: f64,
+    pub reciprocity_violations: Vec<ReciprocityViolation>,
+    pub R0: f64,
+    pub beta_for_target_R0: Option<f64>,
+}
+
+/// Total initial number of individuals in each ageclass, over all states.
+pub fn ageclass_totals(config: &Config) -> Vec<f64> {
+    let mut N = vec![0.0; config.n_ageclasses];
+    for counts in config.initial_counts.values() {
+        for (a, count) in counts.iter().enumerate().take(config.n_ageclasses) {
+            N[a] += *count as f64;
+        }
+    }
+    N
+}
+
+/// Pairs of ageclasses for which `N_a C[a][b]` and `N_b C[b][a]` differ by more than
+/// `RECIPROCITY_TOLERANCE`, relative to the larger of the two.
+pub fn reciprocity_violations(C: &[Vec<f64>], N: &[f64]) -> Vec<ReciprocityViolation> {
+    let mut violations = Vec::new();
+    for a in 0..N.len() {
+        for b in (a + 1)..N.len() {
+            let x = N[a] * C[a][b];
+            let y = N[b] * C[b][a];
+            let scale = x.max(y);
+            let relative_difference = if scale > 0.0 { (x - y).abs() / scale } else { 0.0 };
+            if relative_difference > RECIPROCITY_TOLERANCE {
+                violations.push(ReciprocityViolation { ageclasses: (a + 1, b + 1), relative_difference });
+            }
+        }
+    }
+    violations
+}
+
+/// Reciprocal version of `C`, replacing `N_a C[a][b]` and `N_b C[b][a]` by their mean.
+pub fn symmetrize(C: &[Vec<f64>], N: &[f64]) -> Vec<Vec<f64>> {
+    (0..N.len()).map(|a| {
+        (0..N.len()).map(|b| {
+            if N[a] > 0.0 { (N[a] * C[a][b] + N[b] * C[b][a]) / (2.0 * N[a]) } else { C[a][b] }
+        }).collect()
+    }).collect()
+}
+
+impl SettingMultiplier {
+    pub fn for_ageclass(&self, ageclass: usize) -> f64 {
+        match self {
+            SettingMultiplier::Uniform(multiplier) => *multiplier,
+            SettingMultiplier::ByAgeclass(multipliers) => multipliers[ageclass],
+        }
+    }
+}
+
+/// Elementwise sum of matrices of the same dimensions.
+fn sum_matrices(matrices: &[Vec<Vec<f64>>]) -> Vec<Vec<f64>> {
+    let mut sum = matrices[0].clone();
+    for matrix in &matrices[1..] {
+        for (sum_row, row) in sum.iter_mut().zip(matrix) {
+            for (x, y) in sum_row.iter_mut().zip(row) {
+                *x += y;
+            }
+        }
+    }
+    sum
+}
+
+impl Config {
+    /// Contact matrix of each setting in each contact period, as configured (before any
+    /// symmetrization): the setting matrices with rows scaled by the period's multipliers,
+    /// or, without `contact_settings`, the period's `C` as the only setting.
+    fn configured_setting_matrices(&self) -> Vec<Vec<Vec<Vec<f64>>>> {
+        self.contact_parameters.iter().map(|cp| match &self.contact_settings {
+            Some(settings) => settings.iter().map(|setting| {
+                let multiplier = cp.setting_multipliers.as_ref().and_then(|m| m.get(&setting.name));
+                setting.contact_matrix().unwrap_or_default().iter().enumerate().map(|(a, row)| {
+                    let m = multiplier.map(|m| m.for_ageclass(a)).unwrap_or(1.0);
+                    row.iter().map(|x| m * x).collect()
+                }).collect()
+            }).collect(),
+            None => vec![cp.contact_matrix().unwrap_or_default()],
+        }).collect()
+    }
+    
+    /// Contact matrix of each setting in each contact period, as used in the simulation:
+    /// symmetrized if `contact_reciprocity` is `Symmetrize`.
+    pub fn setting_contact_matrices(&self) -> Vec<Vec<Vec<Vec<f64>>>> {
+        let matrices = self.configured_setting_matrices();
+        match self.contact_reciprocity.unwrap_or_default() {
+            ContactReciprocity::Symmetrize => {
+                let N = ageclass_totals(self);
+                matrices.iter().map(
+                    |settings| settings.iter().map(|C| symmetrize(C, &N)).collect()
+                ).collect()
+            },
+            _ => matrices,
+        }
+    }
+    
+    /// Contact matrix for each contact period, as configured (before any symmetrization).
+    pub fn configured_contact_matrices(&self) -> Vec<Vec<Vec<f64>>> {
+        self.configured_setting_matrices().iter().map(|settings| sum_matrices(settings)).collect()
+    }
+    
+    /// Contact matrix for each contact period, as used in the simulation.
+    pub fn contact_matrices(&self) -> Vec<Vec<Vec<f64>>> {
+        self.setting_contact_matrices().iter().map(|settings| sum_matrices(settings)).collect()
+    }
+    
+    /// Reports the dominant eigenvalue, reciprocity violations and R0 for each contact
+    /// period, along with the `beta` that would give `target_R0` if one is provided.
+    /// Like `BranchingProcess::from_config`, fails for configs with a `population`.
+    pub fn contact_matrix_reports(&self, target_R0: Option<f64>) -> Result<Vec<ContactMatrixReport>, Error> {
+        self.validate()?;
+        let N = ageclass_totals(self);
+        let configured = self.configured_contact_matrices();
+        let matrices = self.contact_matrices();
+        self.contact_parameters.iter().zip(&matrices).enumerate().map(|(i, (cp, C))| {
+            let R0 = BranchingProcess::from_config(self, i)?.R0();
+            Ok(ContactMatrixReport {
+                contact_period: i,
+                beta: cp.beta,
+                dominant_eigenvalue: dominant_eigenvalue(C),
+                reciprocity_violations: reciprocity_violations(&configured[i], &N),
+                R0,
+                beta_for_target_R0: target_R0.filter(|_| R0 > 0.0).map(|target| cp.beta * target / R0),
+            })
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::contacts::*;
+    use crate::fixtures::*;
+    use std::collections::HashMap;
+    
+    #[test]
+    fn test_reciprocity() {
+        let mut config = read_test_config();
+        config.initial_counts.get_mut("S").unwrap()[1] = 2005;
+        assert!(config.validate().is_ok());
+        
+        config.contact_reciprocity = Some(ContactReciprocity::Check);
+        let paths: Vec<_> = match config.validate() {
+            Err(Error::InvalidConfig(errors)) => errors.into_iter().map(|e| e.path).collect(),
+            _ => panic!("expected reciprocity violations"),
+        };
+        assert_eq!(paths, vec!["$.contact_parameters[0].C[0][1]", "$.contact_parameters[1].C[0][1]"]);
+        
+        config.contact_reciprocity = Some(ContactReciprocity::Symmetrize);
+        assert!(config.validate().is_ok());
+        let N = ageclass_totals(&config);
+        for C in config.contact_matrices() {
+            assert!(reciprocity_violations(&C, &N).is_empty());
+        }
+    }
+    
+    #[test]
+    fn test_beta_for_target_R0() {
+        let mut config = read_test_config();
+        let reports = config.contact_matrix_reports(Some(2.0)).unwrap();
+        assert_eq!(reports.len(), 2);
+        assert!((reports[0].dominant_eigenvalue - 1.5).abs() < 1e-8);
+        assert!(reports[0].reciprocity_violations.is_empty());
+        
+        config.contact_parameters[0].beta = reports[0].beta_for_target_R0.unwrap();
+        let R0 = BranchingProcess::from_config(&config, 0).unwrap().R0();
+        assert!((R0 - 2.0).abs() < 1e-8);
+    }
+    
+    #[test]
+    fn test_contact_settings() {
+        let mut config = read_test_config();
+        config.contact_settings = Some(vec![
+            ContactSetting { name: "home".into(), C: Some(vec![vec![0.5, 0.25], vec![0.25, 0.5]]), C_factors: None },
+            ContactSetting { name: "school".into(), C: Some(vec![vec![0.5, 0.25], vec![0.25, 0.5]]), C_factors: None },
+        ]);
+        for cp in &mut config.contact_parameters {
+            cp.C = None;
+        }
+        let mut multipliers = HashMap::new();
+        multipliers.insert("school".to_string(), SettingMultiplier::ByAgeclass(vec![0.0, 1.0]));
+        config.contact_parameters[1].setting_multipliers = Some(multipliers.clone());
+        
+        let matrices = config.contact_matrices();
+        assert_eq!(matrices[0], vec![vec![1.0, 0.5], vec![0.5, 1.0]]);
+        assert_eq!(matrices[1], vec![vec![0.5, 0.25], vec![0.5, 1.0]]);
+        
+        // Infections are attributed to settings, and none to school when closed for ageclass 1
+        let output = SimulationBuilder::new(config.clone()).record_all_events(true).run().unwrap();
+        let ageclasses: HashMap<usize, usize> = output.events.individuals.iter().map(
+            |r| (r.id, r.ageclass)
+        ).collect();
+        let infections = &output.events.infections;
+        assert!(!infections.is_empty());
+        assert!(infections.iter().all(|r| r.setting.is_some()));
+        assert!(infections.iter().any(|r| r.setting.as_deref() == Some("school")));
+        assert!(!infections.iter().any(
+            |r| r.time > 40.0 && ageclasses[&r.infected_id] == 0 && r.setting.as_deref() == Some("school")
+        ));
+        
+        multipliers.insert("work".to_string(), SettingMultiplier::Uniform(0.5));
+        config.contact_parameters[1].setting_multipliers = Some(multipliers);
+        config.contact_parameters[0].C = Some(vec![vec![1.0, 0.5], vec![0.5, 1.0]]);
+        let paths: Vec<_> = match config.validate() {
+            Err(Error::InvalidConfig(errors)) => errors.into_iter().map(|e| e.path).collect(),
+            _ => panic!("expected invalid config"),
+        };
+        assert_eq!(paths, vec!["$.contact_parameters[0].C", "$.contact_parameters[1].setting_multipliers.work"]);
+    }
+}
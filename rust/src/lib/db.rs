@@ -0,0 +1,486 @@
+use crate::config::*;
+use crate::errors::*;
+use crate::ibm::*;
+use crate::observer::*;
+use crate::strata::*;
+use crate::util::*;
+
+use rusqlite::types::Value;
+use unindent::unindent;
+
+/// Creates the output tables for a simulation run.
+///
+/// Without strata, `Counts` identifies the stratum by a 1-based `ageclass`; with strata,
+/// by one text column per dimension, named after the dimension. `Individuals` has a 0-based
+/// `ageclass` either way, followed by the stratum columns if there are strata.
+/// `Ageclasses` lists each ageclass (see `write_ageclasses`). `AttributeCounts` has counts
+/// by value of population attributes, in full-agent mode (see `PopulationConfig`).
+pub fn create_tables(conn: &rusqlite::Connection, strata: Option<&[StratumDimension]>) {
+    let individual_strata = match strata {
+        Some(_) => format!("ageclass INTEGER, {}", stratum_column_defs(strata)),
+        None => stratum_column_defs(None),
+    };
+    conn.execute_batch(&unindent(&format!("
+        CREATE TABLE Meta (key, value);
+        CREATE TABLE Individuals (time REAL, id INTEGER, {1}, initial_state TEXT);
+        CREATE TABLE Infections (time REAL, infected_id INTEGER, infectious_id INTEGER, setting TEXT);
+        CREATE TABLE Transitions (time REAL, id INTEGER, start_state TEXT, end_state TEXT);
+        CREATE TABLE Counts (time REAL, state TEXT, {0}, count INTEGER);
+        CREATE TABLE AttributeCounts (time REAL, attribute TEXT, value TEXT, state TEXT, count INTEGER);
+        CREATE TABLE RtSufficientStatistics (
+            time_discrete INTEGER NOT NULL PRIMARY KEY, n_primary INTEGER, n_secondary INTEGER
+        );
+        CREATE TABLE GenerationIntervals (time_discrete INTEGER, interval REAL, count INTEGER);
+        CREATE TABLE SerialIntervals (time_discrete INTEGER, interval REAL, count INTEGER);
+    ", stratum_column_defs(strata), individual_strata))).unwrap();
+    create_ageclasses_table(conn, strata);
+}
+
+/// Creates the `Ageclasses` lookup table, with columns `ageclass` (1-based), `label`,
+/// `lower_bound` and `upper_bound`, followed by the stratum columns if there are strata.
+fn create_ageclasses_table(conn: &rusqlite::Connection, strata: Option<&[StratumDimension]>) {
+    let stratum_columns = match strata {
+        Some(_) => format!(", {}", stratum_column_defs(strata)),
+        None => String::new(),
+    };
+    conn.execute_batch(&format!(
+        "CREATE TABLE Ageclasses (ageclass INTEGER, label TEXT, lower_bound REAL, upper_bound REAL{});",
+        stratum_columns
+    )).unwrap();
+}
+
+/// Writes a row of `Ageclasses` for each ageclass, with its label (see `Config::ageclass_labels`)
+/// and age range (see `Config::ageclass_bounds`), or nulls if they are not configured.
+pub fn write_ageclasses(conn: &rusqlite::Connection, config: &Config) {
+    let labels = config.ageclass_labels();
+    let bounds = config.ageclass_bounds();
+    let n_labels = config.strata.as_ref().map(|dimensions| dimensions.len()).unwrap_or(0);
+    let mut insert = conn.prepare(
+        &format!("INSERT INTO Ageclasses VALUES ({});", placeholders(4 + n_labels))
+    ).unwrap();
+    for ageclass in 0..config.n_ageclasses {
+        let label = labels.as_ref().and_then(|labels| labels.get(ageclass));
+        let bound = bounds.as_ref().and_then(|bounds| bounds.get(ageclass));
+        let mut values = vec![
+            Value::Integer(to_i64(ageclass + 1)),
+            label.map_or(Value::Null, |label| Value::Text(label.clone())),
+            bound.map_or(Value::Null, |(lower, _)| Value::Real(*lower)),
+            bound.and_then(|(_, upper)| *upper).map_or(Value::Null, Value::Real),
+        ];
+        if let Some(dimensions) = &config.strata {
+            values.extend(stratum_labels(dimensions, ageclass).into_iter().map(
+                |label| Value::Text(label.into())
+            ));
+        }
+        insert.execute(values).unwrap();
+    }
+}
+
+/// Names of the columns identifying the stratum of a count: `ageclass`, or one per dimension.
+pub fn stratum_column_names(strata: Option<&[StratumDimension]>) -> Vec<String> {
+    match strata {
+        Some(dimensions) => dimensions.iter().map(|dimension| dimension.name.clone()).collect(),
+        None => vec!["ageclass".into()],
+    }
+}
+
+fn stratum_column_defs(strata: Option<&[StratumDimension]>) -> String {
+    match strata {
+        Some(_) => stratum_column_names(strata).iter().map(
+            |name| format!("\"{}\" TEXT", name)
+        ).collect::<Vec<_>>().join(", "),
+        None => "ageclass INTEGER".into(),
+    }
+}
+
+/// Values of the stratum columns of a count for an ageclass: the 1-based ageclass,
+/// or its label in each dimension.
+fn stratum_values(strata: Option<&[StratumDimension]>, ageclass: usize) -> Vec<Value> {
+    match strata {
+        Some(dimensions) => stratum_labels(dimensions, ageclass).into_iter().map(
+            |label| Value::Text(label.into())
+        ).collect(),
+        None => vec![Value::Integer(to_i64(ageclass + 1))],
+    }
+}
+
+fn placeholders(n: usize) -> String {
+    vec!["?"; n].join(", ")
+}
+
+pub fn write_meta(conn: &rusqlite::Connection, rng_seed: u64) {
+    write_meta_entry(conn, "rng_seed", seed_to_sql(rng_seed));
+}
+
+/// SQLite integers are signed 64-bit, so seeds are stored as decimal text.
+pub fn seed_to_sql(rng_seed: u64) -> String {
+    rng_seed.to_string()
+}
+
+/// Records how an output DB was produced, so that it can be reproduced exactly and
+/// duplicates detected: the resolved config (with the seed actually used), its hash,
+/// the sirtools version and git revision it was built from, and the start time.
+pub fn write_provenance(conn: &rusqlite::Connection, config: &Config) {
+    write_meta_entry(conn, "config", config.to_canonical_json());
+    write_meta_entry(conn, "config_hash", config.hash());
+    write_meta_entry(conn, "sirtools_version", env!("CARGO_PKG_VERSION"));
+    write_meta_entry(conn, "git_revision", option_env!("SIRTOOLS_GIT_REVISION").unwrap_or("unknown"));
+    write_meta_entry(conn, "start_time", format_utc_time(std::time::SystemTime::now()));
+}
+
+pub fn write_end_time(conn: &rusqlite::Connection) {
+    write_meta_entry(conn, "end_time", format_utc_time(std::time::SystemTime::now()));
+}
+
+pub fn write_meta_entry<T: rusqlite::ToSql>(conn: &rusqlite::Connection, key: &str, value: T) {
+    conn.execute(
+        "INSERT INTO Meta VALUES (?, ?);",
+        rusqlite::params![key, value]
+    ).unwrap();
+}
+
+/// Values of a row of `Counts`, without any key columns.
+fn count_values(record: &CountRecord, strata: Option<&[StratumDimension]>) -> Vec<Value> {
+    let mut values = vec![Value::Real(record.time), Value::Text(record.state.clone())];
+    values.extend(stratum_values(strata, record.ageclass));
+    values.push(Value::Integer(to_i64(record.count)));
+    values
+}
+
+pub fn write_counts(conn: &rusqlite::Connection, records: &[CountRecord], strata: Option<&[StratumDimension]>) {
+    let n_columns = 3 + stratum_column_names(strata).len();
+    let mut insert = conn.prepare(&format!("INSERT INTO Counts VALUES ({});", placeholders(n_columns))).unwrap();
+    for record in records {
+        insert.execute(count_values(record, strata)).unwrap();
+    }
+}
+
+pub fn write_attribute_counts(conn: &rusqlite::Connection, records: &[AttributeCountRecord]) {
+    let mut insert = conn.prepare("INSERT INTO AttributeCounts VALUES (?, ?, ?, ?, ?);").unwrap();
+    for record in records {
+        insert.execute(rusqlite::params![
+            record.time, record.attribute, record.value, record.state, to_i64(record.count)
+        ]).unwrap();
+    }
+}
+
+pub fn write_event_records(
+    conn: &rusqlite::Connection, records: &EventRecords, strata: Option<&[StratumDimension]>
+) {
+    let n_labels = strata.map(|dimensions| dimensions.len()).unwrap_or(0);
+    let mut insert_individual = conn.prepare(
+        &format!("INSERT INTO Individuals VALUES ({});", placeholders(4 + n_labels))
+    ).unwrap();
+    for record in &records.individuals {
+        let mut values = vec![
+            Value::Real(record.time), Value::Integer(to_i64(record.id)), Value::Integer(to_i64(record.ageclass))
+        ];
+        if let Some(dimensions) = strata {
+            values.extend(stratum_labels(dimensions, record.ageclass).into_iter().map(
+                |label| Value::Text(label.into())
+            ));
+        }
+        values.push(Value::Text(record.initial_state.clone()));
+        insert_individual.execute(values).unwrap();
+    }
+    
+    let mut insert_infection = conn.prepare(
+        "INSERT INTO Infections VALUES (?,?,?,?);"
+    ).unwrap();
+    for record in &records.infections {
+        insert_infection.execute(rusqlite::params![
+            record.time, to_i64(record.infected_id), to_i64(record.infectious_id), record.setting
+        ]).unwrap();
+    }
+    
+    let mut insert_transition = conn.prepare(
+        "INSERT INTO Transitions VALUES (?,?,?,?);"
+    ).unwrap();
+    for record in &records.transitions {
+        insert_transition.execute(rusqlite::params![
+            record.time, to_i64(record.id), record.start_state, record.end_state
+        ]).unwrap();
+    }
+}
+
+pub fn write_rt_records(conn: &rusqlite::Connection, records: &[RtRecord]) {
+    let mut insert = conn.prepare(
+        "INSERT OR REPLACE INTO RtSufficientStatistics VALUES (?, ?, ?);"
+    ).unwrap();
+    for record in records {
+        insert.execute(rusqlite::params![
+            record.time_discrete, to_i64(record.n_primary), to_i64(record.n_secondary)
+        ]).unwrap();
+    }
+}
+
+pub fn write_interval_records(
+    conn: &rusqlite::Connection, table_name: &str, records: &[IntervalRecord]
+) {
+    let mut insert = conn.prepare(
+        &format!("INSERT INTO {} VALUES (?, ?, ?);", table_name)
+    ).unwrap();
+    for record in records {
+        insert.execute(rusqlite::params![
+            record.time_discrete, record.interval, to_i64(record.count)
+        ]).unwrap();
+    }
+}
+
+/// Creates output tables for multiple runs, whose rows are prefixed by `key_columns`
+/// (e.g. `replicate`) identifying the run. Runs are listed in a `Runs` table with their seeds.
+///
+/// Strata are identified in `Counts`, and ageclasses listed in `Ageclasses`, as in `create_tables`.
+pub fn create_multi_run_tables(
+    conn: &rusqlite::Connection, key_columns: &[&str], strata: Option<&[StratumDimension]>
+) {
+    let keys: String = key_columns.iter().map(|c| format!("{} INTEGER, ", c)).collect();
+    conn.execute_batch(&unindent(&format!("
+        CREATE TABLE Meta (key, value);
+        CREATE TABLE Runs ({0}rng_seed TEXT, n_infections INTEGER, n_transitions INTEGER);
+        CREATE TABLE Counts ({0}time REAL, state TEXT, {1}, count INTEGER);
+        CREATE TABLE AttributeCounts ({0}time REAL, attribute TEXT, value TEXT, state TEXT, count INTEGER);
+        CREATE TABLE RtSufficientStatistics ({0}time_discrete INTEGER, n_primary INTEGER, n_secondary INTEGER);
+        CREATE TABLE GenerationIntervals ({0}time_discrete INTEGER, interval REAL, count INTEGER);
+        CREATE TABLE SerialIntervals ({0}time_discrete INTEGER, interval REAL, count INTEGER);
+    ", keys, stratum_column_defs(strata)))).unwrap();
+    create_ageclasses_table(conn, strata);
+}
+
+/// Writes the output of one run into tables created by `create_multi_run_tables`.
+pub fn write_multi_run_output(
+    conn: &rusqlite::Connection, keys: &[i64], output: &SimulationOutput, strata: Option<&[StratumDimension]>
+) {
+    let placeholders = |n: usize| placeholders(keys.len() + n);
+    conn.execute(
+        &format!("INSERT INTO Runs VALUES ({});", placeholders(3)),
+        with_keys(keys, vec![
+            &seed_to_sql(output.rng_seed), &to_i64(output.n_infections), &to_i64(output.n_transitions)
+        ])
+    ).unwrap();
+    
+    let n_columns = 3 + stratum_column_names(strata).len();
+    let mut insert = conn.prepare(&format!("INSERT INTO Counts VALUES ({});", placeholders(n_columns))).unwrap();
+    for record in &output.counts {
+        let values = count_values(record, strata);
+        insert.execute(with_keys(keys, values.iter().map(|v| v as &dyn rusqlite::ToSql).collect())).unwrap();
+    }
+    
+    let mut insert = conn.prepare(
+        &format!("INSERT INTO AttributeCounts VALUES ({});", placeholders(5))
+    ).unwrap();
+    for record in &output.attribute_counts {
+        insert.execute(with_keys(keys, vec![
+            &record.time, &record.attribute, &record.value, &record.state, &to_i64(record.count)
+        ])).unwrap();
+    }
+    
+    let mut insert = conn.prepare(
+        &format!("INSERT INTO RtSufficientStatistics VALUES ({});", placeholders(3))
+    ).unwrap();
+    for record in &output.rt {
+        insert.execute(with_keys(keys, vec![
+            &record.time_discrete, &to_i64(record.n_primary), &to_i64(record.n_secondary)
+        ])).unwrap();
+    }
+    
+    for (table_name, records) in &[
+        ("GenerationIntervals", &output.generation_intervals),
+        ("SerialIntervals", &output.serial_intervals),
+    ] {
+        let mut insert = conn.prepare(
+            &format!("INSERT INTO {} VALUES ({});", table_name, placeholders(3))
+        ).unwrap();
+        for record in records.iter() {
+            insert.execute(with_keys(keys, vec![
+                &record.time_discrete, &record.interval, &to_i64(record.count)
+            ])).unwrap();
+        }
+    }
+}
+
+fn with_keys<'a>(
+    keys: &'a [i64], values: Vec<&'a dyn rusqlite::ToSql>
+) -> Vec<&'a dyn rusqlite::ToSql> {
+    keys.iter().map(|k| k as &dyn rusqlite::ToSql).chain(values).collect()
+}
+
+/// Records a simulation to SQLite: the observer that produces `sirsim` output DBs.
+///
+/// Each timestep (call to `Simulation::simulate`) is written in its own transaction,
+/// followed by counts at the end of the step. Individual-level events are recorded
+/// only if `record_all_events` is set.
+pub struct SqliteRecorder {
+    conn: rusqlite::Connection,
+    events: Option<EventRecorder>,
+    strata: Option<Vec<StratumDimension>>,
+}
+
+impl SqliteRecorder {
+    /// Creates output tables in `conn` and begins the first transaction.
+    pub fn new(
+        conn: rusqlite::Connection, record_all_events: bool, strata: Option<Vec<StratumDimension>>
+    ) -> Self {
+        conn.execute_batch("BEGIN;").unwrap();
+        create_tables(&conn, strata.as_deref());
+        Self {
+            conn,
+            events: if record_all_events { Some(EventRecorder::new()) } else { None },
+            strata,
+        }
+    }
+    
+    pub fn connection(&self) -> &rusqlite::Connection {
+        &self.conn
+    }
+    
+    /// Writes metadata, counts and any pending events, and commits.
+    ///
+    /// Called once after the simulation is constructed, before the first step.
+    pub fn start(&mut self, sim: &Simulation) {
+        write_meta(&self.conn, sim.rng_seed());
+        self.write_step(sim);
+    }
+    
+    /// Writes statistics that are only complete at the end of the simulation, event counts
+    /// and the end time, and commits.
+    pub fn finish(&mut self, sim: &Simulation) {
+        write_meta_entry(&self.conn, "n_infections", to_i64(sim.n_infections()));
+        write_meta_entry(&self.conn, "n_transitions", to_i64(sim.n_transitions()));
+        write_end_time(&self.conn);
+        write_rt_records(&self.conn, &sim.rt_records());
+        write_interval_records(&self.conn, "GenerationIntervals", &sim.generation_intervals().records());
+        write_interval_records(&self.conn, "SerialIntervals", &sim.serial_intervals().records());
+        self.conn.execute_batch("COMMIT;").unwrap();
+    }
+    
+    fn write_step(&mut self, sim: &Simulation) {
+        if let Some(events) = &mut self.events {
+            write_event_records(&self.conn, &events.take_records(), self.strata.as_deref());
+        }
+        write_counts(&self.conn, &sim.count_records(), self.strata.as_deref());
+        write_attribute_counts(&self.conn, &sim.attribute_count_records());
+        self.conn.execute_batch("COMMIT; BEGIN;").unwrap();
+    }
+}
+
+impl SimulationObserver for SqliteRecorder {
+    fn on_individual_created(
+        &mut self, t: f64, id: usize, ageclass: usize, state: &State, is_initial: bool
+    ) {
+        if let Some(events) = &mut self.events {
+            events.on_individual_created(t, id, ageclass, state, is_initial);
+        }
+    }
+    
+    fn on_infection(&mut self, event: &InfectionEvent) {
+        if let Some(events) = &mut self.events {
+            events.on_infection(event);
+        }
+    }
+    
+    fn on_transition(
+        &mut self, t: f64, id: usize, ageclass: usize, from_state: &State, to_state: &State
+    ) {
+        if let Some(events) = &mut self.events {
+            events.on_transition(t, id, ageclass, from_state, to_state);
+        }
+    }
+    
+    fn on_step_end(&mut self, sim: &Simulation) {
+        self.write_step(sim);
+    }
+}
+
+/// Writes tables of a DB as CSV files named `<output_prefix>_<table>.csv`, with a header row
+/// of column names: every table if `tables` is empty, otherwise those listed.
+/// Returns the paths written.
+pub fn export_tables(
+    conn: &rusqlite::Connection, output_prefix: &str, tables: &[&str]
+) -> Result<Vec<String>, Error> {
+    let db_err = |e: rusqlite::Error| Error::InvalidDatabase(format!("{}", e));
+    
+    let mut stmt = conn.prepare(
+        "SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name;"
+    ).map_err(db_err)?;
+    let all_tables = stmt.query_map(rusqlite::params![], |row| row.get::<_, String>(0)).map_err(db_err)?
+        .collect::<Result<Vec<_>, _>>().map_err(db_err)?;
+    if let Some(table) = tables.iter().find(|table| !all_tables.iter().any(|t| t == *table)) {
+        return Err(Error::InvalidArgument(format!("no such table: {}", table)));
+    }
+    
+    let mut paths = Vec::new();
+    for table in all_tables.iter().filter(|table| tables.is_empty() || tables.contains(&table.as_str())) {
+        let mut stmt = conn.prepare(&format!("SELECT * FROM \"{}\";", table)).map_err(db_err)?;
+        let mut lines = vec![stmt.column_names().iter().map(|name| csv_field(name)).collect::<Vec<_>>().join(",")];
+        let n_columns = stmt.column_count();
+        let mut rows = stmt.query(rusqlite::params![]).map_err(db_err)?;
+        while let Some(row) = rows.next().map_err(db_err)? {
+            lines.push((0..n_columns).map(|i| match row.get_raw(i) {
+                rusqlite::types::ValueRef::Null => String::new(),
+                rusqlite::types::ValueRef::Integer(x) => x.to_string(),
+                rusqlite::types::ValueRef::Real(x) => x.to_string(),
+                rusqlite::types::ValueRef::Text(x) => csv_field(&String::from_utf8_lossy(x)),
+                rusqlite::types::ValueRef::Blob(_) => String::new(),
+            }).collect::<Vec<_>>().join(","));
+        }
+        
+        let path = format!("{}_{}.csv", output_prefix, table);
+        std::fs::write(&path, lines.join("\n") + "\n").map_err(|_| Error::InvalidOutputPath(path.clone()))?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// A CSV field, quoted if it contains a comma, quote or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    }
+    else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::*;
+    use crate::fixtures::*;
+    
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    
+    #[test]
+    fn test_export_tables() {
+        let config = read_test_config();
+        let recorder = Rc::new(RefCell::new(SqliteRecorder::new(
+            rusqlite::Connection::open_in_memory().unwrap(), true, None
+        )));
+        let mut sim = SimulationBuilder::new(config.clone()).observer(recorder.clone()).build().unwrap();
+        write_provenance(recorder.borrow().connection(), &config);
+        recorder.borrow_mut().start(&sim);
+        while sim.t < 10.0 && !sim.simulate(sim.t + 1.0) {}
+        recorder.borrow_mut().finish(&sim);
+        
+        let recorder = recorder.borrow();
+        let conn = recorder.connection();
+        let output_prefix = std::env::temp_dir().join(format!("sirtools-export-{}", std::process::id()));
+        let output_prefix = output_prefix.to_string_lossy();
+        let paths = export_tables(conn, &output_prefix, &["Counts", "Meta"]).unwrap();
+        assert_eq!(paths, vec![format!("{}_Counts.csv", output_prefix), format!("{}_Meta.csv", output_prefix)]);
+        
+        let counts = read_data_from_file(&paths[0]).unwrap();
+        let n_counts: i64 = conn.query_row("SELECT COUNT(*) FROM Counts;", rusqlite::params![], |row| row.get(0)).unwrap();
+        assert!(counts.starts_with("time,state,ageclass,count\n"));
+        assert_eq!(counts.lines().count() as i64, n_counts + 1);
+        
+        // The config, which contains commas and quotes, is a single quoted field
+        let meta = read_data_from_file(&paths[1]).unwrap();
+        assert!(meta.lines().any(|line| line.starts_with("config,\"{\"\"")));
+        for path in &paths {
+            std::fs::remove_file(path).unwrap();
+        }
+        
+        assert!(export_tables(conn, &output_prefix, &["Nonexistent"]).is_err());
+    }
+}
@@ -6,6 +6,33 @@ pub enum Error {
     InvalidInputFile(String),
     InputReadFailure,
     InvalidJson(JsonError),
+    InvalidYaml(String),
+    InvalidToml(String),
+    InvalidCsv { path: String, message: String },
+    InvalidArgument(String),
+    InvalidDatabase(String),
+    InvalidOutputPath(String),
+    OutputExists(String),
+    OutputWriteFailure,
+    EstablishmentFailed { n_attempts: usize },
+    InvalidConfig(Vec<ConfigError>),
+}
+
+impl Error {
+    /// Numeric code for the kind of error, used as the `sirsim` exit code and
+    /// returned by the C interface:
+    /// 1 for simulation failures, 2 for invalid arguments, 3 for invalid input
+    /// (config, input file, or DB), and 4 for output errors.
+    pub fn code(&self) -> i32 {
+        match self {
+            Error::EstablishmentFailed { .. } => 1,
+            Error::InvalidArgument(_) => 2,
+            Error::InvalidInputPath(_) | Error::InvalidInputFile(_) | Error::InputReadFailure |
+            Error::InvalidJson(_) | Error::InvalidYaml(_) | Error::InvalidToml(_) |
+            Error::InvalidCsv { .. } | Error::InvalidConfig(_) | Error::InvalidDatabase(_) => 3,
+            Error::InvalidOutputPath(_) | Error::OutputExists(_) | Error::OutputWriteFailure => 4,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -15,3 +42,25 @@ pub struct JsonError {
     pub line: usize,
     pub column: usize,
 }
+
+/// A single problem found while validating a config, located by JSON path
+/// (e.g. `$.infected_states[1].next_states[0]`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConfigError {
+    pub path: String,
+    pub problem: ConfigProblem,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ConfigProblem {
+    UnknownState { name: String },
+    DuplicateState { name: String },
+    UnreachableState { name: String },
+    DimensionMismatch { expected: usize, found: usize },
+    ProbabilitiesDoNotSumToOne { sum: f64 },
+    NonIncreasingTime { t: f64, t_previous: f64 },
+    MissingValue { message: String },
+    InvalidValue { message: String },
+    NonReciprocalContacts { ageclasses: (usize, usize), relative_difference: f64 },
+}
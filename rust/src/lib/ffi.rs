@@ -0,0 +1,335 @@
+//! C interface for embedding the simulator (see `include/sirsim.h`).
+//!
+//! Functions returning `int32_t` return 0 on success, or an error code (`Error::code`,
+//! or `SIRSIM_ERROR_PANIC` if a panic was caught); the error itself is then available
+//! as JSON, in the same form `sirsim` prints, from `sirsim_last_error`.
+//! Strings returned through out-parameters must be freed with `sirsim_string_free`,
+//! and simulations with `sirsim_simulation_free`.
+//!
+//! Simulations hold observers behind `Rc`, so a handle must be used and freed only on
+//! the thread that created it. The last error is thread-local: `sirsim_last_error`
+//! returns the error of the last failing call on the calling thread.
+
+use crate::config::*;
+use crate::errors::*;
+use crate::ibm::*;
+use crate::observer::*;
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::rc::Rc;
+
+/// Returned when a panic is caught at the boundary.
+pub const SIRSIM_ERROR_PANIC: i32 = 5;
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+/// A simulation together with the records accumulated as it is advanced.
+pub struct SirsimSimulation {
+    sim: Simulation,
+    establishment: Option<EstablishmentResult>,
+    counts: Vec<CountRecord>,
+    attribute_counts: Vec<AttributeCountRecord>,
+    recorder: Option<Rc<RefCell<EventRecorder>>>,
+}
+
+impl SirsimSimulation {
+    fn new(config_json: &str) -> Result<Self, Error> {
+        let config = Config::from_json(config_json)?;
+        config.validate()?;
+        let mut builder = SimulationBuilder::new(config.clone());
+        
+        let establishment = match &config.establishment {
+            Some(establishment) => {
+                let result = builder.find_established_seed(establishment)?;
+                if !result.established {
+                    return Err(Error::EstablishmentFailed { n_attempts: result.n_attempts() });
+                }
+                builder = builder.clone_with(*result.seeds.last().unwrap());
+                Some(result)
+            },
+            None => None,
+        };
+        let recorder = if config.record_all_events {
+            let recorder = Rc::new(RefCell::new(EventRecorder::new()));
+            builder = builder.observer(recorder.clone());
+            Some(recorder)
+        } else {
+            None
+        };
+        
+        let sim = builder.build()?;
+        let counts = sim.count_records();
+        let attribute_counts = sim.attribute_count_records();
+        Ok(Self { sim, establishment, counts, attribute_counts, recorder })
+    }
+    
+    /// Advances in unit timesteps (the last possibly shorter) to `t`, recording counts
+    /// after each step, as `SimulationBuilder::run` does; returns whether no events remain.
+    fn advance(&mut self, t: f64) -> bool {
+        let mut done = false;
+        while self.sim.t < t && !done {
+            done = self.sim.simulate((self.sim.t + 1.0).min(t));
+            self.counts.extend(self.sim.count_records());
+            self.attribute_counts.extend(self.sim.attribute_count_records());
+        }
+        done
+    }
+    
+    fn count(&self, state_name: &str, ageclass: i64) -> Result<usize, Error> {
+        let state = self.sim.states().iter().find(|s| s.name == state_name).ok_or_else(
+            || Error::InvalidArgument(format!("unknown state: {}", state_name))
+        )?;
+        if ageclass < 0 {
+            Ok(self.sim.counts().total_for_state(state.id))
+        } else if (ageclass as usize) < self.sim.n_ageclasses() {
+            Ok(self.sim.counts().get(state.id, ageclass as usize))
+        } else {
+            Err(Error::InvalidArgument(format!("ageclass out of range: {}", ageclass)))
+        }
+    }
+    
+    fn output(&self) -> SimulationOutput {
+        SimulationOutput {
+            rng_seed: self.sim.rng_seed(),
+            establishment: self.establishment.clone(),
+            counts: self.counts.clone(),
+            attribute_counts: self.attribute_counts.clone(),
+            events: self.recorder.as_ref().map(|r| r.borrow().records().clone()).unwrap_or_default(),
+            rt: self.sim.rt_records(),
+            generation_intervals: self.sim.generation_intervals().records(),
+            serial_intervals: self.sim.serial_intervals().records(),
+            n_infections: self.sim.n_infections(),
+            n_transitions: self.sim.n_transitions(),
+        }
+    }
+    
+    fn checkpoint(&self) -> Self {
+        let mut sim = self.sim.checkpoint();
+        let recorder = self.recorder.as_ref().map(|recorder| {
+            let recorder = Rc::new(RefCell::new(recorder.borrow().clone()));
+            sim.add_observer(recorder.clone());
+            recorder
+        });
+        Self {
+            sim,
+            establishment: self.establishment.clone(),
+            counts: self.counts.clone(),
+            attribute_counts: self.attribute_counts.clone(),
+            recorder,
+        }
+    }
+}
+
+/// Runs `f`, converting errors and panics into a return code and the last error.
+fn handle<F>(f: F) -> i32 where F: FnOnce() -> Result<(), Error> {
+    let (code, error_json) = match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => return 0,
+        Ok(Err(error)) => (error.code(), serde_json::to_string(&Err::<(), _>(&error)).unwrap()),
+        Err(panic) => {
+            let message = panic.downcast_ref::<&str>().map(|s| s.to_string()).or_else(
+                || panic.downcast_ref::<String>().cloned()
+            ).unwrap_or_default();
+            (SIRSIM_ERROR_PANIC, serde_json::json!({ "Err": { "Panic": message } }).to_string())
+        },
+    };
+    LAST_ERROR.with(|e| *e.borrow_mut() = CString::new(error_json).unwrap());
+    code
+}
+
+unsafe fn read_str<'a>(s: *const c_char) -> Result<&'a str, Error> {
+    if s.is_null() {
+        return Err(Error::InvalidArgument("null string".into()));
+    }
+    CStr::from_ptr(s).to_str().map_err(|e| Error::InvalidArgument(format!("{}", e)))
+}
+
+unsafe fn deref<'a, T>(ptr: *const T) -> Result<&'a T, Error> {
+    ptr.as_ref().ok_or_else(|| Error::InvalidArgument("null pointer".into()))
+}
+
+unsafe fn deref_mut<'a, T>(ptr: *mut T) -> Result<&'a mut T, Error> {
+    ptr.as_mut().ok_or_else(|| Error::InvalidArgument("null pointer".into()))
+}
+
+/// JSON for the last error on the calling thread; valid until that thread's next failing call.
+#[no_mangle]
+pub extern "C" fn sirsim_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ptr())
+}
+
+#[no_mangle]
+pub extern "C" fn sirsim_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
+
+/// Creates a simulation from a JSON config, as read by `sirsim`; nothing is written
+/// to the config's output path.
+///
+/// # Safety
+/// `config_json` must be a null-terminated string and `out` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn sirsim_simulation_new(
+    config_json: *const c_char, out: *mut *mut SirsimSimulation
+) -> i32 {
+    handle(|| {
+        let out = deref_mut(out)?;
+        *out = Box::into_raw(Box::new(SirsimSimulation::new(read_str(config_json)?)?));
+        Ok(())
+    })
+}
+
+/// Advances a simulation to time `t`, setting `done` (if not null) to 1 if no events remain.
+///
+/// # Safety
+/// `sim` must come from `sirsim_simulation_new` or `sirsim_simulation_checkpoint`.
+#[no_mangle]
+pub unsafe extern "C" fn sirsim_simulation_advance(
+    sim: *mut SirsimSimulation, t: f64, done: *mut i32
+) -> i32 {
+    handle(|| {
+        let finished = deref_mut(sim)?.advance(t);
+        if let Some(done) = done.as_mut() {
+            *done = finished as i32;
+        }
+        Ok(())
+    })
+}
+
+/// # Safety
+/// `sim` must come from `sirsim_simulation_new` or `sirsim_simulation_checkpoint`.
+#[no_mangle]
+pub unsafe extern "C" fn sirsim_simulation_time(sim: *const SirsimSimulation, t: *mut f64) -> i32 {
+    handle(|| {
+        *deref_mut(t)? = deref(sim)?.sim.t;
+        Ok(())
+    })
+}
+
+/// Current count in a state, in one ageclass (numbered from 0) or, if `ageclass`
+/// is negative, summed over all of them.
+///
+/// # Safety
+/// `sim` must come from `sirsim_simulation_new` or `sirsim_simulation_checkpoint`, and
+/// `state` must be a null-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn sirsim_simulation_count(
+    sim: *const SirsimSimulation, state: *const c_char, ageclass: i64, count: *mut u64
+) -> i32 {
+    handle(|| {
+        *deref_mut(count)? = deref(sim)?.count(read_str(state)?, ageclass)? as u64;
+        Ok(())
+    })
+}
+
+/// Records accumulated so far (counts after each step, events if `record_all_events`
+/// is set, and Rt and interval statistics) as JSON in the form of `SimulationOutput`.
+///
+/// # Safety
+/// `sim` must come from `sirsim_simulation_new` or `sirsim_simulation_checkpoint`; the
+/// string stored in `json` must be freed with `sirsim_string_free`.
+#[no_mangle]
+pub unsafe extern "C" fn sirsim_simulation_records(
+    sim: *const SirsimSimulation, json: *mut *mut c_char
+) -> i32 {
+    handle(|| {
+        let output = serde_json::to_string(&deref(sim)?.output()).unwrap();
+        *deref_mut(json)? = CString::new(output).unwrap().into_raw();
+        Ok(())
+    })
+}
+
+/// Copies a simulation, including its records and random number state, so that it
+/// can be continued from this point more than once.
+///
+/// # Safety
+/// `sim` must come from `sirsim_simulation_new` or `sirsim_simulation_checkpoint`, and
+/// `out` must be a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn sirsim_simulation_checkpoint(
+    sim: *const SirsimSimulation, out: *mut *mut SirsimSimulation
+) -> i32 {
+    handle(|| {
+        let copy = deref(sim)?.checkpoint();
+        *deref_mut(out)? = Box::into_raw(Box::new(copy));
+        Ok(())
+    })
+}
+
+/// # Safety
+/// `sim` must be null or come from `sirsim_simulation_new` or `sirsim_simulation_checkpoint`,
+/// and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn sirsim_simulation_free(sim: *mut SirsimSimulation) {
+    if !sim.is_null() {
+        drop(Box::from_raw(sim));
+    }
+}
+
+/// # Safety
+/// `s` must be null or a string returned by this library, and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn sirsim_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ffi::*;
+    use crate::fixtures::*;
+    
+    use std::ptr::null_mut;
+    
+    #[test]
+    fn test_ffi_simulation() {
+        let config_json = CString::new(read_test_config_json().to_string()).unwrap();
+        unsafe {
+            let mut sim = null_mut();
+            assert_eq!(sirsim_simulation_new(config_json.as_ptr(), &mut sim), 0);
+            assert_eq!(sirsim_simulation_advance(sim, 10.0, null_mut()), 0);
+            
+            // A checkpoint continues exactly as the original does
+            let mut copy = null_mut();
+            assert_eq!(sirsim_simulation_checkpoint(sim, &mut copy), 0);
+            let state = CString::new("R").unwrap();
+            let mut counts = [0u64; 2];
+            for (i, s) in [sim, copy].iter().enumerate() {
+                let mut done = 0;
+                assert_eq!(sirsim_simulation_advance(*s, 30.0, &mut done), 0);
+                assert_eq!(sirsim_simulation_count(*s, state.as_ptr(), -1, &mut counts[i]), 0);
+            }
+            assert_eq!(counts[0], counts[1]);
+            
+            let mut t = 0.0;
+            assert_eq!(sirsim_simulation_time(sim, &mut t), 0);
+            assert_eq!(t, 30.0);
+            
+            let mut json = null_mut();
+            assert_eq!(sirsim_simulation_records(sim, &mut json), 0);
+            let output: SimulationOutput = serde_json::from_str(CStr::from_ptr(json).to_str().unwrap()).unwrap();
+            assert_eq!(output.counts.len(), 31 * 5 * 2);
+            sirsim_string_free(json);
+            
+            // Errors are reported as codes and JSON
+            let mut count = 0;
+            let unknown = CString::new("X").unwrap();
+            assert_eq!(sirsim_simulation_count(sim, unknown.as_ptr(), 0, &mut count), 2);
+            let error: serde_json::Value = serde_json::from_str(
+                CStr::from_ptr(sirsim_last_error()).to_str().unwrap()
+            ).unwrap();
+            assert!(error["Err"]["InvalidArgument"].is_string());
+            
+            sirsim_simulation_free(sim);
+            sirsim_simulation_free(copy);
+            
+            let invalid = CString::new("{}").unwrap();
+            assert_eq!(sirsim_simulation_new(invalid.as_ptr(), &mut sim), 3);
+        }
+    }
+}
@@ -0,0 +1,33 @@
+//! Configs shared by unit tests, read relative to the crate directory.
+
+use crate::config::*;
+use crate::util::*;
+
+/// The SEIR test config as JSON, for tests that change it before parsing.
+pub(crate) fn read_test_config_json() -> serde_json::Value {
+    serde_json::from_str(&read_data_from_file("tests/sirsim-seir.json").unwrap()).unwrap()
+}
+
+/// The SEIR test config.
+pub(crate) fn read_test_config() -> Config {
+    Config::from_json(&read_test_config_json().to_string()).unwrap()
+}
+
+/// The SEIR test config in full-agent mode, with the population in `tests/population.csv`,
+/// susceptibility and transition probabilities by comorbidity, and household contacts.
+pub(crate) fn read_population_config() -> Config {
+    let mut json = read_test_config_json();
+    json.as_object_mut().unwrap().remove("initial_counts");
+    json["ageclass_labels"] = serde_json::json!(["child", "adult"]);
+    json["population"] = serde_json::json!({
+        "path": "tests/population.csv",
+        "susceptibility": [{ "attribute": "comorbidity", "multipliers": { "yes": 2.0 } }],
+        "group_contacts": [{ "attribute": "household", "beta": 0.5 }],
+        "output_attributes": ["comorbidity", "region"]
+    });
+    json["infected_states"][1]["probabilities_by_attribute"] = serde_json::json!({
+        "attribute": "comorbidity",
+        "probabilities": { "yes": { "child": [0.9, 0.1], "adult": [0.5, 0.5] } }
+    });
+    Config::from_json(&json.to_string()).unwrap()
+}
@@ -8,28 +8,21 @@ use rand_distr::{Gamma};
 use rand_xoshiro::rand_core::SeedableRng;
 use rand_xoshiro::Xoshiro256PlusPlus;
 
-use std::collections::{BTreeSet, BTreeMap};
+use std::collections::{BTreeMap, BinaryHeap};
+use std::cmp::Reverse;
 use std::cmp::Ordering;
 use rand::Rng;
 use std::f64::INFINITY;
+use std::rc::Rc;
 
-use indoc::indoc;
-use unindent::unindent;
+use schemars::JsonSchema;
+use serde::{Serialize, Deserialize};
 
-use std::convert::{TryInto, TryFrom};
+use crate::observer::*;
+use crate::population::*;
+use crate::util::fnv1a_64;
 
-const INDIVIDUALS_SQL: &str = "INSERT INTO Individuals VALUES (?,?,?,?);";
-const INFECTIONS_SQL: &str = "INSERT INTO Infections VALUES (?,?,?);";
-const TRANSITIONS_SQL: &str = "INSERT INTO Transitions VALUES (?,?,?,?);";
-const RT_INSERT_SQL: &str = indoc!("
-    INSERT OR IGNORE INTO RtSufficientStatistics VALUES (?, 0, 0);
-");
-const RT_INCREMENT_PRIMARY: &str = indoc!("
-    UPDATE RtSufficientStatistics SET n_primary = n_primary + 1 WHERE time_discrete = ?;
-");
-const RT_INCREMENT_SECONDARY: &str = indoc!("
-    UPDATE RtSufficientStatistics SET n_secondary = n_secondary + 1 WHERE time_discrete = ?;
-");
+use std::convert::TryInto;
 
 pub fn to_i64(x: usize) -> i64  {
     x.try_into().unwrap()
@@ -57,6 +50,12 @@ pub fn cumulative_sum(v: &Vec<f64>) -> Vec<f64> {
     cs
 }
 
+/// Draws one of `n` categories from a CDF, by binary search.
+///
+/// Used for small categorical draws (next states, settings, members of a group), where
+/// building the CDF for each draw costs less than maintaining one across events. Ageclasses,
+/// whose number grows with strata, are drawn from `FenwickTree`s instead (see
+/// `Simulation::contact_rates` and `CIOverN`).
 fn draw_categorical(rng: &mut Xoshiro256PlusPlus, n: usize, cdf: &Vec<f64>) -> usize {
     assert!(cdf.len() == n || cdf.len() == (n - 1));
     
@@ -64,71 +63,306 @@ fn draw_categorical(rng: &mut Xoshiro256PlusPlus, n: usize, cdf: &Vec<f64>) -> u
         return 0;
     }
     else {
+        // First i with u < cdf[i], or n - 1 if there is none
         let u: f64 = rng.gen();
-        for i in 0..(n - 1) {
-            if u < cdf[i] {
-                return i;
-            }
-        }
-        return n - 1;
+        cdf[..(n - 1)].partition_point(|c| *c <= u)
     }
 }
 
+/// Disjoint sets of small nonnegative integers (such as `Arena` slots), one per group,
+/// supporting constant-time insertion, removal and uniform sampling within a group: each
+/// group's items are kept densely in a vector, with the position of every item in a single
+/// vector indexed by item, shared by all groups since an item is in at most one of them.
 #[derive(Debug, Clone)]
-pub struct VecSet<T> {
-    vec: Vec<T>,
-    index_map: BTreeMap<T, usize>,
+pub struct GroupedVecSet {
+    groups: Vec<Vec<usize>>,
+    index: Vec<usize>,
 }
 
-impl<T> VecSet<T> where T: Copy + Ord {
-    pub fn new() -> Self {
+const NOT_IN_SET: usize = usize::MAX;
+
+impl GroupedVecSet {
+    pub fn new(n_groups: usize) -> Self {
         Self {
-            vec: Vec::new(),
-            index_map: BTreeMap::new(),
+            groups: vec![Vec::new(); n_groups],
+            index: Vec::new(),
         }
     }
     
-    pub fn add(&mut self, item: T) {
-        self.index_map.insert(item, self.vec.len());
-        self.vec.push(item);
+    pub fn add(&mut self, group: usize, item: usize) {
+        if item >= self.index.len() {
+            self.index.resize(item + 1, NOT_IN_SET);
+        }
+        assert!(self.index[item] == NOT_IN_SET);
+        self.index[item] = self.groups[group].len();
+        self.groups[group].push(item);
+    }
+    
+    pub fn remove(&mut self, group: usize, item: usize) {
+        let index = self.index[item];
+        assert!(index != NOT_IN_SET && self.groups[group][index] == item);
+        self.index[item] = NOT_IN_SET;
+        
+        // Remove the last item in the group
+        let vec = &mut self.groups[group];
+        let last_item = vec.pop().unwrap();
+        if index != vec.len() {
+            // Move it to where the removed item was, and update its index
+            vec[index] = last_item;
+            self.index[last_item] = index;
+        }
+    }
+    
+    pub fn contains(&self, item: usize) -> bool {
+        item < self.index.len() && self.index[item] != NOT_IN_SET
+    }
+    
+    pub fn len(&self, group: usize) -> usize {
+        self.groups[group].len()
+    }
+    
+    pub fn sample(&self, group: usize, mut rng: &mut Xoshiro256PlusPlus) -> usize {
+        let vec = &self.groups[group];
+        vec[Uniform::new(0, vec.len()).sample(&mut rng)]
+    }
+}
+
+/// Storage for values in reusable slots: removing a value frees its slot,
+/// which is handed out again by a later insertion.
+#[derive(Debug, Clone)]
+struct Arena<T> {
+    slots: Vec<Option<T>>,
+    free_slots: Vec<usize>,
+}
+
+impl<T> Arena<T> {
+    fn new() -> Self {
+        Self { slots: Vec::new(), free_slots: Vec::new() }
+    }
+    
+    fn insert(&mut self, value: T) -> usize {
+        match self.free_slots.pop() {
+            Some(slot) => {
+                self.slots[slot] = Some(value);
+                slot
+            },
+            None => {
+                self.slots.push(Some(value));
+                self.slots.len() - 1
+            },
+        }
     }
     
-    pub fn remove(&mut self, item: T) {
-        let index = self.index_map[&item];
+    fn get(&self, slot: usize) -> Option<&T> {
+        self.slots.get(slot).and_then(|value| value.as_ref())
+    }
+    
+    fn get_mut(&mut self, slot: usize) -> Option<&mut T> {
+        self.slots.get_mut(slot).and_then(|value| value.as_mut())
+    }
+    
+    fn remove(&mut self, slot: usize) -> Option<T> {
+        let value = self.slots.get_mut(slot).and_then(|value| value.take());
+        if value.is_some() {
+            self.free_slots.push(slot);
+        }
+        value
+    }
+}
+
+/// A square matrix stored by rows (compressed sparse row format), keeping only
+/// nonzero entries.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SparseMatrix {
+    row_starts: Vec<usize>,
+    cols: Vec<usize>,
+    values: Vec<f64>,
+}
+
+impl SparseMatrix {
+    pub fn from_dense(m: &[Vec<f64>]) -> Self {
+        let mut row_starts = vec![0];
+        let mut cols = Vec::new();
+        let mut values = Vec::new();
+        for row in m {
+            for (j, x) in row.iter().enumerate() {
+                if *x != 0.0 {
+                    cols.push(j);
+                    values.push(*x);
+                }
+            }
+            row_starts.push(cols.len());
+        }
+        Self { row_starts, cols, values }
+    }
+    
+    pub fn n_rows(&self) -> usize {
+        self.row_starts.len() - 1
+    }
+    
+    /// Number of nonzero entries.
+    pub fn nnz(&self) -> usize {
+        self.values.len()
+    }
+    
+    /// Columns and values of the nonzero entries in row `i`, in column order.
+    pub fn row(&self, i: usize) -> (&[usize], &[f64]) {
+        let range = self.row_starts[i]..self.row_starts[i + 1];
+        (&self.cols[range.clone()], &self.values[range])
+    }
+    
+    pub fn get(&self, i: usize, j: usize) -> f64 {
+        let (cols, values) = self.row(i);
+        cols.binary_search(&j).map(|k| values[k]).unwrap_or(0.0)
+    }
+}
+
+/// Nonnegative weights in a Fenwick (binary indexed) tree, so that a weight can be changed,
+/// and an index drawn with probability proportional to its weight, in O(log n) time.
+///
+/// Partial sums are updated by differences, so they are recomputed from the weights every
+/// so often to keep rounding errors from accumulating, and the total is exactly zero
+/// whenever every weight is.
+#[derive(Debug, Clone)]
+pub struct FenwickTree {
+    weights: Vec<f64>,
+    // Partial sums, 1-based: tree[i] is the sum of weights (i - lowbit(i))..i
+    tree: Vec<f64>,
+    n_positive: usize,
+    n_updates: usize,
+}
+
+// Number of updates per weight after which partial sums are recomputed
+const FENWICK_UPDATES_PER_REBUILD: usize = 64;
+
+impl FenwickTree {
+    pub fn new(weights: Vec<f64>) -> Self {
+        assert!(weights.iter().all(|w| *w >= 0.0));
+        let mut tree = Self { weights, tree: Vec::new(), n_positive: 0, n_updates: 0 };
+        tree.rebuild();
+        tree
+    }
     
-        // Remove the last item in vec
-        let last_item = self.vec.pop().unwrap();
-        if index != self.vec.len() {
-            // Move it to where the removed item was, and update the index map
-            self.vec[index] = last_item;
-            self.index_map.insert(last_item, index);
+    fn rebuild(&mut self) {
+        let n = self.weights.len();
+        self.tree = std::iter::once(0.0).chain(self.weights.iter().copied()).collect();
+        for i in 1..=n {
+            let parent = i + (i & i.wrapping_neg());
+            if parent <= n {
+                self.tree[parent] += self.tree[i];
+            }
         }
+        self.n_positive = self.weights.iter().filter(|w| **w > 0.0).count();
+        self.n_updates = 0;
     }
     
     pub fn len(&self) -> usize {
-        self.vec.len()
+        self.weights.len()
     }
     
-    pub fn sample(&self, mut rng: &mut Xoshiro256PlusPlus) -> T {
-        self.vec[Uniform::new(0, self.vec.len()).sample(&mut rng)].clone()
+    pub fn is_empty(&self) -> bool {
+        self.weights.is_empty()
+    }
+    
+    pub fn weight(&self, i: usize) -> f64 {
+        self.weights[i]
+    }
+    
+    pub fn set(&mut self, i: usize, weight: f64) {
+        assert!(weight >= 0.0);
+        let delta = weight - self.weights[i];
+        if delta == 0.0 {
+            return;
+        }
+        match (self.weights[i] > 0.0, weight > 0.0) {
+            (false, true) => self.n_positive += 1,
+            (true, false) => self.n_positive -= 1,
+            _ => {},
+        }
+        self.weights[i] = weight;
+        
+        self.n_updates += 1;
+        if self.n_updates >= FENWICK_UPDATES_PER_REBUILD * self.len() {
+            self.rebuild();
+            return;
+        }
+        let mut j = i + 1;
+        while j < self.tree.len() {
+            self.tree[j] += delta;
+            j += j & j.wrapping_neg();
+        }
+    }
+    
+    pub fn total(&self) -> f64 {
+        if self.n_positive == 0 {
+            return 0.0;
+        }
+        let mut sum = 0.0;
+        let mut j = self.len();
+        while j > 0 {
+            sum += self.tree[j];
+            j -= j & j.wrapping_neg();
+        }
+        sum.max(0.0)
+    }
+    
+    /// Draws an index with probability proportional to its weight; the total must be positive.
+    pub fn sample(&self, rng: &mut Xoshiro256PlusPlus) -> usize {
+        assert!(self.n_positive > 0);
+        if self.len() == 1 {
+            return 0;
+        }
+        let u: f64 = rng.gen();
+        self.find(u * self.total())
+    }
+    
+    /// The index whose interval of cumulative weight contains `target`, or, if rounding
+    /// error lands on an index with zero weight, the nearest index with positive weight.
+    fn find(&self, target: f64) -> usize {
+        let n = self.len();
+        let mut i = 0;
+        let mut remaining = target;
+        let mut step = if n == 0 { 0 } else { 1 << (usize::BITS - 1 - n.leading_zeros()) };
+        while step > 0 {
+            if i + step <= n && self.tree[i + step] <= remaining {
+                i += step;
+                remaining -= self.tree[i];
+            }
+            step >>= 1;
+        }
+        let i = i.min(n - 1);
+        if self.weights[i] > 0.0 {
+            i
+        }
+        else {
+            ((i + 1)..n).chain((0..i).rev()).find(|j| self.weights[*j] > 0.0).unwrap()
+        }
     }
 }
 
+/// Reference to an individual stored in the simulation's arena: its slot, and its id,
+/// which distinguishes it from any later occupant of the slot once it has been removed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct IndividualKey {
+    slot: usize,
+    id: usize,
+}
+
 #[derive(Debug, Copy, Clone)]
 struct Event {
     t: f64,
-    individual_id: usize,
+    individual: IndividualKey,
 }
 
 impl Event {
-    pub fn new(t: f64, individual_id: usize) -> Self {
-        Self { t, individual_id }
+    pub fn new(t: f64, individual: IndividualKey) -> Self {
+        Self { t, individual }
     }
 }
 
 impl PartialEq for Event {
     fn eq(&self, other: &Self) -> bool {
-        self.t == other.t && self.individual_id == other.individual_id
+        self.t == other.t && self.individual.id == other.individual.id
     }
 }
 
@@ -143,7 +377,7 @@ impl Ord for Event {
             Ordering::Greater
         }
         else {
-            self.individual_id.cmp(&other.individual_id)
+            self.individual.id.cmp(&other.individual.id)
         }
     }
 }
@@ -225,6 +459,27 @@ pub struct InfectedState {
     pub gamma_shape: f64,
     pub next_state_ids: Vec<usize>,
     pub transition_cdfs: Vec<Vec<f64>>,
+    pub periods: Vec<InfectedStatePeriod>,
+}
+
+/// Natural-history parameters of an infected state from `t_start` on (see `StatePeriod`).
+#[derive(Debug, Clone)]
+pub struct InfectedStatePeriod {
+    pub t_start: f64,
+    pub mean_duration: f64,
+    pub gamma_shape: f64,
+    pub transition_cdfs: Vec<Vec<f64>>,
+}
+
+impl InfectedState {
+    /// Mean duration, gamma shape and transition CDFs in effect at time `t`: those of the
+    /// last period started by `t`, or the state's own before any period has started.
+    pub fn natural_history_at(&self, t: f64) -> (f64, f64, &[Vec<f64>]) {
+        match self.periods.iter().rev().find(|period| period.t_start <= t) {
+            Some(period) => (period.mean_duration, period.gamma_shape, &period.transition_cdfs),
+            None => (self.mean_duration, self.gamma_shape, &self.transition_cdfs),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -287,61 +542,199 @@ impl Counts {
     }
 }
 
+/// Force of infection per unit `beta` on ageclass `row` from ageclass `col`,
+/// `C[row][col] I[col] / N[col]`, for a sparse contact matrix `C`.
+///
+/// Each row keeps its nonzero entries in a `FenwickTree`, so row sums are cached and the
+/// infecting ageclass is drawn in O(log n) time; a change in `I[col]` updates only the
+/// rows with contacts in column `col`.
 #[derive(Debug, Clone)]
 pub struct CIOverN {
-    C: Vec<Vec<f64>>,
-    I: Vec<f64>,
+    C: SparseMatrix,
+    I: Vec<usize>,
     N: Vec<f64>,
-//    _val: Vec<Vec<f64>>,
-//    _row_sums: Vec<f64>,
+    rows: Vec<FenwickTree>,
+    // Row, and position within the row, of the nonzero entries in each column
+    columns: Vec<Vec<(usize, usize)>>,
 }
 
 impl CIOverN {
-    fn new(C: Vec<Vec<f64>>, I: Vec<usize>, N: Vec<usize>) -> Self {
-        let obj = Self {
-            C,
-            I: I.iter().map(|x| *x as f64).collect(),
+    fn new(C: SparseMatrix, I: Vec<usize>, N: Vec<usize>) -> Self {
+        let mut obj = Self {
+            C: SparseMatrix::from_dense(&[]),
+            I,
             N: N.iter().map(|x| *x as f64).collect(),
+            rows: Vec::new(),
+            columns: Vec::new(),
         };
-        
+        obj.update_C(C);
         obj
     }
     
     fn increment(&mut self, ageclass: usize) {
-        self.I[ageclass] += 1.0;
+        self.I[ageclass] += 1;
+        self.update_column(ageclass);
     }
     
     fn decrement(&mut self, ageclass: usize){
-        self.I[ageclass] -= 1.0;
+        self.I[ageclass] -= 1;
+        self.update_column(ageclass);
     }
     
-    fn update_C(&mut self, C: Vec<Vec<f64>>) {
-        self.C = C;
+    fn update_column(&mut self, col: usize) {
+        for (row, k) in &self.columns[col] {
+            let value = self.C.row(*row).1[*k] * self.I_over_N(col);
+            self.rows[*row].set(*k, value);
+        }
     }
     
-    fn n_ageclasses(&self) -> usize {
-        self.C.len()
+    fn update_C(&mut self, C: SparseMatrix) {
+        let n = C.n_rows();
+        self.columns = vec![Vec::new(); n];
+        self.rows = (0..n).map(|row| {
+            let (cols, values) = C.row(row);
+            for (k, col) in cols.iter().enumerate() {
+                self.columns[*col].push((row, k));
+            }
+            FenwickTree::new(cols.iter().zip(values).map(|(col, x)| x * self.I_over_N(*col)).collect())
+        }).collect();
+        self.C = C;
     }
     
-    fn value(&self, row: usize, col: usize) -> f64 {
-        self.C[row][col] * self.I[col] / self.N[col]
+    fn I_over_N(&self, col: usize) -> f64 {
+        if self.I[col] == 0 { 0.0 } else { self.I[col] as f64 / self.N[col] }
     }
     
-    fn row(&self, ageclass: usize) -> Vec<f64> {
-        (0..self.n_ageclasses()).map(|col| self.value(ageclass, col)).collect()
+    /// Rows whose force of infection depends on `I[col]`.
+    fn rows_with_contacts_in(&self, col: usize) -> impl Iterator<Item = usize> + '_ {
+        self.columns[col].iter().map(|(row, _)| *row)
     }
     
     fn row_sum(&self, ageclass: usize) -> f64 {
-        self.row(ageclass).iter().sum()
+        self.rows[ageclass].total()
     }
     
-    fn row_cdf(&self, ageclass: usize) -> Vec<f64> {
-        let row = self.row(ageclass);
-        weights_to_cdf(&row)
+    /// Draws an infecting ageclass for ageclass `row`, proportional to force of infection.
+    fn sample_col(&self, row: usize, rng: &mut Xoshiro256PlusPlus) -> usize {
+        self.C.row(row).0[self.rows[row].sample(rng)]
     }
 }
 
+/// Binned counts of intervals (generation or serial) by discrete calendar time.
+///
+/// Calendar time is discretized as in `RtSufficientStatistics` (the ceiling of the time);
+/// intervals are assigned to bins of width `bin_width`, identified by their lower bound.
+#[derive(Debug, Clone)]
+pub struct IntervalHistogram {
+    bin_width: f64,
+    counts: BTreeMap<(i64, i64), usize>,
+}
 
+impl IntervalHistogram {
+    pub fn new(bin_width: f64) -> Self {
+        assert!(bin_width > 0.0);
+        Self { bin_width, counts: BTreeMap::new() }
+    }
+    
+    pub fn record(&mut self, t: f64, interval: f64) {
+        let t_discrete = t.ceil() as i64;
+        let bin = (interval / self.bin_width).floor() as i64;
+        *self.counts.entry((t_discrete, bin)).or_insert(0) += 1;
+    }
+    
+    pub fn records(&self) -> Vec<IntervalRecord> {
+        self.counts.iter().map(|((time_discrete, bin), count)| {
+            IntervalRecord {
+                time_discrete: *time_discrete,
+                interval: *bin as f64 * self.bin_width,
+                count: *count,
+            }
+        }).collect()
+    }
+}
+
+/// Records corresponding to rows of the output tables.
+///
+/// Ageclasses are zero-based here; the `Counts` table numbers them from 1.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CountRecord {
+    pub time: f64,
+    pub state: String,
+    pub ageclass: usize,
+    pub count: usize,
+}
+
+/// Counts by state for a value of a population attribute (see `PopulationConfig`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AttributeCountRecord {
+    pub time: f64,
+    pub attribute: String,
+    pub value: String,
+    pub state: String,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndividualRecord {
+    pub time: f64,
+    pub id: usize,
+    pub ageclass: usize,
+    pub initial_state: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InfectionRecord {
+    pub time: f64,
+    pub infected_id: usize,
+    pub infectious_id: usize,
+    pub setting: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransitionRecord {
+    pub time: f64,
+    pub id: usize,
+    pub start_state: String,
+    pub end_state: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RtRecord {
+    pub time_discrete: i64,
+    pub n_primary: usize,
+    pub n_secondary: usize,
+}
+
+/// Binned interval counts; `interval` is the lower bound of the bin.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IntervalRecord {
+    pub time_discrete: i64,
+    pub interval: f64,
+    pub count: usize,
+}
+
+/// Individual-level events, as accumulated by an `EventRecorder`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EventRecords {
+    pub individuals: Vec<IndividualRecord>,
+    pub infections: Vec<InfectionRecord>,
+    pub transitions: Vec<TransitionRecord>,
+}
+
+impl EventRecords {
+    pub fn append(&mut self, mut other: EventRecords) {
+        self.individuals.append(&mut other.individuals);
+        self.infections.append(&mut other.infections);
+        self.transitions.append(&mut other.transitions);
+    }
+}
+
+/// Seed for natural-history streams, from a stream jumped ahead of the transmission stream.
+fn natural_history_seed(rng_seed: u64) -> u64 {
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(rng_seed);
+    rng.jump();
+    rng.gen()
+}
 
 #[derive(Debug, Copy, Clone)]
 struct Individual {
@@ -349,36 +742,263 @@ struct Individual {
     ageclass: usize,
     state_id: usize,
     t_infected: Option<f64>,
+    
+    // Infector, if infected during the simulation and there is an onset state
+    // (used to match up onsets for serial intervals)
+    infector: Option<IndividualKey>,
+    infector_t_onset: Option<f64>,
+    t_onset: Option<f64>,
+    
+    // Number of infected states entered, which keys natural-history draws with
+    // `RngStreams::Separate` so that re-entering a state doesn't repeat them
+    n_states_entered: u64,
 }
 
 impl Individual {
     fn new(id: usize, ageclass: usize, state_id: usize, t_infected: Option<f64>) -> Self {
-        Individual { id, ageclass, state_id, t_infected }
+        Individual {
+            id, ageclass, state_id, t_infected,
+            infector: None, infector_t_onset: None, t_onset: None, n_states_entered: 0,
+        }
+    }
+
+}
+
+/// State of full-agent mode (see `PopulationConfig`), in which every person in a population
+/// is an individual, in the arena slot given by their position in the population.
+#[derive(Debug, Clone)]
+struct Agents {
+    model: Rc<PopulationModel>,
+    // Susceptibility of each person while susceptible (zero otherwise), by ageclass and
+    // position in `ageclass_members`, for drawing infectees
+    susceptible: Vec<FenwickTree>,
+    ageclass_members: Vec<Vec<usize>>,
+    ageclass_positions: Vec<usize>,
+    group_layers: Vec<GroupLayerState>,
+    // Counts by output attribute, value and state
+    attribute_counts: Vec<Vec<Vec<usize>>>,
+}
+
+/// Susceptible and infectious members of the groups of a `GroupLayer`, and the resulting
+/// rate of infection within each group.
+#[derive(Debug, Clone)]
+struct GroupLayerState {
+    group_of: Vec<usize>,
+    n_susceptible: Vec<usize>,
+    susceptible_weight: Vec<f64>,
+    n_infectious: Vec<usize>,
+    rates: FenwickTree,
+}
+
+impl Agents {
+    fn new(model: PopulationModel, n_ageclasses: usize, n_states: usize) -> Self {
+        let mut ageclass_members = vec![Vec::new(); n_ageclasses];
+        let mut ageclass_positions = Vec::with_capacity(model.len());
+        for person in 0..model.len() {
+            let members = &mut ageclass_members[model.ageclass(person)];
+            ageclass_positions.push(members.len());
+            members.push(person);
+        }
+        let group_layers = model.group_layers.iter().map(|layer| {
+            let mut group_of = vec![NOT_IN_SET; model.len()];
+            for (group, members) in layer.groups.iter().enumerate() {
+                for person in members {
+                    group_of[*person] = group;
+                }
+            }
+            let n_groups = layer.groups.len();
+            GroupLayerState {
+                group_of,
+                n_susceptible: vec![0; n_groups],
+                susceptible_weight: vec![0.0; n_groups],
+                n_infectious: vec![0; n_groups],
+                rates: FenwickTree::new(vec![0.0; n_groups]),
+            }
+        }).collect();
+        let attribute_counts = model.output_attributes().map(
+            |attribute| vec![vec![0; n_states]; attribute.values.len()]
+        ).collect();
+        
+        Self {
+            susceptible: ageclass_members.iter().map(|members| FenwickTree::new(vec![0.0; members.len()])).collect(),
+            model: Rc::new(model),
+            ageclass_members,
+            ageclass_positions,
+            group_layers,
+            attribute_counts,
+        }
+    }
+    
+    /// Updates everything that depends on a person's state, as they move from `from`
+    /// (or, as they are created, from no state) to `to`.
+    fn change_state(&mut self, person: usize, from: Option<&State>, to: &State) {
+        let susceptibility = self.model.susceptibility[person];
+        let was_susceptible = from.is_some_and(|state| state.is_susceptible());
+        let was_infectious = from.is_some_and(|state| state.is_infectious());
+        
+        if was_susceptible != to.is_susceptible() {
+            let weight = if to.is_susceptible() { susceptibility } else { 0.0 };
+            self.susceptible[self.model.ageclass(person)].set(self.ageclass_positions[person], weight);
+        }
+        
+        for (layer, layer_state) in self.model.group_layers.iter().zip(&mut self.group_layers) {
+            let group = layer_state.group_of[person];
+            if group == NOT_IN_SET {
+                continue;
+            }
+            match (was_susceptible, to.is_susceptible()) {
+                (false, true) => {
+                    layer_state.n_susceptible[group] += 1;
+                    layer_state.susceptible_weight[group] += susceptibility;
+                },
+                (true, false) => {
+                    // Reset to exactly zero once empty, so that rounding can't leave a positive rate
+                    layer_state.n_susceptible[group] -= 1;
+                    layer_state.susceptible_weight[group] = if layer_state.n_susceptible[group] == 0 {
+                        0.0
+                    }
+                    else {
+                        (layer_state.susceptible_weight[group] - susceptibility).max(0.0)
+                    };
+                },
+                _ => {},
+            }
+            match (was_infectious, to.is_infectious()) {
+                (false, true) => layer_state.n_infectious[group] += 1,
+                (true, false) => layer_state.n_infectious[group] -= 1,
+                _ => {},
+            }
+            let n = layer.groups[group].len();
+            let rate = if n > 1 {
+                layer.beta * layer_state.susceptible_weight[group] * layer_state.n_infectious[group] as f64
+                    / (n - 1) as f64
+            }
+            else {
+                0.0
+            };
+            layer_state.rates.set(group, rate);
+        }
+        
+        for (i, attribute) in self.model.output_attributes().enumerate() {
+            let value = attribute.indices[person];
+            if let Some(from) = from {
+                self.attribute_counts[i][value][from.id] -= 1;
+            }
+            self.attribute_counts[i][value][to.id] += 1;
+        }
     }
     
-    fn update_state(&self, state_id: usize) -> Self {
-        Self { state_id, ..*self }
+    fn group_contact_rate(&self) -> f64 {
+        self.group_layers.iter().map(|layer_state| layer_state.rates.total()).sum()
+    }
+}
+
+/// Where a contact leading to infection happened: a contact setting, or a group layer
+/// in full-agent mode.
+#[derive(Debug, Copy, Clone)]
+enum ContactSource {
+    Setting(usize),
+    Group(usize),
+}
+
+/// How random draws are divided among random number streams.
+///
+/// With `Shared` (the default), every draw comes from one stream, so any change to the
+/// course of an epidemic changes all later draws. With `Separate`, transmission (contact
+/// times and choice of infector) has its own stream, and each individual's natural history
+/// (durations and next states) is drawn from streams keyed by the individual and by how many
+/// states they have entered, so paired runs with the same seed differ only where the change
+/// actually matters. Individuals are identified by id: by position in the population in
+/// full-agent mode, and otherwise by order of infection (after the initial infecteds), so the
+/// `n`th infection of one run shares its natural history with the `n`th infection of the other.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum RngStreams {
+    #[default]
+    Shared,
+    Separate,
+}
+
+// Purposes of per-individual draws with `RngStreams::Separate`
+const DRAW_DURATION: u64 = 0;
+const DRAW_NEXT_STATE: u64 = 1;
+
+/// Contact parameters over time: `beta[i]` and `C[i]` apply until `t_change[i]`, and the
+/// last of each after the last change.
+pub struct ContactSchedule {
+    pub t_change: Vec<f64>,
+    pub beta: Vec<f64>,
+    pub C: Vec<Vec<Vec<f64>>>,
+}
+
+/// Optional parts of a simulation (see `Simulation::new`).
+pub struct SimulationOptions {
+    pub onset_state_id: Option<usize>,
+    pub interval_bin_width: f64,
+    pub population: Option<PopulationModel>,
+    pub rng_seed: Option<u64>,
+    pub rng_streams: RngStreams,
+    pub observers: Vec<ObserverRef>,
+}
+
+impl Default for SimulationOptions {
+    fn default() -> Self {
+        Self {
+            onset_state_id: None,
+            interval_bin_width: 1.0,
+            population: None,
+            rng_seed: None,
+            rng_streams: RngStreams::default(),
+            observers: Vec::new(),
+        }
     }
 }
 
 pub struct Simulation {
     n_ageclasses: usize,
-    states: Vec<State>,
+    // Shared, so that events can refer to states (whose transition probabilities have
+    // a row per ageclass) without copying them
+    states: Rc<Vec<State>>,
     susceptible_state_id: usize,
     initial_infected_state_id: usize,
+    onset_state_id: Option<usize>,
     t_change: Vec<f64>,
     beta: Vec<f64>,
-    C: Vec<Vec<Vec<f64>>>,
+    C: Vec<SparseMatrix>,
+    // Names and contact matrices (by contact period) of settings, if infections are attributed to them
+    setting_names: Vec<String>,
+    setting_C: Vec<Vec<SparseMatrix>>,
     intervention_index: usize,
     counts: Counts,
     C_I_over_N: CIOverN,
+    // Rate of infection of each ageclass per unit `beta`, S times its force of infection
+    contact_rates: FenwickTree,
+    // Every person in the population, in full-agent mode
+    agents: Option<Agents>,
     pub t: f64,
     next_id: usize,
-    individuals: BTreeMap<usize, Individual>,
-    infectious_individuals: Vec<VecSet<usize>>,
-    t_contact: Vec<f64>,
-    event_queue: BTreeSet<Event>,
+    // Individuals currently infected, in reusable slots; `infectious_individuals` and
+    // events refer to them by slot
+    individuals: Arena<Individual>,
+    infectious_individuals: GroupedVecSet,
+    t_contact: f64,
+    // Transition events, earliest first; events for individuals no longer present are skipped
+    event_queue: BinaryHeap<Reverse<Event>>,
+    rng_seed: u64,
     rng: Xoshiro256PlusPlus,
+    rng_streams: RngStreams,
+    natural_history_seed: u64,
+    
+    observers: Vec<ObserverRef>,
+    n_infections: usize,
+    n_transitions: usize,
+    rt_statistics: BTreeMap<i64, (usize, usize)>,
+    generation_intervals: IntervalHistogram,
+    serial_intervals: IntervalHistogram,
+    
+    // Infectees whose infector has not yet had onset, keyed by infector id
+    awaiting_infector_onset: BTreeMap<usize, Vec<IndividualKey>>,
+    // Onset times of infectees whose onset preceded their infector's, keyed by infector id
+    early_onsets: BTreeMap<usize, Vec<f64>>,
 }
 
 impl Simulation {
@@ -387,42 +1007,24 @@ impl Simulation {
         states: Vec<State>,
         susceptible_state_id: usize,
         initial_infected_state_id: usize,
-        t_change: Vec<f64>,
-        beta: Vec<f64>,
-        C: Vec<Vec<Vec<f64>>>,
+        contacts: ContactSchedule,
         initial_counts: Counts,
-        rng_seed_opt: Option<u32>,
-        db_transaction: &mut rusqlite::Transaction,
-        record_all_events: bool,
+        options: SimulationOptions,
     ) -> Self {
-        let rng_seed = if let Some(rng_seed) = rng_seed_opt {
+        let ContactSchedule { t_change, beta, C } = contacts;
+        let SimulationOptions {
+            onset_state_id, interval_bin_width, population, rng_seed, rng_streams, observers
+        } = options;
+        let rng_seed = if let Some(rng_seed) = rng_seed {
             rng_seed
         }
         else {
             rand::thread_rng().gen()
         };
-    
-        db_transaction.execute_batch(&unindent("
-            CREATE TABLE Meta (key, value);
-            CREATE TABLE Individuals (time REAL, id INTEGER, ageclass INTEGER, initial_state TEXT);
-            CREATE TABLE Infections (time REAL, infected_id INTEGER, infectious_id INTEGER);
-            CREATE TABLE Transitions (time REAL, id INTEGER, start_state TEXT, end_state TEXT);
-            CREATE TABLE Counts (time REAL, state TEXT, ageclass INTEGER, count INTEGER);
-            CREATE TABLE RtSufficientStatistics (
-                time_discrete INTEGER NOT NULL PRIMARY KEY, n_primary INTEGER, n_secondary INTEGER
-            );
-        ")).unwrap();
-    
-        db_transaction.execute(
-            "INSERT INTO Meta VALUES ('rng_seed', ?);",
-            rusqlite::params![rng_seed]
-        ).unwrap();
         
         let n_states = states.len();
         
-        let infectious_individuals =
-            std::iter::repeat(VecSet::new()).take(n_ageclasses).collect();
-        
+        let C: Vec<SparseMatrix> = C.iter().map(|C| SparseMatrix::from_dense(C)).collect();
         let C_I_over_N = CIOverN::new(
             C[0].clone(),
             std::iter::repeat(0).take(n_ageclasses).collect(),
@@ -431,59 +1033,240 @@ impl Simulation {
         
         let mut sim = Self {
             n_ageclasses,
-            states,
+            states: Rc::new(states),
             susceptible_state_id,
             initial_infected_state_id,
+            onset_state_id,
             t_change,
             beta,
             C,
+            setting_names: Vec::new(),
+            setting_C: Vec::new(),
             intervention_index: 0,
             counts: Counts::new(n_states, n_ageclasses),
             C_I_over_N,
+            contact_rates: FenwickTree::new(vec![0.0; n_ageclasses]),
+            agents: population.map(|model| Agents::new(model, n_ageclasses, n_states)),
             t: 0.0,
             next_id: 1,
-            individuals: BTreeMap::new(),
-            infectious_individuals,
-            t_contact: std::iter::repeat(INFINITY).take(n_ageclasses).collect(),
-            event_queue: BTreeSet::new(),
-            rng: Xoshiro256PlusPlus::seed_from_u64(rng_seed as u64),
+            individuals: Arena::new(),
+            infectious_individuals: GroupedVecSet::new(n_ageclasses),
+            t_contact: INFINITY,
+            event_queue: BinaryHeap::new(),
+            rng_seed,
+            rng: Xoshiro256PlusPlus::seed_from_u64(rng_seed),
+            rng_streams,
+            natural_history_seed: natural_history_seed(rng_seed),
+            observers,
+            n_infections: 0,
+            n_transitions: 0,
+            rt_statistics: BTreeMap::new(),
+            generation_intervals: IntervalHistogram::new(interval_bin_width),
+            serial_intervals: IntervalHistogram::new(interval_bin_width),
+            awaiting_infector_onset: BTreeMap::new(),
+            early_onsets: BTreeMap::new(),
         };
         
-        // Initialize initial infecteds
-        sim.initialize_individuals(
-            &initial_counts,
-            db_transaction,
-            record_all_events,
-        );
-    
+        // Initialize initial infecteds, or everyone in full-agent mode
+        if sim.agents.is_some() {
+            sim.initialize_population();
+        }
+        else {
+            sim.initialize_individuals(&initial_counts);
+        }
+        
+        sim.update_contact_rates(0..n_ageclasses);
         sim.update_contact();
+        
+        sim
+    }
     
+    pub fn rng_seed(&self) -> u64 {
+        self.rng_seed
+    }
+    
+    /// An exact copy of the current state, including random number generator state, but
+    /// without observers: the copy continues exactly as the original would.
+    pub fn checkpoint(&self) -> Self {
+        Self {
+            n_ageclasses: self.n_ageclasses,
+            states: self.states.clone(),
+            susceptible_state_id: self.susceptible_state_id,
+            initial_infected_state_id: self.initial_infected_state_id,
+            onset_state_id: self.onset_state_id,
+            t_change: self.t_change.clone(),
+            beta: self.beta.clone(),
+            C: self.C.clone(),
+            setting_names: self.setting_names.clone(),
+            setting_C: self.setting_C.clone(),
+            intervention_index: self.intervention_index,
+            counts: self.counts.clone(),
+            C_I_over_N: self.C_I_over_N.clone(),
+            contact_rates: self.contact_rates.clone(),
+            agents: self.agents.clone(),
+            t: self.t,
+            next_id: self.next_id,
+            individuals: self.individuals.clone(),
+            infectious_individuals: self.infectious_individuals.clone(),
+            t_contact: self.t_contact,
+            event_queue: self.event_queue.clone(),
+            rng_seed: self.rng_seed,
+            rng: self.rng.clone(),
+            rng_streams: self.rng_streams,
+            natural_history_seed: self.natural_history_seed,
+            observers: Vec::new(),
+            n_infections: self.n_infections,
+            n_transitions: self.n_transitions,
+            rt_statistics: self.rt_statistics.clone(),
+            generation_intervals: self.generation_intervals.clone(),
+            serial_intervals: self.serial_intervals.clone(),
+            awaiting_infector_onset: self.awaiting_infector_onset.clone(),
+            early_onsets: self.early_onsets.clone(),
+        }
+    }
+    
+    /// An independent copy of the current state, without observers, that continues
+    /// with its own random number stream.
+    ///
+    /// The pending contact time is redrawn, which leaves the process unchanged since it
+    /// is exponentially distributed; transitions already scheduled are kept.
+    pub fn fork(&self, rng_seed: u64) -> Self {
+        let mut sim = self.checkpoint();
+        sim.rng_seed = rng_seed;
+        sim.rng = Xoshiro256PlusPlus::seed_from_u64(rng_seed);
+        sim.natural_history_seed = natural_history_seed(rng_seed);
+        sim.update_contact();
         sim
     }
     
-    pub fn write_counts(&self, db_transaction: &mut rusqlite::Transaction) {
-        for state in &self.states {
+    /// Attributes each subsequent infection to one of the named settings, with probability
+    /// proportional to the setting's contacts between the ageclasses of infectee and infector.
+    ///
+    /// `C[i][s]` is the contact matrix of setting `s` in contact period `i`; the setting
+    /// matrices for each period must sum to the contact matrix the simulation was created with.
+    pub fn set_contact_settings(&mut self, names: Vec<String>, C: Vec<Vec<Vec<Vec<f64>>>>) {
+        assert_eq!(C.len(), self.C.len());
+        assert!(C.iter().all(|settings| settings.len() == names.len()));
+        self.setting_names = names;
+        self.setting_C = C.iter().map(
+            |settings| settings.iter().map(|C| SparseMatrix::from_dense(C)).collect()
+        ).collect();
+    }
+    
+    /// Cumulative number of infections during the simulation, not including initial infecteds.
+    pub fn n_infections(&self) -> usize {
+        self.n_infections
+    }
+    
+    /// Cumulative number of transitions between states during the simulation.
+    pub fn n_transitions(&self) -> usize {
+        self.n_transitions
+    }
+    
+    /// Counts by state and ageclass at the current time.
+    pub fn count_records(&self) -> Vec<CountRecord> {
+        let mut records = Vec::with_capacity(self.states.len() * self.n_ageclasses);
+        for state in self.states.iter() {
             for ageclass in 0..self.n_ageclasses {
-                db_transaction.execute(
-                    "INSERT INTO Counts VALUES (?, ?, ?, ?);",
-                    rusqlite::params![
-                        self.t, state.name,
-                        to_i64(ageclass + 1),
-                        to_i64(self.counts.get(state.id, ageclass))
-                    ]
-                ).unwrap();
+                records.push(CountRecord {
+                    time: self.t,
+                    state: state.name.clone(),
+                    ageclass,
+                    count: self.counts.get(state.id, ageclass),
+                });
             }
         }
+        records
     }
     
-    fn initialize_individuals(
-        &mut self, initial_counts: &Counts,
-        db_transaction: &mut rusqlite::Transaction,
-        record_all_events: bool,
-    ) {
-        let mut insert_individual = db_transaction.prepare(INDIVIDUALS_SQL).unwrap();
-        
-        for state in self.states.clone() {
+    /// Counts by state for each value of each output attribute at the current time,
+    /// in full-agent mode (see `PopulationConfig`).
+    pub fn attribute_count_records(&self) -> Vec<AttributeCountRecord> {
+        let mut records = Vec::new();
+        if let Some(agents) = &self.agents {
+            for (i, attribute) in agents.model.output_attributes().enumerate() {
+                for (j, value) in attribute.values.iter().enumerate() {
+                    for state in self.states.iter() {
+                        records.push(AttributeCountRecord {
+                            time: self.t,
+                            attribute: attribute.name.clone(),
+                            value: value.clone(),
+                            state: state.name.clone(),
+                            count: agents.attribute_counts[i][j][state.id],
+                        });
+                    }
+                }
+            }
+        }
+        records
+    }
+    
+    /// Registers an observer, which will be notified of all subsequent events.
+    ///
+    /// Observers that need to see the creation of initial infecteds must instead be
+    /// passed to `Simulation::new`.
+    pub fn add_observer(&mut self, observer: ObserverRef) {
+        self.observers.push(observer);
+    }
+    
+    fn notify<F>(&self, mut f: F) where F: FnMut(&mut dyn SimulationObserver) {
+        for observer in &self.observers {
+            f(&mut *observer.borrow_mut());
+        }
+    }
+    
+    pub fn n_ageclasses(&self) -> usize {
+        self.n_ageclasses
+    }
+    
+    pub fn t_change(&self) -> &[f64] {
+        &self.t_change
+    }
+    
+    pub fn intervention_index(&self) -> usize {
+        self.intervention_index
+    }
+    
+    pub fn states(&self) -> &[State] {
+        &self.states
+    }
+    
+    pub fn counts(&self) -> &Counts {
+        &self.counts
+    }
+    
+    /// Number of people infected during each discrete timestep (`n_primary`), and
+    /// the number of infections they have caused so far (`n_secondary`).
+    ///
+    /// Secondary infections accumulate long after the timestep they are assigned to,
+    /// so these are only complete at the end of the simulation.
+    pub fn rt_records(&self) -> Vec<RtRecord> {
+        self.rt_statistics.iter().map(|(time_discrete, (n_primary, n_secondary))| {
+            RtRecord {
+                time_discrete: *time_discrete,
+                n_primary: *n_primary,
+                n_secondary: *n_secondary,
+            }
+        }).collect()
+    }
+    
+    /// Intervals between the infections of infectors and their infectees, recorded at the
+    /// infectee's infection. Infections by initial infecteds, whose own infection times are
+    /// unknown, are not included.
+    pub fn generation_intervals(&self) -> &IntervalHistogram {
+        &self.generation_intervals
+    }
+    
+    /// Intervals between the onsets of infectors and their infectees, recorded at the
+    /// infectee's onset, for every pair whose onsets both occur during the simulation:
+    /// initial infecteds are included if they have onset after the start, but not if they
+    /// start in or past the onset state, as their onset times are unknown.
+    pub fn serial_intervals(&self) -> &IntervalHistogram {
+        &self.serial_intervals
+    }
+    
+    fn initialize_individuals(&mut self, initial_counts: &Counts) {
+        for state in self.states.clone().iter() {
             if state.is_susceptible() || state.is_final() {
                 for ageclass in 0..self.n_ageclasses {
                     self.counts.increment(state.id, ageclass, initial_counts.get(state.id, ageclass));
@@ -492,71 +1275,219 @@ impl Simulation {
             else if state.is_infected() {
                 for ageclass in 0..self.n_ageclasses {
                     for _ in 0..initial_counts.get(state.id, ageclass) {
-                        self.add_individual(
-                            ageclass, &state, true,
-                            &mut insert_individual,
-                            record_all_events,
-                        );
+                        self.add_individual(ageclass, state, None);
                     }
                 }
             }
         }
     }
     
+    /// Creates every person in the population as an individual, in their initial state.
+    fn initialize_population(&mut self) {
+        let model = self.agents.as_ref().unwrap().model.clone();
+        let states = self.states.clone();
+        for person in 0..model.len() {
+            let ageclass = model.ageclass(person);
+            let state = &states[model.initial_state_id(person)];
+            let id = self.next_id;
+            self.next_id += 1;
+            let key = IndividualKey { slot: self.individuals.insert(Individual::new(id, ageclass, state.id, None)), id };
+            assert_eq!(key.slot, person);
+            
+            self.agents.as_mut().unwrap().change_state(person, None, state);
+            self.counts.increment(state.id, ageclass, 1);
+            let t = self.t;
+            self.notify(|o| o.on_individual_created(t, id, ageclass, state, true));
+            
+            if state.is_infectious() {
+                self.infectious_individuals.add(ageclass, key.slot);
+                self.increment_infectious(ageclass);
+            }
+            if state.is_infected() {
+                self.insert_transition_event(state, key);
+            }
+        }
+    }
+    
     fn add_individual(
-        &mut self, ageclass: usize, state: &State, is_initial: bool,
-        insert_individual: &mut rusqlite::Statement,
-        record_all_events: bool,
+        &mut self, ageclass: usize, state: &State, infector_opt: Option<(Individual, IndividualKey)>,
     ) -> usize {
         let id = self.next_id;
         self.next_id += 1;
-
-        let individual = Individual::new(
+        let is_initial = infector_opt.is_none();
+        
+        let mut individual = Individual::new(
             id, ageclass, state.id,
             if is_initial { None } else { Some(self.t) }
         );
-        self.individuals.insert(id, individual);
-        
-        if record_all_events {
-            insert_individual.execute(
-                rusqlite::params![self.t, i64::try_from(id).unwrap(), i64::try_from(ageclass).unwrap(), state.name]
-            ).unwrap();
+        let mut infector_key = None;
+        if let Some((infector, key)) = infector_opt {
+            if self.onset_state_id.is_some() {
+                individual.infector = Some(key);
+                individual.infector_t_onset = infector.t_onset;
+                infector_key = Some(key);
+            }
         }
-
+        let key = IndividualKey { slot: self.individuals.insert(individual), id };
+        if let Some(infector_key) = infector_key {
+            if individual.infector_t_onset.is_none() {
+                self.awaiting_infector_onset.entry(infector_key.id).or_default().push(key);
+            }
+        }
+        
+        let t = self.t;
+        self.notify(|o| o.on_individual_created(t, id, ageclass, state, is_initial));
+        
         if state.is_infectious() {
-            self.infectious_individuals[ageclass].add(id);
-            self.C_I_over_N.increment(ageclass);
+            self.infectious_individuals.add(ageclass, key.slot);
+            self.increment_infectious(ageclass);
         }
-        self.insert_transition_event(state, individual.id);
+        self.insert_transition_event(state, key);
         
         if is_initial {
             self.counts.increment(state.id, ageclass, 1);
         }
         else {
             self.counts.transition(self.susceptible_state_id, state.id, ageclass);
+            self.update_contact_rates(std::iter::once(ageclass));
+            if Some(state.id) == self.onset_state_id {
+                self.record_onset(key);
+            }
         }
-
+        
         id
     }
     
-    fn insert_transition_event(&mut self, state: &State, individual_id: usize) {
-        let t = self.draw_transition_time(state);
-        self.event_queue.insert(Event::new(t, individual_id));
+    /// Infects the susceptible `person` in full-agent mode, as `add_individual` does for a new individual.
+    fn infect_person(&mut self, person: usize, state: &State, infector: (Individual, IndividualKey)) -> usize {
+        let t = self.t;
+        let (infector, infector_key) = infector;
+        let individual = self.individuals.get_mut(person).unwrap();
+        let key = IndividualKey { slot: person, id: individual.id };
+        let ageclass = individual.ageclass;
+        individual.state_id = state.id;
+        individual.t_infected = Some(t);
+        if self.onset_state_id.is_some() {
+            individual.infector = Some(infector_key);
+            individual.infector_t_onset = infector.t_onset;
+            if infector.t_onset.is_none() {
+                self.awaiting_infector_onset.entry(infector_key.id).or_default().push(key);
+            }
+        }
+        
+        let states = self.states.clone();
+        self.agents.as_mut().unwrap().change_state(person, Some(&states[self.susceptible_state_id]), state);
+        if state.is_infectious() {
+            self.infectious_individuals.add(ageclass, key.slot);
+            self.increment_infectious(ageclass);
+        }
+        self.insert_transition_event(state, key);
+        
+        self.counts.transition(self.susceptible_state_id, state.id, ageclass);
+        self.update_contact_rates(std::iter::once(ageclass));
+        if Some(state.id) == self.onset_state_id {
+            self.record_onset(key);
+        }
+        
+        key.id
     }
     
-    fn S(&self, ageclass: usize) -> f64 {
-        self.counts.get(self.susceptible_state_id, ageclass) as f64
+    /// The individual referred to by `key`, if it has not been removed.
+    fn individual(&self, key: IndividualKey) -> Option<&Individual> {
+        self.individuals.get(key.slot).filter(|individual| individual.id == key.id)
     }
     
-    fn update_contact(&mut self) {
-//        println!("update_contact");
+    fn individual_mut(&mut self, key: IndividualKey) -> Option<&mut Individual> {
+        self.individuals.get_mut(key.slot).filter(|individual| individual.id == key.id)
+    }
+    
+    fn record_onset(&mut self, key: IndividualKey) {
+        let t = self.t;
+        let id = key.id;
+        let individual = self.individual_mut(key).unwrap();
+        individual.t_onset = Some(t);
+        let individual = *individual;
+        
+        // Serial interval with respect to this individual's infector
+        if let Some(infector_t_onset) = individual.infector_t_onset {
+            self.serial_intervals.record(t, t - infector_t_onset);
+        }
+        else if let Some(infector) = individual.infector {
+            // Infector hasn't had onset yet; if they're gone (or, in full-agent mode,
+            // in a final state), they never will
+            let states = &self.states;
+            if self.individual(infector).is_some_and(|infector| !states[infector.state_id].is_final()) {
+                self.early_onsets.entry(infector.id).or_default().push(t);
+            }
+        }
         
-        for i in 0..self.n_ageclasses {
-            let rate = self.beta[self.intervention_index] * self.S(i) * self.C_I_over_N.row_sum(i);
-            self.t_contact[i] = self.t + self.draw_exponential(rate);
+        // Serial intervals with respect to this individual's infectees
+        if let Some(onsets) = self.early_onsets.remove(&id) {
+            for t_infectee_onset in onsets {
+                self.serial_intervals.record(t_infectee_onset, t_infectee_onset - t);
+            }
+        }
+        if let Some(infectees) = self.awaiting_infector_onset.remove(&id) {
+            for infectee in infectees {
+                if let Some(infectee) = self.individual_mut(infectee) {
+                    if infectee.t_onset.is_none() {
+                        infectee.infector_t_onset = Some(t);
+                    }
+                }
+            }
+        }
+    }
+    
+    fn insert_transition_event(&mut self, state: &State, individual: IndividualKey) {
+        let entry = {
+            let individual = self.individual_mut(individual).unwrap();
+            individual.n_states_entered += 1;
+            individual.n_states_entered
+        };
+        let t = self.draw_transition_time(state, individual.id, entry);
+        self.event_queue.push(Reverse(Event::new(t, individual)));
+    }
+    
+    /// Number of susceptibles in an ageclass or, in full-agent mode, their total susceptibility.
+    fn S(&self, ageclass: usize) -> f64 {
+        match &self.agents {
+            Some(agents) => agents.susceptible[ageclass].total(),
+            None => self.counts.get(self.susceptible_state_id, ageclass) as f64,
+        }
+    }
+    
+    fn increment_infectious(&mut self, ageclass: usize) {
+        self.C_I_over_N.increment(ageclass);
+        let rows: Vec<usize> = self.C_I_over_N.rows_with_contacts_in(ageclass).collect();
+        self.update_contact_rates(rows.into_iter());
+    }
+    
+    fn decrement_infectious(&mut self, ageclass: usize) {
+        self.C_I_over_N.decrement(ageclass);
+        let rows: Vec<usize> = self.C_I_over_N.rows_with_contacts_in(ageclass).collect();
+        self.update_contact_rates(rows.into_iter());
+    }
+    
+    /// Recomputes the infection rates of ageclasses whose susceptible count or force
+    /// of infection has changed.
+    fn update_contact_rates<I>(&mut self, ageclasses: I) where I: Iterator<Item = usize> {
+        for i in ageclasses {
+            let rate = self.S(i) * self.C_I_over_N.row_sum(i);
+            self.contact_rates.set(i, rate);
         }
     }
     
+    /// Rate of infection within groups, in full-agent mode.
+    fn group_contact_rate(&self) -> f64 {
+        self.agents.as_ref().map_or(0.0, |agents| agents.group_contact_rate())
+    }
+    
+    /// Draws the time of the next contact from the total infection rate.
+    fn update_contact(&mut self) {
+        let rate = self.beta[self.intervention_index] * self.contact_rates.total() + self.group_contact_rate();
+        self.t_contact = self.t + self.draw_exponential(rate);
+    }
+    
     fn draw_exponential(&mut self, rate: f64) -> f64 {
         assert!(rate >= 0.0);
         if rate == 0.0 {
@@ -567,19 +1498,35 @@ impl Simulation {
         }
     }
     
-    fn draw_gamma(&mut self, shape: f64, scale: f64) -> f64 {
-        assert!(shape > 0.0);
-        assert!(scale > 0.0);
-        Gamma::new(shape, scale).unwrap().sample(&mut self.rng)
+    /// Makes a natural-history draw for an individual in a state, from the shared stream
+    /// or, with `RngStreams::Separate`, from a stream specific to the individual, their
+    /// `entry`th infected state (counting from 1), the state and the purpose of the draw.
+    fn draw_natural_history<T, F>(
+        &mut self, individual_id: usize, entry: u64, state_id: usize, purpose: u64, f: F
+    ) -> T
+        where F: FnOnce(&mut Xoshiro256PlusPlus) -> T
+    {
+        match self.rng_streams {
+            RngStreams::Shared => f(&mut self.rng),
+            RngStreams::Separate => {
+                let key: Vec<u8> = [self.natural_history_seed, individual_id as u64, entry, state_id as u64, purpose]
+                    .iter().flat_map(|x| x.to_le_bytes().to_vec()).collect();
+                f(&mut Xoshiro256PlusPlus::seed_from_u64(fnv1a_64(&key)))
+            },
+        }
     }
     
-    fn draw_transition_time(&mut self, state: &State) -> f64 {
+    fn draw_transition_time(&mut self, state: &State, individual_id: usize, entry: u64) -> f64 {
         match &state.detail {
             StateDetail::Infected(Some(infected_state)) => {
-                self.t + self.draw_gamma(
-                    infected_state.gamma_shape,
-                    infected_state.mean_duration / infected_state.gamma_shape
-                )
+                // Durations follow the parameters in effect on entering the state
+                let (mean_duration, shape, _) = infected_state.natural_history_at(self.t);
+                let scale = mean_duration / shape;
+                assert!(shape > 0.0);
+                assert!(scale > 0.0);
+                self.t + self.draw_natural_history(individual_id, entry, state.id, DRAW_DURATION, |rng| {
+                    Gamma::new(shape, scale).unwrap().sample(rng)
+                })
             },
             _ => {
                 panic!()
@@ -587,33 +1534,16 @@ impl Simulation {
         }
     }
     
-    fn get_next_contact(&self) -> (f64, Option<usize>) {
-        let mut t = INFINITY;
-        let mut ageclass_opt = None;
-        for i in 0..self.n_ageclasses {
-            if self.t_contact[i] < t {
-                t = self.t_contact[i];
-                ageclass_opt = Some(i);
-            }
+    pub fn simulate(&mut self, t_until: f64) -> bool {
+        for observer in &self.observers {
+            observer.borrow_mut().on_step_start(self);
         }
-        (t, ageclass_opt)
-    }
-    
-    pub fn simulate(
-        &mut self, t_until: f64, db_transaction: &mut rusqlite::Transaction, record_all_events: bool,
-    ) -> bool {
-        let mut insert_individual = db_transaction.prepare(INDIVIDUALS_SQL).unwrap();
-        let mut insert_infection = db_transaction.prepare(INFECTIONS_SQL).unwrap();
-        let mut insert_transition = db_transaction.prepare(TRANSITIONS_SQL).unwrap();
-        let mut insert_rt = db_transaction.prepare(RT_INSERT_SQL).unwrap();
-        let mut increment_rt_primary = db_transaction.prepare(RT_INCREMENT_PRIMARY).unwrap();
-        let mut increment_rt_secondary = db_transaction.prepare(RT_INCREMENT_SECONDARY).unwrap();
         
         let mut done = false;
         while self.t < t_until {
             let mut found_event = false;
             
-            let (t_contact, ageclass_opt) = self.get_next_contact();
+            let t_contact = self.t_contact;
             let t_transition = self.t_next_transition().unwrap_or(INFINITY);
 //            println!("t_contact = {}, t_transition = {}", t_contact, t_transition);
             
@@ -625,23 +1555,21 @@ impl Simulation {
             
             if t_contact < t_transition {
                 if t_contact <= t_until {
-                    self.do_contact_event(
-                        t_contact, ageclass_opt.unwrap(),
-                        &mut insert_individual,
-                        &mut insert_infection,
-                        &mut insert_transition,
-                        &mut insert_rt,
-                        &mut increment_rt_primary,
-                        &mut increment_rt_secondary,
-                        record_all_events,
-                    );
+                    if self.agents.is_some() {
+                        self.do_agent_contact_event(t_contact);
+                    }
+                    else {
+                        // Draw the ageclass of the infected individual proportional to its rate
+                        let ageclass = self.contact_rates.sample(&mut self.rng);
+                        self.do_contact_event(t_contact, ageclass);
+                    }
                     found_event = true;
                 }
             }
             else {
                 if t_transition.is_finite() && t_transition <= t_until {
                     let event = self.dequeue_next_transition_event().unwrap();
-                    self.do_transition_event(event, &mut insert_transition, record_all_events);
+                    self.do_transition_event(event);
                     found_event = true;
                 }
             }
@@ -655,91 +1583,176 @@ impl Simulation {
                 if self.t > self.t_change[self.intervention_index] {
                     self.intervention_index += 1;
                     self.C_I_over_N.update_C(self.C[self.intervention_index].clone());
+                    self.update_contact_rates(0..self.n_ageclasses);
                     self.update_contact();
                     
-                    eprintln!("Updated intervention to {} at t = {}", self.intervention_index, self.t);
+                    let (t, intervention_index) = (self.t, self.intervention_index);
+                    self.notify(|o| o.on_intervention_change(t, intervention_index));
                 }
             }
         }
-    
+        
+        for observer in &self.observers {
+            observer.borrow_mut().on_step_end(self);
+        }
+        
         done
     }
     
-    pub fn do_contact_event(
-        &mut self, t: f64, ageclass: usize,
-        insert_individual: &mut rusqlite::Statement,
-        insert_infection: &mut rusqlite::Statement,
-        insert_transition: &mut rusqlite::Statement,
-        insert_rt: &mut rusqlite::Statement,
-        increment_rt_primary: &mut rusqlite::Statement,
-        increment_rt_secondary: &mut rusqlite::Statement,
-        record_all_events: bool,
-    ) {
+    /// Infection of a susceptible in `ageclass`, by an infectious individual drawn in proportion
+    /// to the force of infection; in full-agent mode, the infectee is drawn in proportion
+    /// to susceptibility.
+    pub fn do_contact_event(&mut self, t: f64, ageclass: usize) {
 //        println!("do_contact_event()");
         self.t = t;
         
         // Draw ageclass of infecting individual proportional to C_I_over_N
-        let cdf = self.C_I_over_N.row_cdf(ageclass);
-//        println!("cdf: {:?}", cdf);
-        let infecting_ageclass = draw_categorical(&mut self.rng, self.n_ageclasses, &cdf);
-//        println!("infecting_ageclass = {}", infecting_ageclass);
+        let infecting_ageclass = self.C_I_over_N.sample_col(ageclass, &mut self.rng);
         
         // Randomly choose an infectious individual from the infecting ageclass
-        let infectious_id = self.infectious_individuals[infecting_ageclass].sample(&mut self.rng);
-        let infectious_individual = self.individuals[&infectious_id];
-        
-        // Create a new infected individual
-        let state = self.states[self.initial_infected_state_id].clone();
-        let infected_id = self.add_individual(
-            ageclass, &state, false,
-            insert_individual,
-            record_all_events
-        );
+        let infectious_slot = self.infectious_individuals.sample(infecting_ageclass, &mut self.rng);
+        let infectious_individual = *self.individuals.get(infectious_slot).unwrap();
+        let infectious_key = IndividualKey { slot: infectious_slot, id: infectious_individual.id };
         
-        // Insert individual events
-        if record_all_events {
-            insert_infection.execute(
-                rusqlite::params![self.t, i64::try_from(infected_id).unwrap(), i64::try_from(infectious_id).unwrap()]
-            ).unwrap();
-            insert_transition.execute(
-                rusqlite::params![
-                    self.t, i64::try_from(infected_id).unwrap(),
-                    self.states[self.susceptible_state_id].name,
-                    state.name
-                ]
-            ).unwrap();
+        // Draw the setting of the contact, if settings are configured
+        let setting_opt = if self.setting_names.is_empty() {
+            None
+        }
+        else {
+            let weights: Vec<f64> = self.setting_C[self.intervention_index].iter().map(
+                |C| C.get(ageclass, infecting_ageclass)
+            ).collect();
+            Some(ContactSource::Setting(draw_categorical(&mut self.rng, weights.len(), &weights_to_cdf(&weights))))
         };
         
+        let infected_person = match &self.agents {
+            Some(agents) => Some(agents.ageclass_members[ageclass][agents.susceptible[ageclass].sample(&mut self.rng)]),
+            None => None,
+        };
+        self.infect(ageclass, infected_person, (infectious_individual, infectious_key), setting_opt);
+    }
+    
+    /// Infection in full-agent mode, either through contacts between ageclasses or within
+    /// a group, in proportion to their rates.
+    fn do_agent_contact_event(&mut self, t: f64) {
+        let contact_rate = self.beta[self.intervention_index] * self.contact_rates.total();
+        let mut u = self.rng.gen::<f64>() * (contact_rate + self.group_contact_rate());
+        if u < contact_rate {
+            let ageclass = self.contact_rates.sample(&mut self.rng);
+            self.do_contact_event(t, ageclass);
+            return;
+        }
+        u -= contact_rate;
+        self.t = t;
+        
+        // Draw the layer, then the group within it, in proportion to their rates
+        let agents = self.agents.as_ref().unwrap();
+        let mut layer = None;
+        for (i, layer_state) in agents.group_layers.iter().enumerate() {
+            let rate = layer_state.rates.total();
+            if rate > 0.0 {
+                layer = Some(i);
+                if u < rate {
+                    break;
+                }
+                u -= rate;
+            }
+        }
+        let layer = layer.unwrap();
+        let group = agents.group_layers[layer].rates.sample(&mut self.rng);
+        let members = &agents.model.group_layers[layer].groups[group];
+        
+        // Draw the infectee in proportion to susceptibility, and the infector uniformly
+        let mut weights = Vec::with_capacity(members.len());
+        let mut infectious_members = Vec::new();
+        for person in members {
+            let state = &self.states[self.individuals.get(*person).unwrap().state_id];
+            weights.push(if state.is_susceptible() { agents.model.susceptibility[*person] } else { 0.0 });
+            if state.is_infectious() {
+                infectious_members.push(*person);
+            }
+        }
+        let infected_person = members[draw_categorical(&mut self.rng, weights.len(), &weights_to_cdf(&weights))];
+        let infectious_slot = infectious_members[Uniform::new(0, infectious_members.len()).sample(&mut self.rng)];
+        
+        let infectious_individual = *self.individuals.get(infectious_slot).unwrap();
+        let infectious_key = IndividualKey { slot: infectious_slot, id: infectious_individual.id };
+        let ageclass = agents.model.ageclass(infected_person);
+        self.infect(ageclass, Some(infected_person), (infectious_individual, infectious_key), Some(ContactSource::Group(layer)));
+    }
+    
+    /// Infects a susceptible in `ageclass`: a new individual or, in full-agent mode,
+    /// `infected_person`.
+    fn infect(
+        &mut self, ageclass: usize, infected_person: Option<usize>,
+        infector: (Individual, IndividualKey), source: Option<ContactSource>,
+    ) {
+        let (infectious_individual, _) = infector;
+        let infectious_id = infectious_individual.id;
+        
+        // Create a new infected individual, or infect an existing one
+        let states = self.states.clone();
+        let state = &states[self.initial_infected_state_id];
+        let infected_id = match infected_person {
+            Some(person) => self.infect_person(person, state, infector),
+            None => self.add_individual(ageclass, state, Some(infector)),
+        };
+        
+        // Generation interval, for infectors who were infected during the simulation
+        if let Some(t_past) = infectious_individual.t_infected {
+            self.generation_intervals.record(self.t, self.t - t_past);
+        }
+        
+        // Notify observers of infection and corresponding transition
+        if !self.observers.is_empty() {
+            let event = InfectionEvent {
+                t: self.t,
+                infected_id,
+                infected_ageclass: ageclass,
+                infectious_id,
+                infectious_ageclass: infectious_individual.ageclass,
+                infectious_t_infected: infectious_individual.t_infected,
+                setting: source.map(|source| match source {
+                    ContactSource::Setting(s) => self.setting_names[s].as_str(),
+                    ContactSource::Group(layer) => self.agents.as_ref().unwrap().model.group_layers[layer].name.as_str(),
+                }),
+            };
+            let susceptible_state = &self.states[self.susceptible_state_id];
+            self.notify(|o| {
+                o.on_infection(&event);
+                o.on_transition(event.t, infected_id, ageclass, susceptible_state, state);
+            });
+        }
+        
         // Update count of people infected during this discrete timestep
         // (denominator of Rt)
+        self.n_infections += 1;
         let t_discrete_present = self.t.ceil() as i64;
-        insert_rt.execute(rusqlite::params![t_discrete_present]).unwrap();
-        increment_rt_primary.execute(rusqlite::params![t_discrete_present]).unwrap();
+        self.rt_statistics.entry(t_discrete_present).or_insert((0, 0)).0 += 1;
         
         // Update count of number of infections caused by people infected at a previous timestep
         // (numerator of Rt)
         if let Some(t_past) = infectious_individual.t_infected {
             let t_past_discrete = t_past.ceil() as i64;
-            increment_rt_secondary.execute(rusqlite::params![t_past_discrete]).unwrap();
+            self.rt_statistics.entry(t_past_discrete).or_insert((0, 0)).1 += 1;
         }
         
         // Update contact times
         self.update_contact()
     }
     
-    fn do_transition_event(
-        &mut self, event: Event,
-        insert_transition: &mut rusqlite::Statement,
-        record_all_events: bool,
-    ) {
+    fn do_transition_event(&mut self, event: Event) {
 //        println!("do_transition_event()");
         
         self.t = event.t;
+        self.n_transitions += 1;
         
-        let id = event.individual_id;
-        let individual = self.individuals[&id].clone();
+        let key = event.individual;
+        let id = key.id;
+        let individual = *self.individual(key).unwrap();
         let ageclass = individual.ageclass;
-        let last_state = self.states[individual.state_id].clone();
+        let states = self.states.clone();
+        let last_state = &states[individual.state_id];
         let last_infected_state = match last_state.detail {
             StateDetail::Infected(Some(ref infected_state)) => {
                 infected_state
@@ -749,16 +1762,25 @@ impl Simulation {
             }
         };
         
-        let next_state_id = last_infected_state.next_state_ids[
+        // Transition probabilities may change by period, and depend on the person in full-agent mode
+        let model = self.agents.as_ref().map(|agents| agents.model.clone());
+        let person_cdfs = model.as_ref().and_then(|model| model.transition_cdfs[last_state.id].as_ref());
+        let transition_cdfs = match person_cdfs.and_then(|cdfs| cdfs.variants[key.slot]) {
+            Some(variant) => &person_cdfs.unwrap().cdfs[variant],
+            None => last_infected_state.natural_history_at(self.t).2,
+        };
+        let next_state_index = self.draw_natural_history(
+            id, individual.n_states_entered, last_state.id, DRAW_NEXT_STATE, |rng| {
             draw_categorical(
-                &mut self.rng,
+                rng,
                 last_infected_state.next_state_ids.len(),
-                &last_infected_state.transition_cdfs[individual.ageclass]
+                &transition_cdfs[individual.ageclass]
             )
-        ];
+        });
+        let next_state_id = last_infected_state.next_state_ids[next_state_index];
 //        println!("last state: {}; next state: {}", self.states[last_state.id].name, self.states[next_state_id].name);
-    
-        let next_state = self.states[next_state_id].clone();
+        
+        let next_state = &states[next_state_id];
         assert!(next_state.is_infected() || next_state.is_final());
         
         // Update ageclass-specific state counts
@@ -768,57 +1790,79 @@ impl Simulation {
         // Update infectious counts
         match (last_state.is_infectious(), next_state.is_infectious()) {
             (false, true) => {
-                self.infectious_individuals[ageclass].add(id);
-                self.C_I_over_N.increment(ageclass);
+                self.infectious_individuals.add(ageclass, key.slot);
+                self.increment_infectious(ageclass);
             },
             (true, false) => {
-                self.infectious_individuals[ageclass].remove(id);
-                self.C_I_over_N.decrement(ageclass);
+                self.infectious_individuals.remove(ageclass, key.slot);
+                self.decrement_infectious(ageclass);
             },
             _ => {},
         }
         
+        self.individual_mut(key).unwrap().state_id = next_state.id;
+        if let Some(agents) = &mut self.agents {
+            agents.change_state(key.slot, Some(last_state), next_state);
+        }
+        if Some(next_state.id) == self.onset_state_id {
+            self.record_onset(key);
+        }
+        
         if next_state.is_final() {
-            // Remove individual from memory if they're moving to a final state
-            self.individuals.remove(&id);
+            // Remove individual from memory if they're moving to a final state (unless
+            // everyone is kept, in full-agent mode), along with any infectees still waiting
+            // on their onset
+            if self.agents.is_none() {
+                self.individuals.remove(key.slot);
+            }
+            self.awaiting_infector_onset.remove(&id);
+            self.early_onsets.remove(&id);
         }
         else {
-            // Otherwise insert an updated individual and queue the next transition
-            self.individuals.insert(id, individual.update_state(next_state.id));
-            self.insert_transition_event(&next_state, individual.id);
-        }
-        
-        if record_all_events {
-            insert_transition.execute(
-                rusqlite::params![
-                    self.t,
-                    i64::try_from(id).unwrap(),
-                    last_state.name,
-                    next_state.name
-                ]
-            ).unwrap();
-        };
+            // Otherwise queue the next transition
+            self.insert_transition_event(next_state, key);
+        }
+        
+        let t = self.t;
+        self.notify(|o| o.on_transition(t, id, ageclass, last_state, next_state));
         
         // Update contact times
         self.update_contact()
     }
     
-    fn t_next_transition(&self) -> Option<f64> {
-        self.event_queue.iter().next().map(|event| event.t)
+    fn t_next_transition(&mut self) -> Option<f64> {
+        self.discard_stale_events();
+        self.event_queue.peek().map(|Reverse(event)| event.t)
     }
     
     fn dequeue_next_transition_event(&mut self) -> Option<Event> {
-        let event_opt = self.event_queue.iter().next().map(|event| *event);
-        if let Some(event) = event_opt {
-            self.event_queue.remove(&event);
+        self.discard_stale_events();
+        self.event_queue.pop().map(|Reverse(event)| event)
+    }
+    
+    /// Removes events at the front of the queue for individuals no longer present,
+    /// so that removing an individual doesn't require finding its events in the queue.
+    fn discard_stale_events(&mut self) {
+        while let Some(Reverse(event)) = self.event_queue.peek() {
+            if self.individual(event.individual).is_some() {
+                break;
+            }
+            self.event_queue.pop();
         }
-        event_opt
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::ibm::{weights_to_cdf, draw_categorical};
+    use crate::config::*;
+    use crate::fixtures::*;
+    use crate::ibm::{
+        weights_to_cdf, draw_categorical, Arena, CIOverN, FenwickTree, GroupedVecSet, IntervalHistogram, SparseMatrix
+    };
+    use crate::observer::*;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
     use rand_xoshiro::Xoshiro256PlusPlus;
     use rand_xoshiro::rand_core::SeedableRng;
     
@@ -837,4 +1881,153 @@ mod tests {
         }
         println!("counts: {:?}", counts);
     }
+    
+    #[test]
+    fn test_interval_histogram() {
+        let mut hist = IntervalHistogram::new(0.5);
+        hist.record(2.3, 1.2);
+        hist.record(2.9, 1.4);
+        hist.record(3.1, -0.2);
+        
+        let rows: Vec<_> = hist.records().iter().map(
+            |r| (r.time_discrete, r.interval, r.count)
+        ).collect();
+        assert_eq!(rows, vec![(3, 1.0, 2), (4, -0.5, 1)]);
+    }
+    
+    #[test]
+    fn test_serial_intervals() {
+        // With infectiousness before onset, and recovery without onset, infectees can have
+        // onset before their infector, or never see their infector's onset
+        let mut config = read_test_config();
+        config.interval_bin_width = Some(0.01);
+        config.infected_states[0].infectious = true;
+        config.infected_states[0].next_states = vec!["I".into(), "R".into()];
+        config.infected_states[0].probabilities = Some(vec![vec![0.7, 0.3]; 2]);
+        let recorder = Rc::new(RefCell::new(EventRecorder::new()));
+        let mut sim = SimulationBuilder::new(config).observer(recorder.clone()).build().unwrap();
+        while sim.t < 60.0 && !sim.simulate(sim.t + 1.0) {}
+        let events = recorder.borrow_mut().take_records();
+        
+        // Expected intervals, from onsets and infections recorded as events
+        let onsets: HashMap<usize, f64> = events.transitions.iter().filter(
+            |r| r.end_state == "I"
+        ).map(|r| (r.id, r.time)).collect();
+        let mut expected = IntervalHistogram::new(0.01);
+        let (mut n_early, mut n_without_infector_onset) = (0, 0);
+        for infection in &events.infections {
+            match (onsets.get(&infection.infected_id), onsets.get(&infection.infectious_id)) {
+                (Some(t_onset), Some(t_infector_onset)) => {
+                    expected.record(*t_onset, t_onset - t_infector_onset);
+                    if t_onset < t_infector_onset {
+                        n_early += 1;
+                    }
+                },
+                (Some(_), None) => n_without_infector_onset += 1,
+                _ => {},
+            }
+        }
+        assert!(n_early > 0 && n_without_infector_onset > 0);
+        assert_eq!(sim.serial_intervals().records(), expected.records());
+    }
+    
+    #[test]
+    fn test_grouped_vec_set() {
+        let mut sets = GroupedVecSet::new(2);
+        for item in &[3, 0, 7, 5] {
+            sets.add(0, *item);
+        }
+        sets.add(1, 2);
+        sets.remove(0, 0);
+        sets.remove(0, 5);
+        assert_eq!(sets.len(0), 2);
+        assert_eq!(sets.len(1), 1);
+        assert!(sets.contains(3) && sets.contains(7) && sets.contains(2));
+        assert!(!sets.contains(0) && !sets.contains(5) && !sets.contains(100));
+        
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        assert!((0..100).all(|_| [3, 7].contains(&sets.sample(0, &mut rng))));
+        assert_eq!(sets.sample(1, &mut rng), 2);
+        sets.add(1, 5);
+        assert!(sets.contains(5));
+    }
+    
+    #[test]
+    fn test_fenwick_tree() {
+        let mut tree = FenwickTree::new(vec![4.0, 0.0, 2.0, 1.0, 0.0]);
+        assert_eq!(tree.total(), 7.0);
+        assert_eq!((0..5).map(|i| tree.find(i as f64 + 0.5)).collect::<Vec<_>>(), vec![0, 0, 0, 0, 2]);
+        assert_eq!(tree.find(6.5), 3);
+        
+        // Rounding that lands on a zero weight falls back to the nearest positive one
+        assert_eq!(tree.find(7.0), 3);
+        
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        let mut counts = [0; 5];
+        for _ in 0..70000 {
+            counts[tree.sample(&mut rng)] += 1;
+        }
+        assert_eq!((counts[1], counts[4]), (0, 0));
+        assert!((counts[0] as f64 / 40000.0 - 1.0).abs() < 0.05);
+        assert!((counts[3] as f64 / 10000.0 - 1.0).abs() < 0.05);
+        
+        // Totals are exactly zero once every weight is, despite rounding in partial sums
+        tree.set(1, 0.1);
+        tree.set(4, 0.2);
+        for i in 0..5 {
+            tree.set(i, 0.0);
+        }
+        assert_eq!(tree.total(), 0.0);
+        for _ in 0..1000 {
+            tree.set(2, 0.3);
+            tree.set(2, 0.0);
+        }
+        assert_eq!(tree.total(), 0.0);
+        tree.set(4, 1.5);
+        assert_eq!(tree.total(), 1.5);
+        assert_eq!(tree.sample(&mut rng), 4);
+    }
+    
+    #[test]
+    fn test_C_I_over_N() {
+        let C = SparseMatrix::from_dense(&[
+            vec![1.0, 0.0, 0.5],
+            vec![0.0, 2.0, 0.0],
+            vec![0.5, 0.0, 1.0],
+        ]);
+        assert_eq!(C.nnz(), 5);
+        assert_eq!(C.get(0, 2), 0.5);
+        assert_eq!(C.get(0, 1), 0.0);
+        
+        let mut foi = CIOverN::new(C, vec![0, 0, 0], vec![100, 50, 200]);
+        assert_eq!(foi.rows_with_contacts_in(2).collect::<Vec<_>>(), vec![0, 2]);
+        foi.increment(2);
+        foi.increment(2);
+        assert_eq!(foi.row_sum(0), 0.5 * 2.0 / 200.0);
+        assert_eq!(foi.row_sum(1), 0.0);
+        foi.increment(0);
+        foi.decrement(2);
+        foi.decrement(2);
+        assert!((foi.row_sum(2) - 0.5 / 100.0).abs() < 1e-15);
+        
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(0);
+        assert_eq!(foi.sample_col(0, &mut rng), 0);
+    }
+    
+    #[test]
+    fn test_arena() {
+        let mut arena = Arena::new();
+        let a = arena.insert("a");
+        let b = arena.insert("b");
+        assert_eq!(arena.remove(a), Some("a"));
+        assert_eq!(arena.get(a), None);
+        assert_eq!(arena.remove(a), None);
+        
+        // Freed slots are reused
+        let c = arena.insert("c");
+        assert_eq!(c, a);
+        assert_eq!(arena.get(c), Some(&"c"));
+        assert_eq!(arena.get(b), Some(&"b"));
+        assert_eq!(arena.insert("d"), 2);
+    }
 }
@@ -1,5 +1,22 @@
+pub mod abc;
+pub mod ageclasses;
+pub mod branching;
+pub mod config;
+pub mod contacts;
+pub mod db;
+pub mod ffi;
+#[cfg(test)]
+mod fixtures;
 pub mod ibm;
+pub mod observer;
+pub mod particle_filter;
+pub mod population;
 pub mod spec;
+pub mod strata;
 pub mod stan;
+pub mod summary;
+pub mod sweep;
+pub mod tree;
 pub mod util;
+pub mod validation;
 pub mod errors;
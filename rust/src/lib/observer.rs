@@ -0,0 +1,146 @@
+use crate::ibm::*;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Shared handle to an observer, so that callers can read its state after a run.
+pub type ObserverRef = Rc<RefCell<dyn SimulationObserver>>;
+
+/// Callbacks invoked by `Simulation` as events happen.
+///
+/// All methods have empty default implementations, so observers need only
+/// implement the ones they care about.
+pub trait SimulationObserver {
+    /// Called when an individual is created: either an initial infected or a new infection
+    /// or, in full-agent mode (see `PopulationConfig`), every person at the start.
+    fn on_individual_created(
+        &mut self, _t: f64, _id: usize, _ageclass: usize, _state: &State, _is_initial: bool
+    ) {}
+    
+    /// Called when a susceptible is infected, before the corresponding transition.
+    fn on_infection(&mut self, _event: &InfectionEvent) {}
+    
+    /// Called for every change of state, including infection (out of the susceptible state).
+    fn on_transition(
+        &mut self, _t: f64, _id: usize, _ageclass: usize, _from_state: &State, _to_state: &State
+    ) {}
+    
+    /// Called when contact parameters change to those at `intervention_index`.
+    fn on_intervention_change(&mut self, _t: f64, _intervention_index: usize) {}
+    
+    /// Called at the start of each call to `Simulation::simulate`.
+    fn on_step_start(&mut self, _sim: &Simulation) {}
+    
+    /// Called at the end of each call to `Simulation::simulate`.
+    fn on_step_end(&mut self, _sim: &Simulation) {}
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct InfectionEvent<'a> {
+    pub t: f64,
+    pub infected_id: usize,
+    pub infected_ageclass: usize,
+    pub infectious_id: usize,
+    pub infectious_ageclass: usize,
+    /// Infection time of the infector; `None` for initial infecteds.
+    pub infectious_t_infected: Option<f64>,
+    /// Setting of the contact, if contact settings are configured.
+    pub setting: Option<&'a str>,
+}
+
+/// Accumulates individual-level events in memory (the `record_all_events` output).
+#[derive(Debug, Clone, Default)]
+pub struct EventRecorder {
+    records: EventRecords,
+}
+
+impl EventRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    pub fn records(&self) -> &EventRecords {
+        &self.records
+    }
+    
+    /// Removes and returns events recorded since the last call.
+    pub fn take_records(&mut self) -> EventRecords {
+        std::mem::take(&mut self.records)
+    }
+}
+
+impl SimulationObserver for EventRecorder {
+    fn on_individual_created(
+        &mut self, t: f64, id: usize, ageclass: usize, state: &State, _is_initial: bool
+    ) {
+        self.records.individuals.push(IndividualRecord {
+            time: t, id, ageclass, initial_state: state.name.clone(),
+        });
+    }
+    
+    fn on_infection(&mut self, event: &InfectionEvent) {
+        self.records.infections.push(InfectionRecord {
+            time: event.t, infected_id: event.infected_id, infectious_id: event.infectious_id,
+            setting: event.setting.map(String::from),
+        });
+    }
+    
+    fn on_transition(
+        &mut self, t: f64, id: usize, _ageclass: usize, from_state: &State, to_state: &State
+    ) {
+        self.records.transitions.push(TransitionRecord {
+            time: t, id,
+            start_state: from_state.name.clone(),
+            end_state: to_state.name.clone(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::*;
+    use crate::observer::*;
+    use crate::fixtures::*;
+    
+    #[derive(Default)]
+    struct CountingObserver {
+        n_created: usize,
+        n_infections: usize,
+        n_steps: usize,
+        n_intervention_changes: usize,
+    }
+    
+    impl SimulationObserver for CountingObserver {
+        fn on_individual_created(
+            &mut self, _t: f64, _id: usize, _ageclass: usize, _state: &State, _is_initial: bool
+        ) {
+            self.n_created += 1;
+        }
+        
+        fn on_infection(&mut self, _event: &InfectionEvent) {
+            self.n_infections += 1;
+        }
+        
+        fn on_intervention_change(&mut self, _t: f64, _intervention_index: usize) {
+            self.n_intervention_changes += 1;
+        }
+        
+        fn on_step_end(&mut self, _sim: &Simulation) {
+            self.n_steps += 1;
+        }
+    }
+    
+    #[test]
+    fn test_observer_callbacks() {
+        let config = read_test_config();
+        let observer = Rc::new(RefCell::new(CountingObserver::default()));
+        let output = SimulationBuilder::new(config).observer(observer.clone()).run().unwrap();
+        
+        let observer = observer.borrow();
+        let n_infections: usize = output.rt.iter().map(|r| r.n_primary).sum();
+        assert_eq!(observer.n_infections, n_infections);
+        assert_eq!(observer.n_created, n_infections + 10);
+        assert_eq!(observer.n_intervention_changes, 1);
+        assert_eq!(observer.n_steps, 60);
+    }
+}
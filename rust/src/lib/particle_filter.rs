@@ -0,0 +1,343 @@
+use crate::abc::SummaryStatistic;
+use crate::config::*;
+use crate::errors::*;
+use crate::ibm::Simulation;
+
+use rand::Rng;
+use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Specification of a bootstrap particle filter run: observed time series, each with an
+/// observation model relating it to a summary statistic of the simulation state.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ParticleFilterSpec {
+    pub observations: Vec<ObservationSeries>,
+    pub n_particles: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ObservationSeries {
+    pub statistic: SummaryStatistic,
+    pub model: ObservationModel,
+    pub times: Vec<f64>,
+    pub values: Vec<f64>,
+}
+
+/// Distribution of an observation given the true value `x` of its statistic, with the
+/// families and parameters of `spec::ObservationDistribution`.
+///
+/// Means are `mean_fraction * x`; negative binomial dispersion is as in Stan's
+/// `neg_binomial_2`, and beta-binomial dispersion is the sum of the beta parameters.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "distribution")]
+pub enum ObservationModel {
+    Normal { mean_fraction: f64, standard_deviation: f64 },
+    Poisson { mean_fraction: f64 },
+    NegativeBinomial { mean_fraction: f64, dispersion: f64 },
+    Binomial { probability: f64 },
+    BetaBinomial { probability: f64, dispersion: f64 },
+}
+
+impl ObservationModel {
+    /// Log-probability (density, for `Normal`) of observing `y` when the true value is `x`.
+    pub fn log_density(&self, y: f64, x: f64) -> f64 {
+        match *self {
+            ObservationModel::Normal { mean_fraction, standard_deviation } => {
+                let z = (y - mean_fraction * x) / standard_deviation;
+                -0.5 * z * z - standard_deviation.ln() - 0.5 * (2.0 * std::f64::consts::PI).ln()
+            },
+            ObservationModel::Poisson { mean_fraction } => {
+                let mean = mean_fraction * x;
+                if mean == 0.0 {
+                    return if y == 0.0 { 0.0 } else { f64::NEG_INFINITY };
+                }
+                y * mean.ln() - mean - ln_gamma(y + 1.0)
+            },
+            ObservationModel::NegativeBinomial { mean_fraction, dispersion } => {
+                let mean = mean_fraction * x;
+                if mean == 0.0 {
+                    return if y == 0.0 { 0.0 } else { f64::NEG_INFINITY };
+                }
+                ln_gamma(y + dispersion) - ln_gamma(dispersion) - ln_gamma(y + 1.0)
+                    + dispersion * (dispersion / (mean + dispersion)).ln()
+                    + y * (mean / (mean + dispersion)).ln()
+            },
+            ObservationModel::Binomial { probability } => {
+                if y > x {
+                    return f64::NEG_INFINITY;
+                }
+                ln_choose(x, y) + xlogy(y, probability) + xlogy(x - y, 1.0 - probability)
+            },
+            ObservationModel::BetaBinomial { probability, dispersion } => {
+                if y > x {
+                    return f64::NEG_INFINITY;
+                }
+                let alpha = probability * dispersion;
+                let beta = (1.0 - probability) * dispersion;
+                ln_choose(x, y) + ln_beta(y + alpha, x - y + beta) - ln_beta(alpha, beta)
+            },
+        }
+    }
+    
+    fn check(&self) -> Result<(), String> {
+        let valid = match *self {
+            ObservationModel::Normal { mean_fraction, standard_deviation } => {
+                mean_fraction >= 0.0 && standard_deviation > 0.0
+            },
+            ObservationModel::Poisson { mean_fraction } => mean_fraction >= 0.0,
+            ObservationModel::NegativeBinomial { mean_fraction, dispersion } => {
+                mean_fraction >= 0.0 && dispersion > 0.0
+            },
+            ObservationModel::Binomial { probability } => (0.0..=1.0).contains(&probability),
+            ObservationModel::BetaBinomial { probability, dispersion } => {
+                probability > 0.0 && probability < 1.0 && dispersion > 0.0
+            },
+        };
+        if valid { Ok(()) } else { Err(format!("invalid observation model: {:?}", self)) }
+    }
+    
+    fn is_discrete(&self) -> bool {
+        !matches!(self, ObservationModel::Normal { .. })
+    }
+}
+
+/// `x * ln(y)`, taken to be 0 when `x` is 0.
+fn xlogy(x: f64, y: f64) -> f64 {
+    if x == 0.0 { 0.0 } else { x * y.ln() }
+}
+
+fn ln_choose(n: f64, k: f64) -> f64 {
+    ln_gamma(n + 1.0) - ln_gamma(k + 1.0) - ln_gamma(n - k + 1.0)
+}
+
+fn ln_beta(a: f64, b: f64) -> f64 {
+    ln_gamma(a) + ln_gamma(b) - ln_gamma(a + b)
+}
+
+/// Log of the gamma function for positive arguments (Lanczos approximation, g = 7).
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    if x < 0.5 {
+        // Reflection formula
+        let pi = std::f64::consts::PI;
+        return (pi / (pi * x).sin()).ln() - ln_gamma(1.0 - x);
+    }
+    let x = x - 1.0;
+    let t = x + 7.5;
+    let sum = COEFFICIENTS[1..].iter().enumerate().fold(COEFFICIENTS[0], |sum, (i, c)| {
+        sum + c / (x + i as f64 + 1.0)
+    });
+    0.5 * (2.0 * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + sum.ln()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ParticleFilterResult {
+    /// Estimate of the log-likelihood of all observations; `null` in JSON if some
+    /// observation had zero likelihood under every particle.
+    pub log_likelihood: f64,
+    
+    /// Observation times, with the log-likelihood contribution and effective sample size
+    /// (before resampling) at each.
+    pub times: Vec<f64>,
+    pub log_likelihood_increments: Vec<f64>,
+    pub effective_sample_sizes: Vec<f64>,
+    
+    /// Counts by state (summed over ageclasses) at each observation time, along the
+    /// ancestral line of each particle remaining at the end: `trajectories[particle][time][state]`.
+    pub states: Vec<String>,
+    pub trajectories: Vec<Vec<Vec<usize>>>,
+}
+
+/// Runs a bootstrap particle filter: `n_particles` simulations, seeded as replicates of
+/// `config`, are advanced together to each observation time, weighted by the likelihood
+/// of the observations there, and resampled (systematically), continuing with fresh
+/// random number streams.
+///
+/// Simulations are held in memory without observers; nothing is written to the config's
+/// output path, and `establishment` is ignored.
+pub fn run_particle_filter(config: &Config, spec: &ParticleFilterSpec) -> Result<ParticleFilterResult, Error> {
+    check_spec(config, spec)?;
+    
+    let mut times: Vec<f64> = spec.observations.iter().flat_map(|o| o.times.iter().copied()).collect();
+    times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    times.dedup();
+    
+    let builder = SimulationBuilder::new(config.clone());
+    let (master_rng_seed, rng_seeds) = builder.replicate_rng_seeds(spec.n_particles);
+    let mut particles = Vec::with_capacity(spec.n_particles);
+    for rng_seed in rng_seeds {
+        particles.push(builder.clone_with(rng_seed).build()?);
+    }
+    
+    // Replicate seeds are drawn from the unjumped stream, so jump to avoid reusing them
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(master_rng_seed);
+    rng.jump();
+    
+    let states: Vec<String> = particles[0].states().iter().map(|s| s.name.clone()).collect();
+    let mut trajectories: Vec<Vec<Vec<usize>>> = vec![Vec::new(); spec.n_particles];
+    let mut result = ParticleFilterResult {
+        log_likelihood: 0.0,
+        times: Vec::new(),
+        log_likelihood_increments: Vec::new(),
+        effective_sample_sizes: Vec::new(),
+        states,
+        trajectories: Vec::new(),
+    };
+    
+    for &t in &times {
+        let mut log_weights = Vec::with_capacity(spec.n_particles);
+        for (sim, trajectory) in particles.iter_mut().zip(&mut trajectories) {
+            if sim.t < t {
+                sim.simulate(t);
+            }
+            log_weights.push(log_likelihood_at(spec, sim, t));
+            trajectory.push(sim.states().iter().map(|s| sim.counts().total_for_state(s.id)).collect());
+        }
+        
+        let max_log_weight = log_weights.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        result.times.push(t);
+        if max_log_weight == f64::NEG_INFINITY {
+            result.log_likelihood = f64::NEG_INFINITY;
+            result.log_likelihood_increments.push(f64::NEG_INFINITY);
+            result.effective_sample_sizes.push(0.0);
+            break;
+        }
+        let weights: Vec<f64> = log_weights.iter().map(|lw| (lw - max_log_weight).exp()).collect();
+        let total_weight: f64 = weights.iter().sum();
+        let increment = max_log_weight + (total_weight / spec.n_particles as f64).ln();
+        result.log_likelihood += increment;
+        result.log_likelihood_increments.push(increment);
+        result.effective_sample_sizes.push(
+            total_weight * total_weight / weights.iter().map(|w| w * w).sum::<f64>()
+        );
+        
+        let ancestors = systematic_resample(&weights, &mut rng);
+        particles = ancestors.iter().map(|&i| particles[i].fork(rng.gen())).collect();
+        trajectories = ancestors.iter().map(|&i| trajectories[i].clone()).collect();
+    }
+    
+    result.trajectories = trajectories;
+    Ok(result)
+}
+
+fn check_spec(config: &Config, spec: &ParticleFilterSpec) -> Result<(), Error> {
+    let invalid = |message: String| Err(Error::InvalidArgument(message));
+    if spec.n_particles == 0 {
+        return invalid("n_particles must be positive".into());
+    }
+    if spec.observations.is_empty() {
+        return invalid("no observations given".into());
+    }
+    for observation in &spec.observations {
+        if observation.times.len() != observation.values.len() {
+            return invalid("observation times and values differ in length".into());
+        }
+        if observation.times.iter().any(|t| !t.is_finite()) {
+            return invalid("observation times must be finite".into());
+        }
+        observation.statistic.check(config).or_else(invalid)?;
+        observation.model.check().or_else(invalid)?;
+        if observation.model.is_discrete() && observation.values.iter().any(|y| *y < 0.0 || y.fract() != 0.0) {
+            return invalid(format!("observations must be counts for {:?}", observation.model));
+        }
+    }
+    Ok(())
+}
+
+/// Log-likelihood of all observations at time `t` given a simulation's current state.
+fn log_likelihood_at(spec: &ParticleFilterSpec, sim: &Simulation, t: f64) -> f64 {
+    let mut log_likelihood = 0.0;
+    for observation in &spec.observations {
+        for (_, &y) in observation.times.iter().zip(&observation.values).filter(|(time, _)| **time == t) {
+            log_likelihood += observation.model.log_density(y, observation.statistic.evaluate_current(sim));
+        }
+    }
+    log_likelihood
+}
+
+/// Indices of particles chosen with probability proportional to `weights`, using a single
+/// uniform offset for evenly spaced draws.
+fn systematic_resample<R: Rng>(weights: &[f64], rng: &mut R) -> Vec<usize> {
+    let n = weights.len();
+    let total: f64 = weights.iter().sum();
+    let offset: f64 = rng.gen();
+    let mut indices = Vec::with_capacity(n);
+    let mut cumulative = weights[0] / total;
+    let mut i = 0;
+    for k in 0..n {
+        let u = (k as f64 + offset) / n as f64;
+        while u > cumulative && i < n - 1 {
+            i += 1;
+            cumulative += weights[i] / total;
+        }
+        indices.push(i);
+    }
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::particle_filter::*;
+    use crate::fixtures::*;
+    
+    #[test]
+    fn test_log_densities() {
+        assert!((ln_gamma(5.0) - 24f64.ln()).abs() < 1e-10);
+        assert!((ln_gamma(0.5) - std::f64::consts::PI.sqrt().ln()).abs() < 1e-10);
+        
+        // Discrete distributions sum to 1 over their support
+        let models = [
+            ObservationModel::Poisson { mean_fraction: 0.5 },
+            ObservationModel::NegativeBinomial { mean_fraction: 0.5, dispersion: 3.0 },
+            ObservationModel::Binomial { probability: 0.3 },
+            ObservationModel::BetaBinomial { probability: 0.3, dispersion: 2.0 },
+        ];
+        for model in &models {
+            let total: f64 = (0..500).map(|y| model.log_density(y as f64, 20.0).exp()).sum();
+            assert!((total - 1.0).abs() < 1e-8, "{:?}: {}", model, total);
+        }
+        assert_eq!(ObservationModel::Binomial { probability: 0.3 }.log_density(21.0, 20.0), f64::NEG_INFINITY);
+    }
+    
+    #[test]
+    fn test_particle_filter() {
+        let mut config = read_test_config();
+        let observed = SimulationBuilder::new(config.clone()).t_final(30.0).run().unwrap();
+        let times: Vec<f64> = (1..=30).map(|t| t as f64).collect();
+        let statistic = SummaryStatistic::Count { states: vec!["I".into()], ageclass: None };
+        let values = statistic.evaluate(&observed, &times).iter().map(|x| (x * 0.5).round()).collect();
+        let spec = ParticleFilterSpec {
+            observations: vec![ObservationSeries {
+                statistic,
+                model: ObservationModel::NegativeBinomial { mean_fraction: 0.5, dispersion: 10.0 },
+                times,
+                values,
+            }],
+            n_particles: 50,
+        };
+        
+        config.rng_seed = Some(2);
+        let result = run_particle_filter(&config, &spec).unwrap();
+        assert_eq!(result.times.len(), 30);
+        assert!(result.log_likelihood.is_finite());
+        assert_eq!(result.trajectories.len(), 50);
+        assert_eq!(result.trajectories[0].len(), 30);
+        assert_eq!(result.trajectories[0][29].iter().sum::<usize>(), 2010);
+        
+        // The true beta is favoured over a much larger one
+        config.contact_parameters[0].beta = 0.9;
+        assert!(run_particle_filter(&config, &spec).unwrap().log_likelihood < result.log_likelihood);
+    }
+}
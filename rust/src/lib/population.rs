@@ -0,0 +1,416 @@
+use crate::config::*;
+use crate::errors::*;
+use crate::ibm::*;
+use crate::util::*;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Full-agent mode: every person in a synthetic population is simulated as an individual,
+/// rather than only those infected, so that susceptibility, contacts and transition
+/// probabilities can depend on individual attributes.
+///
+/// The population is read from the CSV file at `path` (see `Population::read`), which
+/// replaces `initial_counts`. Infection then happens through contacts between ageclasses as
+/// usual, with each susceptible's chance of being infected scaled by its `susceptibility`,
+/// and additionally within the groups of any `group_contacts`. Counts by state are also
+/// written for each value of each of `output_attributes`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PopulationConfig {
+    pub path: String,
+    pub susceptibility: Option<Vec<AttributeMultipliers>>,
+    pub group_contacts: Option<Vec<GroupContacts>>,
+    pub output_attributes: Option<Vec<String>>,
+}
+
+/// Multipliers by value of an attribute; people whose value is not listed have multiplier 1.
+/// A person's susceptibility is the product of their multipliers for every attribute listed.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct AttributeMultipliers {
+    pub attribute: String,
+    pub multipliers: HashMap<String, f64>,
+}
+
+/// Contacts within groups of people sharing a value of an attribute (e.g. `household`
+/// or `region`), in addition to contacts between ageclasses: each infectious member of a group
+/// of `n` infects each susceptible member at rate `beta / (n - 1)`, times its susceptibility.
+/// People with an empty value belong to no group.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct GroupContacts {
+    pub attribute: String,
+    pub beta: f64,
+}
+
+/// Transition probabilities, by ageclass, for people with particular values of an attribute
+/// (e.g. a higher probability of death with a comorbidity), in place of those of the state.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct AttributeProbabilities {
+    pub attribute: String,
+    #[schemars(with = "HashMap<String, ByAgeclass<Vec<f64>>>")]
+    pub probabilities: HashMap<String, Vec<Vec<f64>>>,
+}
+
+/// Columns of a population file with a fixed meaning; all others are attributes.
+pub const POPULATION_COLUMNS: &[&str] = &["ageclass", "initial_state"];
+
+/// A synthetic population, one person per row of a CSV file.
+///
+/// Individual ids in output are row numbers (from 1, not counting the header).
+pub struct Population {
+    path: String,
+    ageclasses: Vec<usize>,
+    initial_states: PopulationAttribute,
+    attributes: Vec<PopulationAttribute>,
+}
+
+/// Values of a column of a population file: the distinct values in order of appearance,
+/// and the index of each person's value among them.
+#[derive(Debug, Clone)]
+pub struct PopulationAttribute {
+    pub name: String,
+    pub values: Vec<String>,
+    pub indices: Vec<usize>,
+    value_indices: HashMap<String, usize>,
+}
+
+impl PopulationAttribute {
+    fn new(name: &str) -> Self {
+        Self { name: name.into(), values: Vec::new(), indices: Vec::new(), value_indices: HashMap::new() }
+    }
+    
+    fn push(&mut self, value: &str) {
+        let index = match self.value_indices.get(value) {
+            Some(index) => *index,
+            None => {
+                self.values.push(value.into());
+                self.value_indices.insert(value.into(), self.values.len() - 1);
+                self.values.len() - 1
+            },
+        };
+        self.indices.push(index);
+    }
+    
+    pub fn value_index(&self, value: &str) -> Option<usize> {
+        self.value_indices.get(value).copied()
+    }
+}
+
+impl std::fmt::Debug for Population {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Population")
+            .field("path", &self.path)
+            .field("len", &self.len())
+            .field("attributes", &self.attributes.iter().map(|a| &a.name).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Population {
+    /// Reads a population from a CSV file with a header row naming the columns.
+    ///
+    /// The `ageclass` column is required, and gives an ageclass label (see
+    /// `Config::ageclass_labels`) or a number from 1. The optional `initial_state` column
+    /// gives the state each person starts in (by default `default_state`, normally the
+    /// susceptible state). Every other column is an attribute, with values taken as text.
+    pub fn read(
+        path: &str, n_ageclasses: usize, labels: Option<&[String]>, default_state: &str
+    ) -> Result<Self, Error> {
+        let error = |message: String| Error::InvalidCsv { path: path.into(), message };
+        let rows = read_csv(path)?;
+        let header = rows.first().ok_or_else(|| error("missing header row".into()))?;
+        
+        let column = |name: &str| header.iter().position(|c| c == name);
+        let ageclass_column = column("ageclass").ok_or_else(|| error("missing ageclass column".into()))?;
+        let state_column = column("initial_state");
+        let attribute_columns: Vec<usize> = (0..header.len()).filter(
+            |j| !POPULATION_COLUMNS.contains(&header[*j].as_str())
+        ).collect();
+        
+        let mut population = Self {
+            path: path.into(),
+            ageclasses: Vec::with_capacity(rows.len() - 1),
+            initial_states: PopulationAttribute::new("initial_state"),
+            attributes: attribute_columns.iter().map(|j| PopulationAttribute::new(&header[*j])).collect(),
+        };
+        for (i, row) in rows.iter().enumerate().skip(1) {
+            if row.len() != header.len() {
+                return Err(error(format!("row {}: expected {} fields, found {}", i + 1, header.len(), row.len())));
+            }
+            let ageclass = &row[ageclass_column];
+            let index = labels.and_then(|labels| labels.iter().position(|label| label == ageclass)).or_else(
+                || ageclass.parse::<usize>().ok().filter(|a| *a >= 1 && *a <= n_ageclasses).map(|a| a - 1)
+            ).ok_or_else(|| error(format!("row {}: invalid ageclass: {}", i + 1, ageclass)))?;
+            population.ageclasses.push(index);
+            population.initial_states.push(state_column.map_or(default_state, |j| row[j].as_str()));
+            for (attribute, j) in population.attributes.iter_mut().zip(&attribute_columns) {
+                attribute.push(&row[*j]);
+            }
+        }
+        Ok(population)
+    }
+    
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+    
+    pub fn len(&self) -> usize {
+        self.ageclasses.len()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.ageclasses.is_empty()
+    }
+    
+    pub fn attribute(&self, name: &str) -> Option<&PopulationAttribute> {
+        self.attributes.iter().find(|attribute| attribute.name == name)
+    }
+    
+    /// Number of people starting in each state, by ageclass, as in `Config::initial_counts`.
+    pub fn initial_counts(&self, n_ageclasses: usize) -> HashMap<String, Vec<usize>> {
+        let mut counts: Vec<Vec<usize>> = vec![vec![0; n_ageclasses]; self.initial_states.values.len()];
+        for (ageclass, state) in self.ageclasses.iter().zip(&self.initial_states.indices) {
+            counts[*state][*ageclass] += 1;
+        }
+        self.initial_states.values.iter().cloned().zip(counts).collect()
+    }
+    
+    /// Resolves attribute-dependent parameters in a validated config to those of each person,
+    /// for `Simulation::new`.
+    pub fn model(self: &Arc<Self>, config: &Config, states: &[State]) -> PopulationModel {
+        let state_id = |name: &str| states.iter().find(|state| state.name == name).unwrap().id;
+        let population_config = config.population.as_ref().unwrap();
+        
+        let mut susceptibility = vec![1.0; self.len()];
+        for multipliers in population_config.susceptibility.iter().flatten() {
+            let attribute = self.attribute(&multipliers.attribute).unwrap();
+            let by_index: Vec<f64> = attribute.values.iter().map(
+                |value| multipliers.multipliers.get(value).copied().unwrap_or(1.0)
+            ).collect();
+            for (s, index) in susceptibility.iter_mut().zip(&attribute.indices) {
+                *s *= by_index[*index];
+            }
+        }
+        
+        let group_layers = population_config.group_contacts.iter().flatten().map(|group_contacts| {
+            let attribute = self.attribute(&group_contacts.attribute).unwrap();
+            let mut groups = vec![Vec::new(); attribute.values.len()];
+            for (person, index) in attribute.indices.iter().enumerate() {
+                if !attribute.values[*index].is_empty() {
+                    groups[*index].push(person);
+                }
+            }
+            groups.retain(|members| !members.is_empty());
+            GroupLayer { name: attribute.name.clone(), beta: group_contacts.beta, groups }
+        }).collect();
+        
+        let mut transition_cdfs = vec![None; states.len()];
+        for state_config in &config.infected_states {
+            if let Some(by_attribute) = &state_config.probabilities_by_attribute {
+                let attribute = self.attribute(&by_attribute.attribute).unwrap();
+                let mut cdfs = Vec::new();
+                let variant_by_index: Vec<Option<usize>> = attribute.values.iter().map(|value| {
+                    by_attribute.probabilities.get(value).map(|probabilities| {
+                        cdfs.push(probabilities.iter().map(cumulative_sum).collect());
+                        cdfs.len() - 1
+                    })
+                }).collect();
+                transition_cdfs[state_id(&state_config.name)] = Some(PersonTransitionCdfs {
+                    variants: attribute.indices.iter().map(|index| variant_by_index[*index]).collect(),
+                    cdfs,
+                });
+            }
+        }
+        
+        PopulationModel {
+            population: self.clone(),
+            initial_state_ids: self.initial_states.values.iter().map(|name| state_id(name)).collect(),
+            susceptibility,
+            group_layers,
+            transition_cdfs,
+            output_attributes: population_config.output_attributes.iter().flatten().map(
+                |name| self.attributes.iter().position(|attribute| &attribute.name == name).unwrap()
+            ).collect(),
+        }
+    }
+}
+
+/// A population with parameters resolved for each person, as simulated by `Simulation`.
+#[derive(Debug, Clone)]
+pub struct PopulationModel {
+    population: Arc<Population>,
+    // Indexed by position in `population.initial_states.values`
+    initial_state_ids: Vec<usize>,
+    pub susceptibility: Vec<f64>,
+    pub group_layers: Vec<GroupLayer>,
+    /// Transition CDFs that differ by person, by state id
+    pub transition_cdfs: Vec<Option<PersonTransitionCdfs>>,
+    // Indices of `population.attributes`
+    output_attributes: Vec<usize>,
+}
+
+/// Groups (lists of people) of `GroupContacts` on an attribute.
+#[derive(Debug, Clone)]
+pub struct GroupLayer {
+    pub name: String,
+    pub beta: f64,
+    pub groups: Vec<Vec<usize>>,
+}
+
+/// Transition CDFs by ageclass for each value of an attribute given `probabilities_by_attribute`,
+/// and the index of each person's CDFs (or `None`, to use those of the state).
+#[derive(Debug, Clone)]
+pub struct PersonTransitionCdfs {
+    pub variants: Vec<Option<usize>>,
+    pub cdfs: Vec<Vec<Vec<f64>>>,
+}
+
+impl PopulationModel {
+    pub fn len(&self) -> usize {
+        self.population.len()
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.population.is_empty()
+    }
+    
+    pub fn ageclass(&self, person: usize) -> usize {
+        self.population.ageclasses[person]
+    }
+    
+    pub fn initial_state_id(&self, person: usize) -> usize {
+        self.initial_state_ids[self.population.initial_states.indices[person]]
+    }
+    
+    /// Attributes by which counts are aggregated.
+    pub fn output_attributes(&self) -> impl Iterator<Item = &PopulationAttribute> + '_ {
+        self.output_attributes.iter().map(move |a| &self.population.attributes[*a])
+    }
+}
+
+/// Reads the population file of a config, if it has one, relative to `base_dir`, and sets
+/// `initial_counts` to the numbers of people starting in each state. Any `initial_counts`
+/// already given must agree with those of the population (as in the config written to `Meta`).
+pub(crate) fn resolve_population(
+    json: &mut serde_json::Value, base_dir: &Path
+) -> Result<Option<Arc<Population>>, Error> {
+    let path = match json.get("population").and_then(|p| p.get("path")).and_then(|p| p.as_str()) {
+        Some(path) => base_dir.join(path).to_string_lossy().into_owned(),
+        None => return Ok(None),
+    };
+    // Missing fields are reported when the config is deserialized
+    let n_ageclasses = match json.get("n_ageclasses").and_then(|n| n.as_u64()) {
+        Some(n) => n as usize,
+        None => return Ok(None),
+    };
+    let susceptible_state = match json.get("susceptible_state").and_then(|s| s.as_str()) {
+        Some(name) => name.to_string(),
+        None => return Ok(None),
+    };
+    
+    let labels = crate::ageclasses::json_ageclass_labels(json);
+    let population = Population::read(&path, n_ageclasses, labels.as_deref(), &susceptible_state)?;
+    let counts = population.initial_counts(n_ageclasses);
+    if let Some(given) = json.get("initial_counts").filter(|given| !given.is_null()) {
+        let nonzero = |counts: HashMap<String, Vec<usize>>| -> HashMap<String, Vec<usize>> {
+            counts.into_iter().filter(|(_, c)| c.iter().any(|x| *x > 0)).collect()
+        };
+        let given: Option<HashMap<String, Vec<usize>>> = serde_json::from_value(given.clone()).ok();
+        if given.map(nonzero) != Some(nonzero(counts.clone())) {
+            return Err(Error::InvalidConfig(vec![ConfigError {
+                path: "$.initial_counts".into(),
+                problem: ConfigProblem::InvalidValue {
+                    message: format!("does not match the population in {}; omit it to use the population", path)
+                },
+            }]));
+        }
+    }
+    json["initial_counts"] = serde_json::to_value(counts)?;
+    Ok(Some(Arc::new(population)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::*;
+    use crate::errors::*;
+    use crate::fixtures::*;
+    
+    #[test]
+    fn test_initial_counts_from_population() {
+        let config = read_population_config();
+        assert_eq!(config.initial_counts["S"], vec![3, 4]);
+        assert_eq!(config.initial_counts["E"], vec![0, 1]);
+        assert_eq!(config.infected_states[1].probabilities_by_attribute.as_ref().unwrap().probabilities["yes"], vec![
+            vec![0.9, 0.1], vec![0.5, 0.5]
+        ]);
+        
+        // Counts given with the population must agree with it
+        let mut json = serde_json::to_value(&config).unwrap();
+        assert!(Config::from_json(&json.to_string()).is_ok());
+        json["initial_counts"]["E"] = serde_json::json!([1, 1]);
+        match Config::from_json(&json.to_string()) {
+            Err(Error::InvalidConfig(errors)) => assert_eq!(errors[0].path, "$.initial_counts"),
+            _ => panic!("expected invalid config"),
+        }
+    }
+    
+    #[test]
+    fn test_attributes_must_exist() {
+        let mut config = read_population_config();
+        let population_config = config.population.as_mut().unwrap();
+        population_config.output_attributes = Some(vec!["occupation".into()]);
+        population_config.susceptibility.as_mut().unwrap()[0].multipliers.insert("maybe".into(), 1.5);
+        let paths: Vec<_> = match config.validate() {
+            Err(Error::InvalidConfig(errors)) => errors.into_iter().map(|e| e.path).collect(),
+            _ => panic!("expected invalid config"),
+        };
+        assert_eq!(paths, vec![
+            "$.population.susceptibility[0].multipliers.maybe", "$.population.output_attributes[0]"
+        ]);
+    }
+    
+    #[test]
+    fn test_periods_with_probabilities_by_attribute() {
+        // Durations may change by period, but not probabilities, which are given by attribute
+        let mut config = read_population_config();
+        config.set("infected_states[1].periods", serde_json::json!([
+            { "t_start": 20.0, "mean_duration": 3.0 }
+        ])).unwrap();
+        assert!(config.validate().is_ok());
+        
+        config.set("infected_states[1].periods[0].probabilities", serde_json::json!([[0.5, 0.5], [0.5, 0.5]])).unwrap();
+        let paths: Vec<_> = match config.validate() {
+            Err(Error::InvalidConfig(errors)) => errors.into_iter().map(|e| e.path).collect(),
+            _ => panic!("expected invalid config"),
+        };
+        assert_eq!(paths, vec!["$.infected_states[1]"]);
+    }
+    
+    #[test]
+    fn test_run_population() {
+        let config = read_population_config();
+        let output = SimulationBuilder::new(config).record_all_events(true).run().unwrap();
+        
+        // Everyone is an individual, and every infection is by an infectious individual
+        assert_eq!(output.events.individuals.len(), 8);
+        let n_infections = output.events.infections.len();
+        assert_eq!(n_infections, output.n_infections);
+        
+        // Counts by attribute add up to counts by ageclass
+        let t_end = output.counts.last().unwrap().time;
+        let total = |state: &str| -> usize {
+            output.counts.iter().filter(|r| r.time == t_end && r.state == state).map(|r| r.count).sum()
+        };
+        for attribute in &["comorbidity", "region"] {
+            for state in &["S", "E", "I", "R", "D"] {
+                let by_attribute: usize = output.attribute_counts.iter().filter(
+                    |r| r.time == t_end && r.attribute == *attribute && r.state == *state
+                ).map(|r| r.count).sum();
+                assert_eq!(by_attribute, total(state));
+            }
+        }
+        assert_eq!(total("S"), 7 - n_infections);
+    }
+}
@@ -5,6 +5,7 @@ use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 
 
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::path::Path;
 use std::fs::File;
@@ -113,7 +114,7 @@ impl StanModel {
                 }
             }
         }
-    
+        
         for var in self.observation_variables() {
             if self.config.infer_observation_delays[&var] {
                 if params {
@@ -247,7 +248,7 @@ impl StanModel {
         for var in self.fixed_delay_vars() {
             v.push(format!("{}_mean_duration", var));
         }
-    
+        
         for (from, to) in self.transitions_with_probabilities() {
             v.push(format!("p_{}_{}", from, to));
         }
@@ -445,7 +446,7 @@ impl StanModel {
                 }
             }.join("\n"))
         }
-    
+        
         for name in self.observation_variables() {
             sections.push(vec![
                 format!("{0} = state[index:(index + {0}_gamma_shape)];", name),
@@ -632,7 +633,7 @@ impl StanModel {
     
     fn ddt_obs_var_changes(&self) -> String {
         let mut sections = Vec::new();
-    
+        
         for obs_var in &self.structure.observation_variables {
             let name = obs_var.name.clone();
             let start_state = obs_var.start_state.clone();
@@ -642,7 +643,7 @@ impl StanModel {
                 "d_{0}[1] = d_{1}_{2};",
                 name, start_state, end_state
             );
-        
+            
             let else_gamma0 = vec![
                 format!(
                     "d_{0}[1] = d_{1}_{2} - d_{0}_{0}[1];",
@@ -656,7 +657,7 @@ impl StanModel {
                     name
                 ),
             ].join("\n");
-        
+            
             sections.push(vec![
                 format!("if({}_gamma_shape == 0) {{", name),
                     indent(&if_gamma0, 2, 1),
@@ -666,7 +667,7 @@ impl StanModel {
                 "}".into()
             ].join("\n"));
         }
-    
+        
         sections.join("\n\n")
     }
     
@@ -781,21 +782,21 @@ impl StanModel {
     fn assign_x_i(&self) -> String {
         let increment: String = "index += 1;".into();
         let mut sections = Vec::new();
-    
+        
         sections.push("int index = 1;".into());
-    
+        
         sections.push(vec![
             "x_i[index] = n_substates;".into(),
             increment.clone()
         ].join("\n"));
-    
+        
         for var in self.variables_with_delays(true, true) {
             sections.push(vec![
                 format!("x_i[index] = {}_gamma_shape;", var),
                 increment.clone(),
             ].join("\n"));
         }
-    
+        
         format_block(sections.join("\n\n"))
     }
     
@@ -864,9 +865,9 @@ impl StanModel {
     
     fn gq_ics(&self) -> String {
         let mut sections = Vec::new();
-    
+        
         sections.push("int index = 1;".into());
-    
+        
         for (state, isgamma) in self.state_isgamma_pairs() {
             if isgamma {
                 sections.push(vec![
@@ -883,7 +884,7 @@ impl StanModel {
                 ].join("\n"));
             }
         }
-    
+        
         for obs_var in self.observation_variables() {
             sections.push(vec![
                 format!("initial_state[index:(index + {}_gamma_shape)] = rep_array(", obs_var),
@@ -892,7 +893,7 @@ impl StanModel {
                 format!("index += {}_gamma_shape + 1;", obs_var),
             ].join("\n"));
         }
-    
+        
         format_block(sections.join("\n\n"))
     }
     
@@ -928,15 +929,15 @@ impl StanModel {
         
         sections.push({
             let mut lines = Vec::new();
-        
+            
             lines.push(format!("{}[i] = N", self.susceptible_state()));
-        
+            
             for (name, isgamma) in self.state_isgamma_pairs() {
                 lines.push(format!("  - {}[i]", name));
             }
-        
+            
             lines.push(";".into());
-        
+            
             lines.join("\n")
         });
         
@@ -1038,27 +1039,46 @@ impl From<serde_json::error::Error> for Error {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Input to `sirstan`: a model structure and the configuration of the inference.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct InputData {
     structure: ModelStructure,
     config: ModelConfig,
 }
 
+impl InputData {
+    /// JSON Schema describing `sirstan` input, as printed by `sirstan schema`.
+    pub fn json_schema() -> schemars::schema::RootSchema {
+        schemars::schema_for!(InputData)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OutputData {
     stan_code: String,
 }
 
 pub fn run() -> Result<OutputData, Error> {
-    // Read config from file specified in first command-line argument or from stdin
+    // Read config from file specified on the command line or from stdin,
+    // as JSON, YAML or TOML (detected from the extension, or given by --format)
     let args: Vec<String> = std::env::args().collect();
-    let json_data = if args.len() > 1 {
-        read_data_from_file(&args[1])?
+    let mut input_path = None;
+    let mut format = None;
+    let mut i = 1;
+    while i < args.len() {
+        if args[i] == "--format" {
+            let value = args.get(i + 1).ok_or_else(
+                || Error::InvalidArgument("missing value for --format".into())
+            )?;
+            format = Some(InputFormat::parse(value)?);
+            i += 2;
+        }
+        else {
+            input_path = Some(args[i].as_str());
+            i += 1;
+        }
     }
-    else {
-        read_data_from_stdin()?
-    };
-    let input_data: InputData = serde_json::from_str(&json_data)?;
+    let input_data: InputData = read_input(input_path, format)?;
     
     // Generate Stan code
     let stan_code = StanModel::new(
@@ -1079,7 +1099,7 @@ mod test {
         let input_filename = format!("tests/{}.json", name);
         let goal_filename = format!("tests/{}.stan", name);
         let gen_filename = format!("tests/{}-generated.stan", name);
-    
+        
         let json_data = read_data_from_file(&input_filename).unwrap();
         let input_data: InputData = serde_json::from_str(&json_data).unwrap();
         
@@ -0,0 +1,182 @@
+#![allow(non_snake_case)]
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// A stratification dimension (e.g. age group, risk group or sex), with a label for each stratum.
+///
+/// With `Config::strata`, ageclasses are the combinations of strata across all dimensions,
+/// in row-major order (the last dimension varying fastest): with dimensions `age` (`0-19`,
+/// `20+`) and `risk` (`low`, `high`), ageclass 0 is (`0-19`, `low`), ageclass 1 is
+/// (`0-19`, `high`), ageclass 2 is (`20+`, `low`), and so on. Arrays indexed by ageclass,
+/// such as `initial_counts` and rows of `probabilities`, follow the same order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct StratumDimension {
+    pub name: String,
+    pub labels: Vec<String>,
+}
+
+/// Output table columns that stratum dimensions cannot be named after.
+pub const RESERVED_COLUMN_NAMES: &[&str] = &[
+    "time", "id", "ageclass", "state", "initial_state", "count", "design_point", "replicate",
+    "label", "lower_bound", "upper_bound",
+];
+
+/// Whether a name collides with a reserved column name, ignoring case as SQLite does.
+pub fn is_reserved_column_name(name: &str) -> bool {
+    RESERVED_COLUMN_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(name))
+}
+
+/// Number of combined strata: the product of the number of labels in each dimension.
+pub fn n_strata(dimensions: &[StratumDimension]) -> usize {
+    dimensions.iter().map(|dimension| dimension.labels.len()).product()
+}
+
+/// Label in each dimension of the combined stratum `ageclass`.
+pub fn stratum_labels(dimensions: &[StratumDimension], ageclass: usize) -> Vec<&str> {
+    let mut labels = Vec::with_capacity(dimensions.len());
+    let mut rest = ageclass;
+    for dimension in dimensions.iter().rev() {
+        labels.push(dimension.labels[rest % dimension.labels.len()].as_str());
+        rest /= dimension.labels.len();
+    }
+    labels.reverse();
+    labels
+}
+
+/// Kronecker product of square matrices, one per dimension, giving a matrix over the
+/// combined strata: entry (i, j) is the product of the entries of each matrix for the
+/// strata of i and j in that dimension.
+pub fn kronecker_product(matrices: &[Vec<Vec<f64>>]) -> Vec<Vec<f64>> {
+    matrices.iter().fold(vec![vec![1.0]], |A, B| {
+        let m = B.len();
+        let n = A.len() * m;
+        (0..n).map(|i| {
+            (0..n).map(|j| A[i / m][j / m] * B[i % m][j % m]).collect()
+        }).collect()
+    })
+}
+
+/// Whether a dimension name can be used unquoted as a column name: a letter or underscore
+/// followed by letters, digits or underscores.
+pub fn is_valid_column_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::*;
+    use crate::db::*;
+    use crate::errors::*;
+    use crate::strata::*;
+    use crate::fixtures::*;
+    
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    
+    #[test]
+    fn test_strata() {
+        let dimensions = vec![
+            StratumDimension { name: "age".into(), labels: vec!["0-19".into(), "20+".into()] },
+            StratumDimension { name: "risk".into(), labels: vec!["low".into(), "high".into(), "very_high".into()] },
+        ];
+        assert_eq!(n_strata(&dimensions), 6);
+        assert_eq!(stratum_labels(&dimensions, 0), vec!["0-19", "low"]);
+        assert_eq!(stratum_labels(&dimensions, 2), vec!["0-19", "very_high"]);
+        assert_eq!(stratum_labels(&dimensions, 4), vec!["20+", "high"]);
+        
+        let C = kronecker_product(&[
+            vec![vec![1.0, 2.0], vec![3.0, 4.0]],
+            vec![vec![0.0, 5.0], vec![6.0, 7.0]],
+        ]);
+        assert_eq!(C, vec![
+            vec![0.0, 5.0, 0.0, 10.0],
+            vec![6.0, 7.0, 12.0, 14.0],
+            vec![0.0, 15.0, 0.0, 20.0],
+            vec![18.0, 21.0, 24.0, 28.0],
+        ]);
+        
+        assert!(is_valid_column_name("risk_group"));
+        assert!(!is_valid_column_name("risk group"));
+        assert!(!is_valid_column_name("1st"));
+        assert!(is_reserved_column_name("Time"));
+        assert!(is_reserved_column_name("upper_bound"));
+        assert!(!is_reserved_column_name("risk"));
+    }
+    
+    #[test]
+    fn test_stratified_config() {
+        // The SEIR test config, with each ageclass split into low and high risk
+        let mut config = read_test_config();
+        config.n_ageclasses = 4;
+        config.strata = Some(vec![
+            StratumDimension { name: "age".into(), labels: vec!["young".into(), "old".into()] },
+            StratumDimension { name: "risk".into(), labels: vec!["low".into(), "high".into()] },
+        ]);
+        for cp in &mut config.contact_parameters {
+            cp.C_factors = Some(vec![cp.C.take().unwrap(), vec![vec![1.0, 0.5], vec![0.5, 1.0]]]);
+        }
+        config.infected_states[1].probabilities = Some(vec![
+            vec![0.99, 0.01], vec![0.95, 0.05], vec![0.9, 0.1], vec![0.8, 0.2]
+        ]);
+        config.initial_counts.insert("S".into(), vec![800, 200, 700, 300]);
+        config.initial_counts.insert("E".into(), vec![5, 0, 5, 0]);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.contact_matrices()[0][1], vec![0.5, 1.0, 0.25, 0.5]);
+        
+        let recorder = Rc::new(RefCell::new(SqliteRecorder::new(
+            rusqlite::Connection::open_in_memory().unwrap(), true, config.strata.clone()
+        )));
+        let mut sim = SimulationBuilder::new(config.clone()).observer(recorder.clone()).build().unwrap();
+        recorder.borrow_mut().start(&sim);
+        while sim.t < 20.0 && !sim.simulate(sim.t + 1.0) {}
+        recorder.borrow_mut().finish(&sim);
+        
+        let recorder = recorder.borrow();
+        let conn = recorder.connection();
+        let initial: Vec<(String, String, i64)> = conn.prepare(
+            "SELECT age, risk, SUM(count) FROM Counts WHERE time = 0 GROUP BY age, risk ORDER BY age DESC, risk DESC;"
+        ).unwrap().query_map(rusqlite::params![], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap().map(
+            |r| r.unwrap()
+        ).collect();
+        assert_eq!(initial, vec![
+            ("young".into(), "low".into(), 805), ("young".into(), "high".into(), 200),
+            ("old".into(), "low".into(), 705), ("old".into(), "high".into(), 300),
+        ]);
+        let n_high_risk_infected: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM Individuals WHERE risk = 'high';", rusqlite::params![], |row| row.get(0)
+        ).unwrap();
+        assert!(n_high_risk_infected > 0);
+        
+        config.n_ageclasses = 2;
+        let paths: Vec<_> = match config.validate() {
+            Err(Error::InvalidConfig(errors)) => errors.into_iter().map(|e| e.path).collect(),
+            _ => panic!("expected invalid config"),
+        };
+        assert!(paths.contains(&"$.n_ageclasses".to_string()));
+        
+        // Dimension names must not collide with other columns, in any case
+        config.n_ageclasses = 4;
+        config.strata.as_mut().unwrap()[0].name = "Label".into();
+        config.strata.as_mut().unwrap()[1].name = "LABEL".into();
+        let paths: Vec<_> = match config.validate() {
+            Err(Error::InvalidConfig(errors)) => errors.into_iter().map(|e| e.path).collect(),
+            _ => panic!("expected invalid config"),
+        };
+        assert_eq!(paths, vec!["$.strata[0].name", "$.strata[1].name"]);
+        
+        config.strata.as_mut().unwrap()[0].name = "Risk".into();
+        config.strata.as_mut().unwrap()[1].name = "risk".into();
+        let paths: Vec<_> = match config.validate() {
+            Err(Error::InvalidConfig(errors)) => errors.into_iter().map(|e| e.path).collect(),
+            _ => panic!("expected invalid config"),
+        };
+        assert_eq!(paths, vec!["$.strata[1].name"]);
+    }
+}
@@ -0,0 +1,184 @@
+use crate::errors::*;
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Summary of an output DB written by `sirsim run`, `ensemble` or `sweep`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DbSummary {
+    pub meta: BTreeMap<String, serde_json::Value>,
+    pub runs: Vec<RunSummary>,
+}
+
+/// Summary statistics for a single run, computed from its counts and Rt statistics.
+///
+/// `design_point` and `replicate` are set only for DBs containing multiple runs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunSummary {
+    pub design_point: Option<i64>,
+    pub replicate: Option<i64>,
+    pub t_final: f64,
+    pub population_size: i64,
+    pub cumulative_infections: i64,
+    pub attack_rate: f64,
+    pub final_counts: BTreeMap<String, i64>,
+    pub peaks: BTreeMap<String, Peak>,
+}
+
+/// Time of the (first) maximum of a state's count, summed over ageclasses.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Peak {
+    pub time: f64,
+    pub count: i64,
+}
+
+impl DbSummary {
+    pub fn read_from_db(conn: &rusqlite::Connection) -> Result<Self, Error> {
+        let db_err = |e: rusqlite::Error| Error::InvalidDatabase(format!("{}", e));
+        
+        let mut meta = BTreeMap::new();
+        {
+            let mut stmt = conn.prepare("SELECT key, value FROM Meta;").map_err(db_err)?;
+            let rows = stmt.query_map(rusqlite::params![], |row| {
+                let value = match row.get_raw(1) {
+                    rusqlite::types::ValueRef::Integer(x) => x.into(),
+                    rusqlite::types::ValueRef::Real(x) => x.into(),
+                    rusqlite::types::ValueRef::Text(x) => String::from_utf8_lossy(x).into(),
+                    _ => serde_json::Value::Null,
+                };
+                Ok((row.get::<_, String>(0)?, value))
+            }).map_err(db_err)?;
+            for row in rows {
+                let (key, value) = row.map_err(db_err)?;
+                meta.insert(key, value);
+            }
+        }
+        
+        let mut runs: BTreeMap<RunKey, RunSummary> = BTreeMap::new();
+        {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {0}, time, state, SUM(count) FROM Counts GROUP BY {0}, time, state ORDER BY {0}, time;",
+                run_key_columns(conn, "Counts")?
+            )).map_err(db_err)?;
+            let rows = stmt.query_map(rusqlite::params![], |row| {
+                Ok((
+                    (row.get::<_, Option<i64>>(0)?, row.get::<_, Option<i64>>(1)?),
+                    row.get::<_, f64>(2)?, row.get::<_, String>(3)?, row.get::<_, i64>(4)?
+                ))
+            }).map_err(db_err)?;
+            
+            // Population size is the total count at the first time recorded
+            let mut t_first: BTreeMap<RunKey, f64> = BTreeMap::new();
+            for row in rows {
+                let (key, time, state, count) = row.map_err(db_err)?;
+                let run = runs.entry(key).or_insert_with(|| RunSummary {
+                    design_point: key.0,
+                    replicate: key.1,
+                    t_final: time,
+                    population_size: 0,
+                    cumulative_infections: 0,
+                    attack_rate: 0.0,
+                    final_counts: BTreeMap::new(),
+                    peaks: BTreeMap::new(),
+                });
+                if *t_first.entry(key).or_insert(time) == time {
+                    run.population_size += count;
+                }
+                run.t_final = time;
+                run.final_counts.insert(state.clone(), count);
+                let peak = run.peaks.entry(state).or_insert(Peak { time, count });
+                if count > peak.count {
+                    *peak = Peak { time, count };
+                }
+            }
+        }
+        if runs.is_empty() {
+            return Err(Error::InvalidDatabase("no counts recorded".into()));
+        }
+        
+        {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {0}, SUM(n_primary) FROM RtSufficientStatistics GROUP BY {0};",
+                run_key_columns(conn, "RtSufficientStatistics")?
+            )).map_err(db_err)?;
+            let rows = stmt.query_map(rusqlite::params![], |row| {
+                Ok((
+                    (row.get::<_, Option<i64>>(0)?, row.get::<_, Option<i64>>(1)?),
+                    row.get::<_, Option<i64>>(2)?
+                ))
+            }).map_err(db_err)?;
+            for row in rows {
+                let (key, n_infections) = row.map_err(db_err)?;
+                if let Some(run) = runs.get_mut(&key) {
+                    run.cumulative_infections = n_infections.unwrap_or(0);
+                }
+            }
+        }
+        for run in runs.values_mut() {
+            if run.population_size > 0 {
+                run.attack_rate = run.cumulative_infections as f64 / run.population_size as f64;
+            }
+        }
+        
+        Ok(Self { meta, runs: runs.into_values().collect() })
+    }
+}
+
+// (design_point, replicate)
+type RunKey = (Option<i64>, Option<i64>);
+
+/// Columns identifying runs in a table, substituting NULL for any that are missing.
+fn run_key_columns(conn: &rusqlite::Connection, table_name: &str) -> Result<String, Error> {
+    let mut columns = Vec::new();
+    for column_name in &["design_point", "replicate"] {
+        columns.push(if has_column(conn, table_name, column_name)? { *column_name } else { "NULL" });
+    }
+    Ok(columns.join(", "))
+}
+
+fn has_column(conn: &rusqlite::Connection, table_name: &str, column_name: &str) -> Result<bool, Error> {
+    let db_err = |e: rusqlite::Error| Error::InvalidDatabase(format!("{}", e));
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({});", table_name)).map_err(db_err)?;
+    let names = stmt.query_map(rusqlite::params![], |row| row.get::<_, String>(1)).map_err(db_err)?;
+    for name in names {
+        if name.map_err(db_err)? == column_name {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::*;
+    use crate::db::*;
+    use crate::summary::*;
+    use crate::fixtures::*;
+    
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    
+    #[test]
+    fn test_summarize_run() {
+        let config = read_test_config();
+        let output = SimulationBuilder::new(config.clone()).run().unwrap();
+        
+        let recorder = Rc::new(RefCell::new(
+            SqliteRecorder::new(rusqlite::Connection::open_in_memory().unwrap(), false, None)
+        ));
+        let mut sim = SimulationBuilder::new(config).observer(recorder.clone()).build().unwrap();
+        recorder.borrow_mut().start(&sim);
+        while sim.t < 60.0 && !sim.simulate(sim.t + 1.0) {}
+        recorder.borrow_mut().finish(&sim);
+        
+        let summary = DbSummary::read_from_db(recorder.borrow().connection()).unwrap();
+        assert_eq!(summary.runs.len(), 1);
+        let run = &summary.runs[0];
+        assert_eq!(run.replicate, None);
+        assert_eq!(run.population_size, 2010);
+        assert_eq!(run.cumulative_infections as usize, output.rt.iter().map(|r| r.n_primary).sum::<usize>());
+        assert_eq!(run.final_counts.values().sum::<i64>(), 2010);
+        assert_eq!(summary.meta["n_infections"], run.cumulative_infections);
+        assert!(summary.meta.contains_key("end_time"));
+    }
+}
@@ -0,0 +1,315 @@
+use crate::config::*;
+use crate::db;
+use crate::errors::*;
+use crate::ibm::to_i64;
+
+use rand::Rng;
+use rand::seq::SliceRandom;
+use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+use rayon::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// A set of config variations to run: parameters referenced by JSON path
+/// (as in `Config::set`), and a design saying which combinations of values to use.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SweepSpec {
+    pub parameters: Vec<SweepParameter>,
+    pub design: Design,
+    
+    /// Runs per design point (default 1). Replicate `r` uses the same seed at every
+    /// design point, so that differences between points are not swamped by noise.
+    pub replicates: Option<usize>,
+}
+
+/// A config field to vary: either a list of `values` (full factorial designs)
+/// or a range `[min, max]` (sampled designs).
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SweepParameter {
+    pub path: String,
+    pub values: Option<Vec<serde_json::Value>>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type")]
+pub enum Design {
+    /// Every combination of parameter values.
+    FullFactorial,
+    
+    /// `n_points` points, one in each of `n_points` equal strata of every parameter's range.
+    LatinHypercube { n_points: usize, seed: Option<u64> },
+    
+    /// The first `n_points` points of a Sobol sequence (skipping the origin), scaled to ranges.
+    Sobol { n_points: usize },
+}
+
+/// Values for each parameter, in the order of `SweepSpec::parameters`.
+pub type DesignPoint = Vec<serde_json::Value>;
+
+impl SweepSpec {
+    pub fn replicates(&self) -> usize {
+        self.replicates.unwrap_or(1)
+    }
+    
+    pub fn design_points(&self) -> Result<Vec<DesignPoint>, Error> {
+        if self.parameters.is_empty() {
+            return Err(Error::InvalidArgument("sweep has no parameters".into()));
+        }
+        match &self.design {
+            Design::FullFactorial => {
+                let mut points = vec![Vec::new()];
+                for parameter in &self.parameters {
+                    let values = parameter.values.as_ref().ok_or_else(|| Error::InvalidArgument(
+                        format!("full factorial designs need values for {}", parameter.path)
+                    ))?;
+                    points = points.into_iter().flat_map(|point: DesignPoint| {
+                        values.iter().map(move |value| {
+                            let mut point = point.clone();
+                            point.push(value.clone());
+                            point
+                        })
+                    }).collect();
+                }
+                Ok(points)
+            },
+            Design::LatinHypercube { n_points, seed } => {
+                let ranges = self.ranges()?;
+                let mut rng = Xoshiro256PlusPlus::seed_from_u64(seed.unwrap_or(0));
+                Ok(self.scale(latin_hypercube(*n_points, ranges.len(), &mut rng), &ranges))
+            },
+            Design::Sobol { n_points } => {
+                let ranges = self.ranges()?;
+                Ok(self.scale(sobol_sequence(*n_points, ranges.len())?, &ranges))
+            },
+        }
+    }
+    
+    fn ranges(&self) -> Result<Vec<(f64, f64)>, Error> {
+        self.parameters.iter().map(|parameter| match (parameter.min, parameter.max) {
+            (Some(min), Some(max)) if min <= max => Ok((min, max)),
+            _ => Err(Error::InvalidArgument(
+                format!("sampled designs need min <= max for {}", parameter.path)
+            )),
+        }).collect()
+    }
+    
+    fn scale(&self, unit_points: Vec<Vec<f64>>, ranges: &[(f64, f64)]) -> Vec<DesignPoint> {
+        unit_points.into_iter().map(|u| {
+            u.iter().zip(ranges).map(|(u, (min, max))| (min + u * (max - min)).into()).collect()
+        }).collect()
+    }
+    
+    /// The config for a design point.
+    pub fn apply(&self, config: &Config, point: &[serde_json::Value]) -> Result<Config, Error> {
+        let mut config = config.clone();
+        for (parameter, value) in self.parameters.iter().zip(point) {
+            config.set(&parameter.path, value.clone())?;
+        }
+        Ok(config)
+    }
+}
+
+/// Latin hypercube sample on the unit cube.
+pub fn latin_hypercube<R: Rng>(n_points: usize, n_dims: usize, rng: &mut R) -> Vec<Vec<f64>> {
+    let mut points = vec![vec![0.0; n_dims]; n_points];
+    for d in 0..n_dims {
+        let mut strata: Vec<usize> = (0..n_points).collect();
+        strata.shuffle(rng);
+        for (point, stratum) in points.iter_mut().zip(strata) {
+            point[d] = (stratum as f64 + rng.gen::<f64>()) / n_points as f64;
+        }
+    }
+    points
+}
+
+// Primitive polynomials (degree s, coefficients a) and initial direction numbers m
+// for dimensions 2 and up, from Joe & Kuo (2008)
+const SOBOL_PARAMETERS: &[(u32, u32, &[u32])] = &[
+    (1, 0, &[1]),
+    (2, 1, &[1, 3]),
+    (3, 1, &[1, 3, 1]),
+    (3, 2, &[1, 1, 1]),
+    (4, 1, &[1, 1, 3, 3]),
+    (4, 4, &[1, 3, 5, 13]),
+    (5, 2, &[1, 1, 5, 5, 17]),
+    (5, 4, &[1, 1, 5, 5, 5]),
+    (5, 7, &[1, 1, 7, 11, 19]),
+    (5, 11, &[1, 1, 5, 1, 1]),
+    (5, 13, &[1, 1, 1, 3, 11]),
+    (5, 14, &[1, 3, 5, 5, 31]),
+    (6, 1, &[1, 3, 3, 9, 7, 49]),
+    (6, 13, &[1, 1, 1, 15, 21, 21]),
+    (6, 16, &[1, 3, 1, 13, 27, 49]),
+    (6, 19, &[1, 1, 1, 15, 7, 5]),
+    (6, 22, &[1, 3, 1, 15, 13, 25]),
+    (6, 25, &[1, 1, 5, 5, 19, 61]),
+    (7, 1, &[1, 3, 7, 11, 23, 15, 103]),
+    (7, 4, &[1, 3, 7, 13, 13, 15, 69]),
+];
+
+const SOBOL_BITS: u32 = 32;
+
+/// The first `n_points` points of the Sobol sequence on the unit cube, after the origin.
+pub fn sobol_sequence(n_points: usize, n_dims: usize) -> Result<Vec<Vec<f64>>, Error> {
+    if n_dims > SOBOL_PARAMETERS.len() + 1 {
+        return Err(Error::InvalidArgument(format!(
+            "Sobol designs support at most {} parameters", SOBOL_PARAMETERS.len() + 1
+        )));
+    }
+    
+    // Direction numbers v[d][k], scaled by 2^32
+    let directions: Vec<Vec<u32>> = (0..n_dims).map(|d| {
+        if d == 0 {
+            return (0..SOBOL_BITS).map(|k| 1 << (SOBOL_BITS - 1 - k)).collect();
+        }
+        let (s, a, m) = SOBOL_PARAMETERS[d - 1];
+        let s = s as usize;
+        let mut v: Vec<u32> = (0..s).map(|k| m[k] << (SOBOL_BITS as usize - 1 - k)).collect();
+        for k in s..(SOBOL_BITS as usize) {
+            let mut x = v[k - s] ^ (v[k - s] >> s);
+            for j in 1..s {
+                if (a >> (s - 1 - j)) & 1 == 1 {
+                    x ^= v[k - j];
+                }
+            }
+            v.push(x);
+        }
+        v
+    }).collect();
+    
+    // Gray-code construction: point i + 1 differs from point i in the direction
+    // indexed by the lowest zero bit of i
+    let mut x = vec![0u32; n_dims];
+    let mut points = Vec::with_capacity(n_points);
+    for i in 0..n_points {
+        let c = (!i).trailing_zeros() as usize;
+        for (x_d, v_d) in x.iter_mut().zip(&directions) {
+            *x_d ^= v_d[c];
+        }
+        points.push(x.iter().map(|x_d| *x_d as f64 / 2f64.powi(SOBOL_BITS as i32)).collect());
+    }
+    Ok(points)
+}
+
+/// Runs every design point and replicate, writing results to `conn`: a `Design` table
+/// mapping design points (from 1) to parameter values, and output tables keyed by
+/// `design_point` and `replicate` (see `db::create_multi_run_tables`).
+///
+/// Runs are done in parallel on `n_threads` threads (default: one per CPU). After each
+/// batch is written, `progress` is called with the number of runs completed and the total.
+pub fn run_sweep(
+    config: &Config, spec: &SweepSpec, n_threads: Option<usize>, conn: &rusqlite::Connection,
+    progress: &mut dyn FnMut(usize, usize)
+) -> Result<(), Error> {
+    let points = spec.design_points()?;
+    let configs = points.iter().map(|point| {
+        let config = spec.apply(config, point)?;
+        config.validate()?;
+        Ok(config)
+    }).collect::<Result<Vec<_>, Error>>()?;
+    
+    let builder = SimulationBuilder::new(config.clone());
+    let (master_rng_seed, rng_seeds) = builder.replicate_rng_seeds(spec.replicates());
+    
+    conn.execute_batch("BEGIN;").unwrap();
+    db::create_multi_run_tables(conn, &["design_point", "replicate"], config.strata.as_deref());
+    conn.execute_batch("CREATE TABLE Design (design_point INTEGER, path TEXT, value);").unwrap();
+    db::write_provenance(conn, builder.clone_with(master_rng_seed).config());
+    db::write_ageclasses(conn, config);
+    db::write_meta_entry(conn, "master_rng_seed", db::seed_to_sql(master_rng_seed));
+    db::write_meta_entry(conn, "sweep", serde_json::to_string(spec).unwrap());
+    {
+        let mut insert = conn.prepare("INSERT INTO Design VALUES (?, ?, ?);").unwrap();
+        for (i, point) in points.iter().enumerate() {
+            for (parameter, value) in spec.parameters.iter().zip(point) {
+                let value: Box<dyn rusqlite::ToSql> = match value {
+                    serde_json::Value::Number(x) => Box::new(x.as_f64()),
+                    serde_json::Value::String(x) => Box::new(x.clone()),
+                    _ => Box::new(value.to_string()),
+                };
+                insert.execute(rusqlite::params![to_i64(i + 1), parameter.path, value]).unwrap();
+            }
+        }
+    }
+    conn.execute_batch("COMMIT;").unwrap();
+    
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(n_threads.unwrap_or(0)).build().map_err(
+        |e| Error::InvalidArgument(format!("{}", e))
+    )?;
+    let jobs: Vec<(usize, usize)> = (0..configs.len()).flat_map(
+        |i| (0..rng_seeds.len()).map(move |r| (i, r))
+    ).collect();
+    
+    // Run in batches, writing each batch before starting the next, so that
+    // outputs don't accumulate in memory
+    let batch_size = 4 * pool.current_num_threads();
+    let mut n_done = 0;
+    for batch in jobs.chunks(batch_size) {
+        let outputs = pool.install(|| batch.par_iter().map(|&(i, r)| {
+            SimulationBuilder::new(configs[i].clone()).rng_seed(rng_seeds[r]).record_all_events(false).run()
+        }).collect::<Vec<_>>());
+        
+        conn.execute_batch("BEGIN;").unwrap();
+        for (&(i, r), output) in batch.iter().zip(outputs) {
+            db::write_multi_run_output(
+                conn, &[to_i64(i + 1), to_i64(r + 1)], &output?, config.strata.as_deref()
+            );
+        }
+        conn.execute_batch("COMMIT;").unwrap();
+        n_done += batch.len();
+        progress(n_done, jobs.len());
+    }
+    db::write_end_time(conn);
+    
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sweep::*;
+    use crate::fixtures::*;
+    
+    #[test]
+    fn test_sobol_sequence() {
+        let points = sobol_sequence(4, 2).unwrap();
+        assert_eq!(points, vec![
+            vec![0.5, 0.5], vec![0.75, 0.25], vec![0.25, 0.75], vec![0.375, 0.375]
+        ]);
+    }
+    
+    #[test]
+    fn test_latin_hypercube_strata() {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(1);
+        let points = latin_hypercube(10, 3, &mut rng);
+        for d in 0..3 {
+            let mut strata: Vec<usize> = points.iter().map(|p| (p[d] * 10.0) as usize).collect();
+            strata.sort();
+            assert_eq!(strata, (0..10).collect::<Vec<_>>());
+        }
+    }
+    
+    #[test]
+    fn test_run_full_factorial() {
+        let config = read_test_config();
+        let spec: SweepSpec = serde_json::from_value(serde_json::json!({
+            "parameters": [
+                { "path": "contact_parameters[0].beta", "values": [0.2, 0.3, 0.4] },
+                { "path": "infected_states[0].mean_duration", "values": [2, 3] }
+            ],
+            "design": { "type": "FullFactorial" },
+            "replicates": 2
+        })).unwrap();
+        
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        run_sweep(&config, &spec, Some(2), &conn, &mut |_, _| {}).unwrap();
+        
+        let count = |sql: &str| conn.query_row(sql, rusqlite::params![], |row| row.get::<_, i64>(0)).unwrap();
+        assert_eq!(count("SELECT COUNT(*) FROM Design;"), 12);
+        assert_eq!(count("SELECT COUNT(*) FROM Runs;"), 12);
+        assert_eq!(count("SELECT COUNT(DISTINCT rng_seed) FROM Runs;"), 2);
+        assert_eq!(count("SELECT COUNT(DISTINCT design_point) FROM Counts;"), 6);
+    }
+}
@@ -0,0 +1,400 @@
+use crate::errors::*;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use rand::Rng;
+use rand_xoshiro::rand_core::SeedableRng;
+use rand_xoshiro::Xoshiro256PlusPlus;
+
+/// A single individual in a transmission tree.
+#[derive(Debug, Clone)]
+pub struct TreeNode {
+    pub id: usize,
+    pub ageclass: usize,
+    pub t_infected: f64,
+    pub infector_id: Option<usize>,
+    pub state_history: Vec<(f64, String)>,
+    pub sampled: bool,
+}
+
+/// The infector→infectee forest recorded in a run DB's `Individuals`, `Infections`
+/// and `Transitions` tables (which requires `record_all_events`).
+///
+/// Initial infecteds, which have no recorded infector, are the roots.
+#[derive(Debug, Clone)]
+pub struct TransmissionTree {
+    nodes: BTreeMap<usize, TreeNode>,
+    children: BTreeMap<usize, Vec<usize>>,
+}
+
+impl TransmissionTree {
+    pub fn read_from_db(conn: &rusqlite::Connection) -> Result<Self, Error> {
+        let db_err = |e: rusqlite::Error| Error::InvalidDatabase(format!("{}", e));
+        
+        let mut infections = Vec::new();
+        {
+            let mut stmt = conn.prepare(
+                "SELECT time, infected_id, infectious_id FROM Infections ORDER BY time, infected_id;"
+            ).map_err(db_err)?;
+            let rows = stmt.query_map(rusqlite::params![], |row| {
+                Ok((row.get::<_, f64>(0)?, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?))
+            }).map_err(db_err)?;
+            for row in rows {
+                let (t, infected_id, infectious_id) = row.map_err(db_err)?;
+                infections.push((t, infected_id as usize, infectious_id as usize));
+            }
+        }
+        let infection_of: BTreeMap<usize, (f64, usize)> = infections.iter().map(
+            |(t, infected_id, infectious_id)| (*infected_id, (*t, *infectious_id))
+        ).collect();
+        
+        // In full-agent mode, Individuals lists the whole population from the start,
+        // including those never infected
+        let uninfected_states = uninfected_states(conn)?;
+        let mut nodes = BTreeMap::new();
+        {
+            let mut stmt = conn.prepare(
+                "SELECT time, id, ageclass, initial_state FROM Individuals ORDER BY id;"
+            ).map_err(db_err)?;
+            let rows = stmt.query_map(rusqlite::params![], |row| {
+                Ok((row.get::<_, f64>(0)?, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?, row.get::<_, String>(3)?))
+            }).map_err(db_err)?;
+            let mut n_individuals = 0;
+            for row in rows {
+                let (t, id, ageclass, initial_state) = row.map_err(db_err)?;
+                n_individuals += 1;
+                let id = id as usize;
+                let infection = infection_of.get(&id);
+                if infection.is_none() && uninfected_states.contains(&initial_state) {
+                    continue;
+                }
+                
+                // Individuals present before their infection enter the infected state by a transition
+                let t_infected = infection.map(|(t_infection, _)| *t_infection).unwrap_or(t);
+                nodes.insert(id, TreeNode {
+                    id,
+                    ageclass: ageclass as usize,
+                    t_infected,
+                    infector_id: infection.map(|(_, infectious_id)| *infectious_id),
+                    state_history: if t == t_infected { vec![(t, initial_state)] } else { Vec::new() },
+                    sampled: true,
+                });
+            }
+            if n_individuals == 0 {
+                return Err(Error::InvalidDatabase(
+                    "no individuals recorded; was the run made with record_all_events?".into()
+                ));
+            }
+        }
+        
+        let mut children: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (_, infected_id, infectious_id) in &infections {
+            children.entry(*infectious_id).or_default().push(*infected_id);
+        }
+        
+        {
+            // Transitions out of the susceptible state duplicate the initial state in Individuals
+            let mut stmt = conn.prepare(
+                "SELECT time, id, end_state FROM Transitions ORDER BY time, id;"
+            ).map_err(db_err)?;
+            let rows = stmt.query_map(rusqlite::params![], |row| {
+                Ok((row.get::<_, f64>(0)?, row.get::<_, i64>(1)?, row.get::<_, String>(2)?))
+            }).map_err(db_err)?;
+            for row in rows {
+                let (t, id, end_state) = row.map_err(db_err)?;
+                if let Some(node) = nodes.get_mut(&(id as usize)) {
+                    if !(t == node.t_infected && node.state_history.len() == 1 && node.state_history[0].1 == end_state) {
+                        node.state_history.push((t, end_state));
+                    }
+                }
+            }
+        }
+        
+        Ok(Self { nodes, children })
+    }
+    
+    pub fn nodes(&self) -> impl Iterator<Item = &TreeNode> {
+        self.nodes.values()
+    }
+    
+    pub fn roots(&self) -> Vec<usize> {
+        self.nodes.values().filter(|node| node.infector_id.is_none()).map(|node| node.id).collect()
+    }
+    
+    fn children_of(&self, id: usize) -> &[usize] {
+        self.children.get(&id).map(|c| c.as_slice()).unwrap_or(&[])
+    }
+    
+    /// Subsamples individuals independently with probability `fraction`.
+    ///
+    /// The resulting tree contains the sampled individuals plus all their ancestors,
+    /// so that it remains connected; unsampled ancestors are marked with `sampled = false`.
+    pub fn subsample(&self, fraction: f64, rng_seed: u64) -> Self {
+        let mut rng = Xoshiro256PlusPlus::seed_from_u64(rng_seed);
+        
+        let mut kept = BTreeSet::new();
+        let mut sampled = BTreeSet::new();
+        for node in self.nodes.values() {
+            if rng.gen::<f64>() < fraction {
+                sampled.insert(node.id);
+                let mut id_opt = Some(node.id);
+                while let Some(id) = id_opt {
+                    if !kept.insert(id) {
+                        break;
+                    }
+                    id_opt = self.nodes.get(&id).and_then(|n| n.infector_id);
+                }
+            }
+        }
+        
+        let nodes = kept.iter().filter_map(|id| self.nodes.get(id)).map(|node| {
+            TreeNode { sampled: sampled.contains(&node.id), ..node.clone() }
+        }).map(|node| (node.id, node)).collect();
+        let children = self.children.iter().filter(|(id, _)| kept.contains(id)).map(|(id, c)| {
+            (*id, c.iter().copied().filter(|child| kept.contains(child)).collect())
+        }).collect();
+        
+        Self { nodes, children }
+    }
+    
+    /// Formats the forest as Newick, one tree per line, with individual IDs as labels
+    /// and branch lengths equal to the time between infector's and infectee's infections.
+    pub fn to_newick(&self) -> String {
+        let mut trees = Vec::new();
+        for root_id in self.roots() {
+            // Iterative post-order traversal, since chains of transmission can be very deep
+            let mut output = String::new();
+            let mut stack = vec![(root_id, 0)];
+            while let Some((id, child_index)) = stack.pop() {
+                let children = self.children_of(id);
+                if child_index == 0 && !children.is_empty() {
+                    output.push('(');
+                }
+                if child_index < children.len() {
+                    if child_index > 0 {
+                        output.push(',');
+                    }
+                    stack.push((id, child_index + 1));
+                    stack.push((children[child_index], 0));
+                }
+                else {
+                    if !children.is_empty() {
+                        output.push(')');
+                    }
+                    let node = &self.nodes[&id];
+                    output.push_str(&format!("{}", id));
+                    if let Some(infector) = node.infector_id.and_then(|i| self.nodes.get(&i)) {
+                        output.push_str(&format!(":{}", node.t_infected - infector.t_infected));
+                    }
+                }
+            }
+            output.push(';');
+            trees.push(output);
+        }
+        trees.join("\n") + "\n"
+    }
+    
+    pub fn to_graphml(&self) -> String {
+        let mut lines = vec![
+            r#"<?xml version="1.0" encoding="UTF-8"?>"#.to_string(),
+            r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#.to_string(),
+            r#"  <key id="ageclass" for="node" attr.name="ageclass" attr.type="int"/>"#.to_string(),
+            r#"  <key id="t_infected" for="node" attr.name="t_infected" attr.type="double"/>"#.to_string(),
+            r#"  <key id="state_history" for="node" attr.name="state_history" attr.type="string"/>"#.to_string(),
+            r#"  <key id="is_root" for="node" attr.name="is_root" attr.type="boolean"/>"#.to_string(),
+            r#"  <key id="sampled" for="node" attr.name="sampled" attr.type="boolean"/>"#.to_string(),
+            r#"  <key id="branch_length" for="edge" attr.name="branch_length" attr.type="double"/>"#.to_string(),
+            r#"  <graph id="transmission_tree" edgedefault="directed">"#.to_string(),
+        ];
+        for node in self.nodes.values() {
+            lines.push(format!(r#"    <node id="n{}">"#, node.id));
+            lines.push(format!(r#"      <data key="ageclass">{}</data>"#, node.ageclass));
+            lines.push(format!(r#"      <data key="t_infected">{}</data>"#, node.t_infected));
+            lines.push(format!(r#"      <data key="state_history">{}</data>"#, xml_escape(&format_state_history(node))));
+            lines.push(format!(r#"      <data key="is_root">{}</data>"#, node.infector_id.is_none()));
+            lines.push(format!(r#"      <data key="sampled">{}</data>"#, node.sampled));
+            lines.push("    </node>".to_string());
+        }
+        for (infector_id, infectee_id, branch_length) in self.edges() {
+            lines.push(format!(r#"    <edge source="n{}" target="n{}">"#, infector_id, infectee_id));
+            lines.push(format!(r#"      <data key="branch_length">{}</data>"#, branch_length));
+            lines.push("    </edge>".to_string());
+        }
+        lines.push("  </graph>".to_string());
+        lines.push("</graphml>".to_string());
+        lines.join("\n") + "\n"
+    }
+    
+    pub fn to_nodes_csv(&self) -> String {
+        let mut lines = vec!["id,ageclass,t_infected,infector_id,is_root,sampled,state_history".to_string()];
+        for node in self.nodes.values() {
+            lines.push(format!(
+                "{},{},{},{},{},{},{}",
+                node.id, node.ageclass, node.t_infected,
+                node.infector_id.map(|i| format!("{}", i)).unwrap_or_default(),
+                node.infector_id.is_none(), node.sampled,
+                format_state_history(node)
+            ));
+        }
+        lines.join("\n") + "\n"
+    }
+    
+    pub fn to_edges_csv(&self) -> String {
+        let mut lines = vec!["infector_id,infectee_id,t_infection,branch_length".to_string()];
+        for (infector_id, infectee_id, branch_length) in self.edges() {
+            lines.push(format!(
+                "{},{},{},{}",
+                infector_id, infectee_id, self.nodes[&infectee_id].t_infected, branch_length
+            ));
+        }
+        lines.join("\n") + "\n"
+    }
+    
+    fn edges(&self) -> Vec<(usize, usize, f64)> {
+        self.nodes.values().filter_map(|node| {
+            node.infector_id.and_then(|i| self.nodes.get(&i)).map(|infector| {
+                (infector.id, node.id, node.t_infected - infector.t_infected)
+            })
+        }).collect()
+    }
+    
+    /// Writes the tree in each requested format to files named from `output_prefix`:
+    /// `.nwk` (Newick), `.graphml`, and `_nodes.csv`/`_edges.csv` (CSV).
+    pub fn write_files(&self, output_prefix: &str, formats: &[TreeFormat]) -> Result<(), Error> {
+        for format in formats {
+            match format {
+                TreeFormat::Newick => {
+                    write_file(&format!("{}.nwk", output_prefix), &self.to_newick())?;
+                },
+                TreeFormat::GraphMl => {
+                    write_file(&format!("{}.graphml", output_prefix), &self.to_graphml())?;
+                },
+                TreeFormat::Csv => {
+                    write_file(&format!("{}_nodes.csv", output_prefix), &self.to_nodes_csv())?;
+                    write_file(&format!("{}_edges.csv", output_prefix), &self.to_edges_csv())?;
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TreeFormat {
+    Newick,
+    GraphMl,
+    Csv,
+}
+
+impl TreeFormat {
+    pub fn all() -> Vec<Self> {
+        vec![Self::Newick, Self::GraphMl, Self::Csv]
+    }
+    
+    pub fn parse(name: &str) -> Result<Self, Error> {
+        match name {
+            "newick" => Ok(Self::Newick),
+            "graphml" => Ok(Self::GraphMl),
+            "csv" => Ok(Self::Csv),
+            _ => Err(Error::InvalidArgument(format!("unknown tree format: {}", name))),
+        }
+    }
+}
+
+/// Susceptible and final states, according to the config recorded in `Meta` (see
+/// `db::write_provenance`); empty if there is none, as individuals are then only
+/// recorded once infected.
+fn uninfected_states(conn: &rusqlite::Connection) -> Result<BTreeSet<String>, Error> {
+    let db_err = |e: rusqlite::Error| Error::InvalidDatabase(format!("{}", e));
+    let mut stmt = conn.prepare("SELECT value FROM Meta WHERE key = 'config';").map_err(db_err)?;
+    let mut rows = stmt.query_map(rusqlite::params![], |row| row.get::<_, String>(0)).map_err(db_err)?;
+    let config: serde_json::Value = match rows.next() {
+        Some(config) => serde_json::from_str(&config.map_err(db_err)?)?,
+        None => return Ok(BTreeSet::new()),
+    };
+    
+    let mut states: BTreeSet<String> = config["final_states"].as_array().into_iter().flatten().filter_map(
+        |name| name.as_str().map(String::from)
+    ).collect();
+    if let Some(name) = config["susceptible_state"].as_str() {
+        states.insert(name.into());
+    }
+    Ok(states)
+}
+
+/// Formats a state history as `state@time` entries separated by semicolons.
+fn format_state_history(node: &TreeNode) -> String {
+    node.state_history.iter().map(|(t, state)| {
+        format!("{}@{}", state, t)
+    }).collect::<Vec<_>>().join(";")
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn write_file(path: &str, data: &str) -> Result<(), Error> {
+    let file = File::create(path).map_err(|_| Error::InvalidOutputPath(path.into()))?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(data.as_bytes()).map_err(|_| Error::OutputWriteFailure)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::*;
+    use crate::db::*;
+    use crate::tree::*;
+    use crate::fixtures::*;
+    
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    
+    fn node(id: usize, t_infected: f64, infector_id: Option<usize>) -> TreeNode {
+        TreeNode {
+            id, ageclass: 0, t_infected, infector_id,
+            state_history: vec![(t_infected, "E".into())],
+            sampled: true,
+        }
+    }
+    
+    #[test]
+    fn test_newick() {
+        let nodes = vec![
+            node(1, 0.0, None), node(2, 1.5, Some(1)), node(3, 2.0, Some(1)),
+            node(4, 4.0, Some(2)), node(5, 0.0, None),
+        ].into_iter().map(|n| (n.id, n)).collect();
+        let children = vec![(1, vec![2, 3]), (2, vec![4])].into_iter().collect();
+        let tree = TransmissionTree { nodes, children };
+        
+        assert_eq!(tree.to_newick(), "((4:2.5)2:1.5,3:2)1;\n5;\n");
+    }
+    
+    #[test]
+    fn test_full_agent_tree() {
+        let config = read_population_config();
+        
+        let recorder = Rc::new(RefCell::new(SqliteRecorder::new(
+            rusqlite::Connection::open_in_memory().unwrap(), true, None
+        )));
+        let mut sim = SimulationBuilder::new(config.clone()).observer(recorder.clone()).build().unwrap();
+        write_provenance(recorder.borrow().connection(), &config);
+        recorder.borrow_mut().start(&sim);
+        while !sim.simulate(sim.t + 1.0) {}
+        recorder.borrow_mut().finish(&sim);
+        assert!(sim.n_infections() > 0);
+        
+        // Only the initial infected and those infected are nodes, with their infection times
+        let recorder = recorder.borrow();
+        let tree = TransmissionTree::read_from_db(recorder.connection()).unwrap();
+        assert_eq!(tree.nodes().count(), 1 + sim.n_infections());
+        assert_eq!(tree.roots(), vec![3]);
+        for node in tree.nodes().filter(|node| node.infector_id.is_some()) {
+            assert!(node.t_infected > 0.0);
+            assert_eq!(node.state_history[0], (node.t_infected, "E".to_string()));
+        }
+        assert_eq!(tree.to_newick().lines().count(), 1);
+    }
+}
@@ -4,6 +4,7 @@ use std::io::{Read, Write};
 use std::path::Path;
 use rusqlite::types::ValueRef;
 use std::iter::FromIterator;
+use serde::de::DeserializeOwned;
 
 pub fn write_data_to_file(path_str: &str, data: &str) {
     let mut file = File::create(path_str).unwrap();
@@ -32,13 +33,184 @@ pub fn read_data_from_file(path_str: &str) -> Result<String, Error> {
     Ok(data)
 }
 
+/// Serialization format of an input file.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum InputFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl InputFormat {
+    pub fn parse(name: &str) -> Result<Self, Error> {
+        match name.to_lowercase().as_str() {
+            "json" => Ok(InputFormat::Json),
+            "yaml" | "yml" => Ok(InputFormat::Yaml),
+            "toml" => Ok(InputFormat::Toml),
+            _ => Err(Error::InvalidArgument(format!("unknown input format: {}", name))),
+        }
+    }
+    
+    /// Detects the format from a file extension, defaulting to JSON.
+    pub fn from_path(path_str: &str) -> Self {
+        Path::new(path_str).extension().and_then(|ext| ext.to_str()).and_then(
+            |ext| Self::parse(ext).ok()
+        ).unwrap_or(InputFormat::Json)
+    }
+}
+
+/// Deserializes data in any supported input format.
+pub fn deserialize_data<T: DeserializeOwned>(data: &str, format: InputFormat) -> Result<T, Error> {
+    match format {
+        InputFormat::Json => Ok(serde_json::from_str(data)?),
+        InputFormat::Yaml => serde_yaml::from_str(data).map_err(
+            |e| Error::InvalidYaml(format!("{}", e))
+        ),
+        InputFormat::Toml => toml::from_str(data).map_err(
+            |e| Error::InvalidToml(format!("{}", e))
+        ),
+    }
+}
+
+/// Reads and deserializes input from a file, or from stdin if no path is given.
+///
+/// If `format` is not given, it is detected from the file extension; stdin defaults to JSON.
+pub fn read_input<T: DeserializeOwned>(
+    path_str: Option<&str>, format: Option<InputFormat>
+) -> Result<T, Error> {
+    let data = match path_str {
+        Some(path_str) => read_data_from_file(path_str)?,
+        None => read_data_from_stdin()?,
+    };
+    let format = format.unwrap_or_else(
+        || path_str.map(InputFormat::from_path).unwrap_or(InputFormat::Json)
+    );
+    deserialize_data(&data, format)
+}
+
+/// Reads the rows of a CSV file, skipping blank lines, with fields trimmed of
+/// whitespace and surrounding quotes.
+pub fn read_csv(path_str: &str) -> Result<Vec<Vec<String>>, Error> {
+    let data = read_data_from_file(path_str)?;
+    Ok(data.lines().filter(|line| !line.trim().is_empty()).map(|line| {
+        line.split(',').map(|field| field.trim().trim_matches('"').to_string()).collect()
+    }).collect())
+}
+
+/// Sets the value at a JSON path such as `$.contact_parameters[0].beta`
+/// (the leading `$.` is optional), creating the final object key if it is missing.
+pub fn set_json_path(
+    root: &mut serde_json::Value, path: &str, value: serde_json::Value
+) -> Result<(), Error> {
+    let invalid = |message: &str| Error::InvalidArgument(format!("{}: {}", message, path));
+    
+    let mut components = Vec::new();
+    let trimmed = path.trim_start_matches('$').trim_start_matches('.');
+    for part in trimmed.split('.') {
+        let (key, mut rest) = match part.find('[') {
+            Some(i) => (&part[..i], &part[i..]),
+            None => (part, ""),
+        };
+        if key.is_empty() && rest.is_empty() {
+            return Err(invalid("empty path component"));
+        }
+        if !key.is_empty() {
+            components.push(JsonPathComponent::Key(key));
+        }
+        while !rest.is_empty() {
+            let end = rest.find(']').ok_or_else(|| invalid("unclosed ["))?;
+            let index = rest[1..end].parse().map_err(|_| invalid("invalid index"))?;
+            components.push(JsonPathComponent::Index(index));
+            rest = &rest[(end + 1)..];
+            if !rest.is_empty() && !rest.starts_with('[') {
+                return Err(invalid("invalid path"));
+            }
+        }
+    }
+    
+    let (last, parents) = components.split_last().ok_or_else(|| invalid("empty path"))?;
+    let mut node = root;
+    for component in parents {
+        node = match component {
+            JsonPathComponent::Key(key) => node.get_mut(*key),
+            JsonPathComponent::Index(index) => node.get_mut(*index),
+        }.ok_or_else(|| invalid("no such field"))?;
+    }
+    match last {
+        JsonPathComponent::Key(key) => {
+            node.as_object_mut().ok_or_else(|| invalid("not an object"))?.insert(key.to_string(), value);
+        },
+        JsonPathComponent::Index(index) => {
+            *node.get_mut(*index).ok_or_else(|| invalid("index out of range"))? = value;
+        },
+    }
+    Ok(())
+}
+
+enum JsonPathComponent<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+/// Recursively sorts the keys of all objects in a JSON value.
+pub fn sort_json_keys(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            let mut entries: Vec<_> = std::mem::take(map).into_iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (key, mut value) in entries {
+                sort_json_keys(&mut value);
+                map.insert(key, value);
+            }
+        },
+        serde_json::Value::Array(values) => {
+            for value in values {
+                sort_json_keys(value);
+            }
+        },
+        _ => {},
+    }
+}
+
+/// 64-bit FNV-1a hash, which is stable across platforms and releases.
+pub fn fnv1a_64(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Formats a time as an ISO 8601 UTC timestamp, e.g. `2020-04-01T12:00:00.000Z`.
+pub fn format_utc_time(time: std::time::SystemTime) -> String {
+    let since_epoch = time.duration_since(std::time::UNIX_EPOCH).unwrap();
+    let seconds = since_epoch.as_secs();
+    let (days, seconds_of_day) = ((seconds / 86400) as i64, seconds % 86400);
+    
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day,
+        seconds_of_day / 3600, seconds_of_day / 60 % 60, seconds_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
 pub fn db_table_to_json_object(
     conn: &rusqlite::Connection, table_name: &str,
     column_names: &Vec<&str>
 ) -> serde_json::Value {
     let col_values_pairs: Vec<(String, serde_json::Value)> = column_names.iter().map(|c| {
         let values: Vec<serde_json::Value> = conn.prepare(
-            &format!("SELECT {} FROM {};", c, table_name)
+            &format!("SELECT \"{}\" FROM {};", c, table_name)
         ).unwrap().query_map(rusqlite::params![], |row| {
             Ok(
                 match row.get_raw(0) {
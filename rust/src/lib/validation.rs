@@ -0,0 +1,639 @@
+use crate::config::*;
+use crate::contacts::*;
+use crate::strata::*;
+use crate::errors::*;
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+const PROBABILITY_TOLERANCE: f64 = 1e-8;
+
+/// Collects every problem with a config, rather than stopping at the first.
+///
+/// Each problem is reported with the JSON path of the offending field.
+pub fn validate_config(config: &Config) -> Vec<ConfigError> {
+    let mut v = Validator { errors: Vec::new() };
+    let n_ageclasses = config.n_ageclasses;
+    
+    if n_ageclasses == 0 {
+        v.push("$.n_ageclasses", ConfigProblem::InvalidValue {
+            message: "must be at least 1".into()
+        });
+    }
+    
+    // Ageclass labels
+    if let Some(labels) = &config.ageclass_labels {
+        if labels.len() != n_ageclasses {
+            v.push("$.ageclass_labels", ConfigProblem::DimensionMismatch {
+                expected: n_ageclasses, found: labels.len()
+            });
+        }
+        let mut seen = HashSet::new();
+        for (i, label) in labels.iter().enumerate() {
+            if !seen.insert(label) {
+                v.push(&format!("$.ageclass_labels[{}]", i), ConfigProblem::InvalidValue {
+                    message: format!("duplicate label: {}", label)
+                });
+            }
+        }
+    }
+    if let Some(lower_bounds) = &config.ageclass_lower_bounds {
+        if lower_bounds.len() != n_ageclasses {
+            v.push("$.ageclass_lower_bounds", ConfigProblem::DimensionMismatch {
+                expected: n_ageclasses, found: lower_bounds.len()
+            });
+        }
+        for (i, lower) in lower_bounds.iter().enumerate() {
+            let path = format!("$.ageclass_lower_bounds[{}]", i);
+            if !lower.is_finite() || *lower < 0.0 {
+                v.push(&path, ConfigProblem::InvalidValue {
+                    message: "must be finite and nonnegative".into()
+                });
+            }
+            else if i > 0 && *lower <= lower_bounds[i - 1] {
+                v.push(&path, ConfigProblem::InvalidValue {
+                    message: "must be greater than the previous lower bound".into()
+                });
+            }
+        }
+    }
+    
+    // Strata
+    if let Some(strata) = &config.strata {
+        if strata.is_empty() {
+            v.push("$.strata", ConfigProblem::MissingValue {
+                message: "at least one dimension is required if strata is given".into()
+            });
+        }
+        let mut names = HashSet::new();
+        for (i, dimension) in strata.iter().enumerate() {
+            let path = format!("$.strata[{}]", i);
+            let name = dimension.name.as_str();
+            if !is_valid_column_name(name) || is_reserved_column_name(name) {
+                v.push(&format!("{}.name", path), ConfigProblem::InvalidValue {
+                    message: format!("not a valid column name: {}", name)
+                });
+            }
+            else if !names.insert(name.to_ascii_lowercase()) {
+                v.push(&format!("{}.name", path), ConfigProblem::InvalidValue {
+                    message: format!("duplicate dimension name: {}", name)
+                });
+            }
+            if dimension.labels.is_empty() {
+                v.push(&format!("{}.labels", path), ConfigProblem::MissingValue {
+                    message: "at least one label is required".into()
+                });
+            }
+            let mut labels = HashSet::new();
+            for (j, label) in dimension.labels.iter().enumerate() {
+                if !labels.insert(label) {
+                    v.push(&format!("{}.labels[{}]", path, j), ConfigProblem::InvalidValue {
+                        message: format!("duplicate label: {}", label)
+                    });
+                }
+            }
+        }
+        if n_strata(strata) != n_ageclasses {
+            v.push("$.n_ageclasses", ConfigProblem::DimensionMismatch {
+                expected: n_strata(strata), found: n_ageclasses
+            });
+        }
+    }
+    
+    // State names
+    let mut declared: Vec<(String, &str, StateKind)> = vec![
+        ("$.susceptible_state".into(), &config.susceptible_state, StateKind::Susceptible)
+    ];
+    for (i, name) in config.final_states.iter().enumerate() {
+        declared.push((format!("$.final_states[{}]", i), name, StateKind::Final));
+    }
+    for (i, state_config) in config.infected_states.iter().enumerate() {
+        declared.push((format!("$.infected_states[{}].name", i), &state_config.name, StateKind::Infected));
+    }
+    let mut kinds: HashMap<&str, StateKind> = HashMap::new();
+    for (path, name, kind) in declared {
+        if kinds.insert(name, kind).is_some() {
+            v.push(&path, ConfigProblem::DuplicateState { name: name.into() });
+        }
+    }
+    
+    match kinds.get(config.initial_infected_state.as_str()) {
+        Some(StateKind::Infected) => {},
+        Some(_) => v.push("$.initial_infected_state", ConfigProblem::InvalidValue {
+            message: format!("{} is not an infected state", config.initial_infected_state)
+        }),
+        None => v.push("$.initial_infected_state", ConfigProblem::UnknownState {
+            name: config.initial_infected_state.clone()
+        }),
+    }
+    
+    if let Some(onset_state) = &config.onset_state {
+        match kinds.get(onset_state.as_str()) {
+            Some(StateKind::Susceptible) => v.push("$.onset_state", ConfigProblem::InvalidValue {
+                message: "onset state cannot be the susceptible state".into()
+            }),
+            Some(_) => {},
+            None => v.push("$.onset_state", ConfigProblem::UnknownState { name: onset_state.clone() }),
+        }
+    }
+    
+    if let Some(width) = config.interval_bin_width {
+        if !(width > 0.0 && width.is_finite()) {
+            v.push("$.interval_bin_width", ConfigProblem::InvalidValue {
+                message: format!("must be positive, not {}", width)
+            });
+        }
+    }
+    
+    // Infected states
+    for (i, state_config) in config.infected_states.iter().enumerate() {
+        let path = format!("$.infected_states[{}]", i);
+        
+        if !(state_config.mean_duration > 0.0 && state_config.mean_duration.is_finite()) {
+            v.push(&format!("{}.mean_duration", path), ConfigProblem::InvalidValue {
+                message: format!("must be positive, not {}", state_config.mean_duration)
+            });
+        }
+        if !(state_config.gamma_shape > 0.0 && state_config.gamma_shape.is_finite()) {
+            v.push(&format!("{}.gamma_shape", path), ConfigProblem::InvalidValue {
+                message: format!("must be positive, not {}", state_config.gamma_shape)
+            });
+        }
+        
+        if state_config.next_states.is_empty() {
+            v.push(&format!("{}.next_states", path), ConfigProblem::InvalidValue {
+                message: "infected states must have at least one next state".into()
+            });
+        }
+        for (j, name) in state_config.next_states.iter().enumerate() {
+            let next_path = format!("{}.next_states[{}]", path, j);
+            match kinds.get(name.as_str()) {
+                Some(StateKind::Susceptible) => v.push(&next_path, ConfigProblem::InvalidValue {
+                    message: "cannot transition back to the susceptible state".into()
+                }),
+                Some(_) => {},
+                None => v.push(&next_path, ConfigProblem::UnknownState { name: name.clone() }),
+            }
+        }
+        
+        let n_next = state_config.next_states.len();
+        match &state_config.probabilities {
+            Some(probabilities) => {
+                v.check_probabilities(&format!("{}.probabilities", path), probabilities, n_ageclasses, n_next);
+            },
+            None => {
+                if n_next > 1 {
+                    v.push(&format!("{}.probabilities", path), ConfigProblem::MissingValue {
+                        message: "required for states with more than one next state".into()
+                    });
+                }
+            },
+        }
+        
+        let mut t_start_prev: Option<f64> = None;
+        for (j, period) in state_config.periods.iter().flatten().enumerate() {
+            let period_path = format!("{}.periods[{}]", path, j);
+            match (period.t_start, period.contact_period) {
+                (Some(_), Some(_)) | (None, None) => v.push(&period_path, ConfigProblem::InvalidValue {
+                    message: "exactly one of t_start and contact_period is required".into()
+                }),
+                (None, Some(i)) if i == 0 || i >= config.contact_parameters.len() => {
+                    v.push(&format!("{}.contact_period", period_path), ConfigProblem::InvalidValue {
+                        message: format!("must index a set of contact parameters after the first, not {}", i)
+                    });
+                },
+                _ => {},
+            }
+            if let Some(t_start) = period.start_time(&config.contact_parameters) {
+                let t_path = match period.t_start {
+                    Some(_) => format!("{}.t_start", period_path),
+                    None => format!("{}.contact_period", period_path),
+                };
+                if !t_start.is_finite() {
+                    v.push(&t_path, ConfigProblem::InvalidValue {
+                        message: format!("must be finite, not {}", t_start)
+                    });
+                }
+                else if let Some(t_previous) = t_start_prev.filter(|t_previous| t_start <= *t_previous) {
+                    v.push(&t_path, ConfigProblem::NonIncreasingTime { t: t_start, t_previous });
+                }
+                t_start_prev = Some(t_start);
+            }
+            
+            for (key, value) in &[("mean_duration", period.mean_duration), ("gamma_shape", period.gamma_shape)] {
+                if let Some(x) = value.filter(|x| !(*x > 0.0 && x.is_finite())) {
+                    v.push(&format!("{}.{}", period_path, key), ConfigProblem::InvalidValue {
+                        message: format!("must be positive, not {}", x)
+                    });
+                }
+            }
+            if let Some(probabilities) = &period.probabilities {
+                v.check_probabilities(
+                    &format!("{}.probabilities", period_path), probabilities, n_ageclasses, n_next
+                );
+            }
+        }
+        
+        // Probabilities by attribute replace those of the state, so would override any by period
+        let periods = state_config.periods.iter().flatten();
+        if state_config.probabilities_by_attribute.is_some() && periods.clone().any(|p| p.probabilities.is_some()) {
+            v.push(&path, ConfigProblem::InvalidValue {
+                message: "probabilities cannot be given both by attribute and by period".into()
+            });
+        }
+    }
+    
+    // Reachability of infected and final states
+    let mut reachable = BTreeSet::new();
+    let mut stack: Vec<&str> = vec![&config.initial_infected_state];
+    for (name, counts) in &config.initial_counts {
+        if counts.iter().any(|c| *c > 0) {
+            stack.push(name);
+        }
+    }
+    let next_states: HashMap<&str, &Vec<String>> = config.infected_states.iter().map(
+        |s| (s.name.as_str(), &s.next_states)
+    ).collect();
+    while let Some(name) = stack.pop() {
+        if reachable.insert(name) {
+            if let Some(next) = next_states.get(name) {
+                stack.extend(next.iter().map(|s| s.as_str()));
+            }
+        }
+    }
+    for (i, name) in config.final_states.iter().enumerate() {
+        if !reachable.contains(name.as_str()) {
+            v.push(&format!("$.final_states[{}]", i), ConfigProblem::UnreachableState { name: name.clone() });
+        }
+    }
+    for (i, state_config) in config.infected_states.iter().enumerate() {
+        if !reachable.contains(state_config.name.as_str()) {
+            v.push(
+                &format!("$.infected_states[{}]", i),
+                ConfigProblem::UnreachableState { name: state_config.name.clone() }
+            );
+        }
+    }
+    
+    // Contact settings and parameters
+    let n_errors_before_contacts = v.errors.len();
+    let mut setting_names = HashSet::new();
+    if let Some(settings) = &config.contact_settings {
+        if settings.is_empty() {
+            v.push("$.contact_settings", ConfigProblem::MissingValue {
+                message: "at least one setting is required if contact_settings is given".into()
+            });
+        }
+        for (i, setting) in settings.iter().enumerate() {
+            let path = format!("$.contact_settings[{}]", i);
+            if !setting_names.insert(setting.name.as_str()) {
+                v.push(&format!("{}.name", path), ConfigProblem::InvalidValue {
+                    message: format!("duplicate setting name: {}", setting.name)
+                });
+            }
+            v.check_contact_matrix(&path, &setting.C, &setting.C_factors, config, "C or C_factors is required");
+        }
+    }
+    
+    if config.contact_parameters.is_empty() {
+        v.push("$.contact_parameters", ConfigProblem::MissingValue {
+            message: "at least one set of contact parameters is required".into()
+        });
+    }
+    let mut t_end_prev: Option<f64> = None;
+    for (i, cp) in config.contact_parameters.iter().enumerate() {
+        let path = format!("$.contact_parameters[{}]", i);
+        if !(cp.beta >= 0.0 && cp.beta.is_finite()) {
+            v.push(&format!("{}.beta", path), ConfigProblem::InvalidValue {
+                message: format!("must be nonnegative, not {}", cp.beta)
+            });
+        }
+        if config.contact_settings.is_none() {
+            v.check_contact_matrix(
+                &path, &cp.C, &cp.C_factors, config, "C or C_factors is required unless contact_settings are given"
+            );
+        }
+        else {
+            for (field, given) in &[("C", cp.C.is_some()), ("C_factors", cp.C_factors.is_some())] {
+                if *given {
+                    v.push(&format!("{}.{}", path, field), ConfigProblem::InvalidValue {
+                        message: "cannot be given with contact_settings; use setting_multipliers".into()
+                    });
+                }
+            }
+        }
+        if let Some(multipliers) = &cp.setting_multipliers {
+            let multipliers_path = format!("{}.setting_multipliers", path);
+            if config.contact_settings.is_none() {
+                v.push(&multipliers_path, ConfigProblem::InvalidValue {
+                    message: "requires contact_settings".into()
+                });
+            }
+            let mut names: Vec<_> = multipliers.keys().collect();
+            names.sort();
+            for name in names {
+                let path = format!("{}.{}", multipliers_path, name);
+                if config.contact_settings.is_some() && !setting_names.contains(name.as_str()) {
+                    v.push(&path, ConfigProblem::InvalidValue { message: format!("unknown setting: {}", name) });
+                }
+                let values = match &multipliers[name] {
+                    SettingMultiplier::Uniform(multiplier) => vec![*multiplier],
+                    SettingMultiplier::ByAgeclass(multipliers) => {
+                        if multipliers.len() != n_ageclasses {
+                            v.push(&path, ConfigProblem::DimensionMismatch {
+                                expected: n_ageclasses, found: multipliers.len()
+                            });
+                        }
+                        multipliers.clone()
+                    },
+                };
+                if let Some(x) = values.iter().find(|x| !(**x >= 0.0 && x.is_finite())) {
+                    v.push(&path, ConfigProblem::InvalidValue {
+                        message: format!("must be nonnegative, not {}", x)
+                    });
+                }
+            }
+        }
+        
+        let is_last = i == config.contact_parameters.len() - 1;
+        match (cp.t_end, is_last) {
+            (Some(_), true) => v.push(&format!("{}.t_end", path), ConfigProblem::InvalidValue {
+                message: "the last set of contact parameters must not have t_end".into()
+            }),
+            (None, false) => v.push(&format!("{}.t_end", path), ConfigProblem::MissingValue {
+                message: "required for all but the last set of contact parameters".into()
+            }),
+            (Some(t_end), false) => {
+                if let Some(t_end_prev) = t_end_prev {
+                    if t_end.is_nan() || t_end <= t_end_prev {
+                        v.push(&format!("{}.t_end", path), ConfigProblem::NonIncreasingTime {
+                            t: t_end, t_previous: t_end_prev,
+                        });
+                    }
+                }
+                t_end_prev = Some(t_end);
+            },
+            (None, true) => {},
+        }
+    }
+    
+    // Initial counts
+    let mut n_by_ageclass = vec![0; n_ageclasses];
+    for (name, counts) in &config.initial_counts {
+        let path = format!("$.initial_counts.{}", name);
+        if !kinds.contains_key(name.as_str()) {
+            v.push(&path, ConfigProblem::UnknownState { name: name.clone() });
+        }
+        if counts.len() != n_ageclasses {
+            v.push(&path, ConfigProblem::DimensionMismatch {
+                expected: n_ageclasses, found: counts.len()
+            });
+        }
+        for (a, count) in counts.iter().enumerate().take(n_ageclasses) {
+            n_by_ageclass[a] += count;
+        }
+    }
+    for (a, n) in n_by_ageclass.iter().enumerate() {
+        if *n == 0 {
+            v.push("$.initial_counts", ConfigProblem::InvalidValue {
+                message: format!("ageclass {} has no individuals", a + 1)
+            });
+        }
+    }
+    
+    // Reciprocity of contact matrices, once their dimensions and the totals are known to be valid
+    if config.contact_reciprocity == Some(ContactReciprocity::Check) && v.errors.len() == n_errors_before_contacts {
+        let totals = ageclass_totals(config);
+        for (i, matrix) in config.configured_contact_matrices().iter().enumerate() {
+            for violation in reciprocity_violations(matrix, &totals) {
+                let (a, b) = violation.ageclasses;
+                let path = match config.contact_settings {
+                    Some(_) => format!("$.contact_parameters[{}]", i),
+                    None => format!("$.contact_parameters[{}].C[{}][{}]", i, a - 1, b - 1),
+                };
+                v.push(&path, ConfigProblem::NonReciprocalContacts {
+                    ageclasses: violation.ageclasses,
+                    relative_difference: violation.relative_difference,
+                });
+            }
+        }
+    }
+    
+    // Population and attribute-dependent parameters
+    let population = config.population_data.as_deref();
+    // Checks that the attribute at `{path}.attribute` exists, and that the values keying
+    // `{path}.{key}` occur in the population
+    let check_attribute = |v: &mut Validator, path: &str, name: &str, key: &str, values: &mut dyn Iterator<Item = &String>| {
+        if let Some(population) = population {
+            match population.attribute(name) {
+                Some(attribute) => for value in values {
+                    if attribute.value_index(value).is_none() {
+                        v.push(&format!("{}.{}.{}", path, key, value), ConfigProblem::InvalidValue {
+                            message: format!("no one in the population has {} {}", name, value)
+                        });
+                    }
+                },
+                None => v.push(&format!("{}.attribute", path), ConfigProblem::InvalidValue {
+                    message: format!("not a column of the population file: {}", name)
+                }),
+            }
+        }
+    };
+    if let Some(population_config) = &config.population {
+        if population.is_none() {
+            v.push("$.population.path", ConfigProblem::MissingValue {
+                message: "population file not read; configs with a population must be read with Config::read or Config::from_json".into()
+            });
+        }
+        for (i, multipliers) in population_config.susceptibility.iter().flatten().enumerate() {
+            let path = format!("$.population.susceptibility[{}]", i);
+            for (value, x) in &multipliers.multipliers {
+                if !(*x >= 0.0 && x.is_finite()) {
+                    v.push(&format!("{}.multipliers.{}", path, value), ConfigProblem::InvalidValue {
+                        message: format!("must be nonnegative, not {}", x)
+                    });
+                }
+            }
+            check_attribute(&mut v, &path, &multipliers.attribute, "multipliers", &mut multipliers.multipliers.keys());
+        }
+        for (i, group_contacts) in population_config.group_contacts.iter().flatten().enumerate() {
+            let path = format!("$.population.group_contacts[{}]", i);
+            if !(group_contacts.beta >= 0.0 && group_contacts.beta.is_finite()) {
+                v.push(&format!("{}.beta", path), ConfigProblem::InvalidValue {
+                    message: format!("must be nonnegative, not {}", group_contacts.beta)
+                });
+            }
+            check_attribute(&mut v, &path, &group_contacts.attribute, "", &mut std::iter::empty());
+        }
+        for (i, name) in population_config.output_attributes.iter().flatten().enumerate() {
+            if population.is_some_and(|population| population.attribute(name).is_none()) {
+                v.push(&format!("$.population.output_attributes[{}]", i), ConfigProblem::InvalidValue {
+                    message: format!("not a column of the population file: {}", name)
+                });
+            }
+        }
+    }
+    for (i, state_config) in config.infected_states.iter().enumerate() {
+        if let Some(by_attribute) = &state_config.probabilities_by_attribute {
+            let path = format!("$.infected_states[{}].probabilities_by_attribute", i);
+            if config.population.is_none() {
+                v.push(&path, ConfigProblem::InvalidValue { message: "requires population".into() });
+            }
+            for (value, probabilities) in &by_attribute.probabilities {
+                v.check_probabilities(
+                    &format!("{}.probabilities.{}", path, value), probabilities,
+                    n_ageclasses, state_config.next_states.len()
+                );
+            }
+            check_attribute(&mut v, &path, &by_attribute.attribute, "probabilities", &mut by_attribute.probabilities.keys());
+        }
+    }
+    
+    // Establishment
+    if let Some(establishment) = &config.establishment {
+        if establishment.max_attempts == Some(0) {
+            v.push("$.establishment.max_attempts", ConfigProblem::InvalidValue {
+                message: "must be at least 1".into()
+            });
+        }
+        if !establishment.by_time.is_finite() {
+            v.push("$.establishment.by_time", ConfigProblem::InvalidValue {
+                message: "must be finite".into()
+            });
+        }
+    }
+    
+    v.errors
+}
+
+impl Config {
+    /// Validates the config, returning all problems found as `Error::InvalidConfig`.
+    pub fn validate(&self) -> Result<(), Error> {
+        let errors = validate_config(self);
+        if errors.is_empty() {
+            Ok(())
+        }
+        else {
+            Err(Error::InvalidConfig(errors))
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum StateKind {
+    Susceptible,
+    Final,
+    Infected,
+}
+
+struct Validator {
+    errors: Vec<ConfigError>,
+}
+
+impl Validator {
+    fn push(&mut self, path: &str, problem: ConfigProblem) {
+        self.errors.push(ConfigError { path: path.into(), problem });
+    }
+    
+    /// Checks a contact matrix at `path`, given as either `C` or `C_factors`
+    /// (one matrix per stratum dimension).
+    fn check_contact_matrix(
+        &mut self, path: &str, matrix: &Option<Vec<Vec<f64>>>, factors: &Option<Vec<Vec<Vec<f64>>>>,
+        config: &Config, missing_message: &str
+    ) {
+        let factors_path = format!("{}.C_factors", path);
+        match (matrix, factors) {
+            (Some(matrix), None) => {
+                self.check_matrix(&format!("{}.C", path), matrix, config.n_ageclasses, config.n_ageclasses);
+            },
+            (None, Some(factors)) => match &config.strata {
+                Some(strata) => {
+                    if factors.len() != strata.len() {
+                        self.push(&factors_path, ConfigProblem::DimensionMismatch {
+                            expected: strata.len(), found: factors.len()
+                        });
+                    }
+                    for (k, (factor, dimension)) in factors.iter().zip(strata).enumerate() {
+                        let n = dimension.labels.len();
+                        self.check_matrix(&format!("{}[{}]", factors_path, k), factor, n, n);
+                    }
+                },
+                None => self.push(&factors_path, ConfigProblem::InvalidValue {
+                    message: "requires strata".into()
+                }),
+            },
+            (Some(_), Some(_)) => self.push(&factors_path, ConfigProblem::InvalidValue {
+                message: "cannot be given with C".into()
+            }),
+            (None, None) => self.push(&format!("{}.C", path), ConfigProblem::MissingValue {
+                message: missing_message.into()
+            }),
+        }
+    }
+    
+    /// Checks a matrix of transition probabilities, with a row per ageclass that sums to 1.
+    fn check_probabilities(&mut self, path: &str, probabilities: &[Vec<f64>], n_ageclasses: usize, n_next: usize) {
+        self.check_matrix(path, probabilities, n_ageclasses, n_next);
+        for (a, row) in probabilities.iter().enumerate() {
+            let sum: f64 = row.iter().sum();
+            if row.len() == n_next && (sum - 1.0).abs() > PROBABILITY_TOLERANCE {
+                self.push(&format!("{}[{}]", path, a), ConfigProblem::ProbabilitiesDoNotSumToOne { sum });
+            }
+        }
+    }
+    
+    /// Checks dimensions of a matrix, and that its entries are nonnegative and finite.
+    fn check_matrix(&mut self, path: &str, m: &[Vec<f64>], n_rows: usize, n_cols: usize) {
+        if m.len() != n_rows {
+            self.push(path, ConfigProblem::DimensionMismatch { expected: n_rows, found: m.len() });
+        }
+        let mut reported = HashSet::new();
+        for (i, row) in m.iter().enumerate() {
+            let row_path = format!("{}[{}]", path, i);
+            if row.len() != n_cols {
+                self.push(&row_path, ConfigProblem::DimensionMismatch { expected: n_cols, found: row.len() });
+            }
+            for (j, x) in row.iter().enumerate() {
+                if !(*x >= 0.0 && x.is_finite()) && reported.insert((i, j)) {
+                    self.push(&format!("{}[{}]", row_path, j), ConfigProblem::InvalidValue {
+                        message: format!("must be nonnegative, not {}", x)
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::validation::*;
+    use crate::fixtures::*;
+    
+    #[test]
+    fn test_valid_config() {
+        assert!(validate_config(&read_test_config()).is_empty());
+    }
+    
+    #[test]
+    fn test_reports_all_problems() {
+        let mut config = read_test_config();
+        config.infected_states[0].next_states[0] = "Q".into();
+        config.infected_states[1].mean_duration = -1.0;
+        config.infected_states[1].probabilities = Some(vec![vec![0.5, 0.4], vec![0.9, 0.1]]);
+        config.contact_parameters[0].C.as_mut().unwrap().pop();
+        config.contact_parameters[0].t_end = None;
+        config.initial_counts.insert("X".into(), vec![1, 2]);
+        
+        let paths: Vec<_> = validate_config(&config).into_iter().map(|e| e.path).collect();
+        for path in &[
+            "$.infected_states[0].next_states[0]",
+            "$.infected_states[1].mean_duration",
+            "$.infected_states[1].probabilities[0]",
+            "$.infected_states[1]",
+            "$.contact_parameters[0].C",
+            "$.contact_parameters[0].t_end",
+            "$.initial_counts.X",
+        ] {
+            assert!(paths.contains(&path.to_string()), "missing {}", path);
+        }
+    }
+}
@@ -1,130 +1,316 @@
 #![allow(non_snake_case)]
 
-use sirtools::ibm::*;
 use std::time::Instant;
 
-use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::Read;
+use std::cell::RefCell;
+use std::rc::Rc;
+
 use std::path::{PathBuf, Path};
-use std::collections::HashMap;
 use std::f64::INFINITY;
+use std::str::FromStr;
 
+use sirtools::abc::*;
+use sirtools::branching::*;
+use sirtools::config::*;
+use sirtools::db;
+use sirtools::ibm::to_i64;
+use sirtools::observer::SimulationObserver;
 use sirtools::util::*;
 use sirtools::errors::*;
+use sirtools::particle_filter::*;
+use sirtools::summary::*;
+use sirtools::sweep::*;
+use sirtools::tree::*;
 use std::iter::FromIterator;
 
-#[derive(Serialize, Deserialize)]
-struct Config {
-    rng_seed: Option<u32>,
-    output_path: Option<String>,
-    write_to_stdout: Option<bool>,
+use indoc::indoc;
+
+const USAGE: &str = indoc!("
+    usage: sirsim [run] [<config_path>] [<config options>] [--stdout]
+           sirsim validate [<config_path>] [<config options>]
+           sirsim ensemble [<config_path>] --replicates <n> [<config options>]
+           sirsim sweep [<config_path>] --spec <sweep_path> [--replicates <n>] [--threads <n>]
+               [<config options>]
+           sirsim abc [<config_path>] --spec <abc_path> [--threads <n>] [<config options>]
+           sirsim particle-filter [<config_path>] --spec <filter_path> [<config options>]
+           sirsim summarize <db_path>
+           sirsim export <db_path> <output_prefix> [--table <name>]...
+           sirsim export-tree <db_path> <output_prefix>
+               [--format newick|graphml|csv]... [--sample-fraction <fraction>] [--seed <seed>]
+           sirsim branching-process [<config_path>] [--contact-period <index>] [<config options>]
+           sirsim contact-matrices [<config_path>] [--target-r0 <R0>] [<config options>]
+           sirsim schema [config|sweep|abc|particle-filter]
+    
+    Configs are read from <config_path>, or from stdin if omitted. Contact matrices (C),
+    probabilities, and initial_counts may be given as paths of CSV files, relative to
+    the config file.
+    
+    config options:
+        --format json|yaml|toml    input format (default: from extension, or JSON)
+        --seed <seed>              override rng_seed
+        --output <db_path>         override output_path (relative to the current directory)
+        --t-final <t>              override t_final
+        --set <path>=<value>       override any field by JSON path, e.g.
+                                   --set 'contact_parameters[0].beta=0.25'
+    
+    Errors are printed to stdout as JSON. Exit codes:
+        0  success
+        1  simulation failed (e.g. no seed established an outbreak)
+        2  invalid command-line arguments
+        3  invalid input (config, input file, or DB)
+        4  output could not be written (e.g. output path already exists)
+");
+
+/// Options accepted by every command that reads a config.
+const CONFIG_OPTIONS: &[&str] = &["--format", "--seed", "--output", "--t-final", "--set"];
+
+fn main() {
+    // Errors are printed to stdout as JSON, in the same form as sirstan output, for the R wrapper
+    if let Err(error) = run() {
+        println!("{}", serde_json::to_string_pretty(&Err::<(), _>(&error)).unwrap());
+        std::process::exit(error.code());
+    }
+}
+
+fn run() -> Result<(), Error> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = args.first().map(|s| s.as_str()).unwrap_or("run");
+    let rest = if args.is_empty() { &args[..] } else { &args[1..] };
+    match command {
+        "run" => run_simulation(rest),
+        "validate" => validate(rest),
+        "ensemble" => ensemble(rest),
+        "sweep" => sweep(rest),
+        "abc" => abc(rest),
+        "particle-filter" => particle_filter(rest),
+        "summarize" => summarize(rest),
+        "export" => export(rest),
+        "export-tree" => export_tree(rest),
+        "branching-process" => branching_process(rest),
+        "contact-matrices" => contact_matrices(rest),
+        "schema" => {
+            let schema = match rest.first().map(|s| s.as_str()) {
+                None | Some("config") => Config::json_schema(),
+                Some("sweep") => schemars::schema_for!(SweepSpec),
+                Some("abc") => schemars::schema_for!(AbcSpec),
+                Some("particle-filter") => schemars::schema_for!(ParticleFilterSpec),
+                Some(other) => return Err(Error::InvalidArgument(format!("unknown schema: {}", other))),
+            };
+            println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+            Ok(())
+        },
+        "help" | "--help" | "-h" => {
+            print!("{}", USAGE);
+            Ok(())
+        },
+        // No command: the config path (or a config option) comes first
+        _ if command.starts_with("--") || Path::new(command).is_file() => run_simulation(&args),
+        _ => Err(Error::InvalidArgument(format!("unknown command (or nonexistent config path): {}\n\n{}", command, USAGE))),
+    }
+}
+
+/// Command-line arguments following the command: positional arguments, options
+/// with values (which may be repeated), and flags without values.
+struct Args {
+    positional: Vec<String>,
+    options: Vec<(String, String)>,
+    flags: Vec<String>,
+}
+
+impl Args {
+    fn parse(args: &[String], option_names: &[&str], flag_names: &[&str]) -> Result<Self, Error> {
+        let mut parsed = Args { positional: Vec::new(), options: Vec::new(), flags: Vec::new() };
+        let mut i = 0;
+        while i < args.len() {
+            let arg = &args[i];
+            if flag_names.contains(&arg.as_str()) {
+                parsed.flags.push(arg.clone());
+                i += 1;
+            }
+            else if option_names.contains(&arg.as_str()) {
+                let value = args.get(i + 1).ok_or_else(
+                    || Error::InvalidArgument(format!("missing value for {}", arg))
+                )?;
+                parsed.options.push((arg.clone(), value.clone()));
+                i += 2;
+            }
+            else if arg.starts_with("--") {
+                return Err(Error::InvalidArgument(format!("unknown option: {}", arg)));
+            }
+            else {
+                parsed.positional.push(arg.clone());
+                i += 1;
+            }
+        }
+        Ok(parsed)
+    }
     
-    record_all_events: bool,
+    fn values(&self, name: &str) -> Vec<&str> {
+        self.options.iter().filter(|(n, _)| n == name).map(|(_, v)| v.as_str()).collect()
+    }
     
-    t_initial: Option<f64>,
-    t_final: Option<f64>,
+    /// The last value given for an option.
+    fn value(&self, name: &str) -> Option<&str> {
+        self.values(name).last().copied()
+    }
     
-    n_ageclasses: usize,
+    fn parsed_value<T: FromStr>(&self, name: &str) -> Result<Option<T>, Error> {
+        self.value(name).map(|value| value.parse().map_err(
+            |_| Error::InvalidArgument(format!("invalid value for {}: {}", name, value))
+        )).transpose()
+    }
     
-    susceptible_state: String,
-    initial_infected_state: String,
-    final_states: Vec<String>,
+    fn has_flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|f| f == name)
+    }
+    
+    /// Checks the number of positional arguments is in `min..=max`.
+    fn check_positional(&self, min: usize, max: usize) -> Result<(), Error> {
+        if self.positional.len() < min || self.positional.len() > max {
+            return Err(Error::InvalidArgument(format!(
+                "wrong number of arguments: {:?}\n\n{}", self.positional, USAGE
+            )));
+        }
+        Ok(())
+    }
     
-    infected_states: Vec<StateConfig>,
+    fn config_path(&self) -> Option<&str> {
+        self.positional.first().map(|s| s.as_str())
+    }
+}
+
+/// Reads the config at the first positional argument (or stdin) and applies overrides
+/// from command-line options.
+fn read_config(args: &Args) -> Result<Config, Error> {
+    let format = args.value("--format").map(InputFormat::parse).transpose()?;
+    let mut config = Config::read(args.config_path(), format)?;
     
-    contact_parameters: Vec<ContactParameters>,
+    for assignment in args.values("--set") {
+        let (path, value) = match assignment.find('=') {
+            Some(i) => (&assignment[..i], &assignment[(i + 1)..]),
+            None => return Err(Error::InvalidArgument(format!("expected <path>=<value>: {}", assignment))),
+        };
+        // Values that aren't valid JSON are taken as strings, so that state names need no quotes
+        let value = serde_json::from_str(value).unwrap_or_else(|_| value.into());
+        config.set(path, value)?;
+    }
+    if let Some(rng_seed) = args.parsed_value("--seed")? {
+        config.rng_seed = Some(rng_seed);
+    }
+    if let Some(t_final) = args.parsed_value("--t-final")? {
+        config.t_final = Some(t_final);
+    }
+    if let Some(output_path) = args.value("--output") {
+        // Resolve now, since output paths in configs are relative to the config file
+        let cwd = std::env::current_dir().map_err(|_| Error::InvalidOutputPath(output_path.into()))?;
+        config.output_path = Some(cwd.join(output_path).to_string_lossy().into());
+    }
     
-    initial_counts: HashMap<String, Vec<usize>>,
+    Ok(config)
 }
 
-#[derive(Serialize, Deserialize)]
-struct ContactParameters {
-    beta: f64,
-    C: Vec<Vec<f64>>,
-    t_end: Option<f64>,
+/// If we were given a config file, uses its parent as the working directory,
+/// so that output paths in the config are relative to it.
+fn set_config_working_directory(args: &Args) -> Result<(), Error> {
+    if let Some(config_path) = args.config_path() {
+        if let Some(parent) = Path::new(config_path).parent() {
+            if parent != Path::new("") {
+                std::env::set_current_dir(parent).map_err(
+                    |_| Error::InvalidInputPath(config_path.into())
+                )?;
+            }
+        }
+    }
+    Ok(())
 }
 
-#[derive(Serialize, Deserialize)]
-struct StateConfig {
-    name: String,
-    infectious: bool,
-    mean_duration: f64,
-    gamma_shape: f64,
-    next_states: Vec<String>,
-    probabilities: Option<Vec<Vec<f64>>>,
+/// Opens a new DB at `output_path`, failing if the file already exists.
+fn create_db(output_path: &str) -> Result<rusqlite::Connection, Error> {
+    let db_path: PathBuf = output_path.into();
+    if db_path.exists() {
+        return Err(Error::OutputExists(output_path.into()));
+    }
+    rusqlite::Connection::open(db_path).map_err(|_| Error::InvalidOutputPath(output_path.into()))
 }
 
-fn main() -> Result<(), Error> {
-    // Read JSON data from file specified in first command-line argument or from stdin
-    let args: Vec<String> = std::env::args().collect();
-    let json_data = if args.len() > 1 {
-        read_data_from_file(&args[1])?
+fn open_db_read_only(db_path: &str) -> Result<rusqlite::Connection, Error> {
+    if !Path::new(db_path).exists() {
+        return Err(Error::InvalidInputPath(db_path.into()));
+    }
+    rusqlite::Connection::open_with_flags(
+        db_path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY
+    ).map_err(|e| Error::InvalidDatabase(format!("{}", e)))
+}
+
+/// Runs a single simulation, writing output to the DB at `output_path`
+/// (or an in-memory DB if not specified):
+///
+/// sirsim [run] [<config_path>] [<config options>] [--stdout]
+fn run_simulation(args: &[String]) -> Result<(), Error> {
+    let args = Args::parse(args, CONFIG_OPTIONS, &["--stdout"])?;
+    args.check_positional(0, 1)?;
+    let mut config = read_config(&args)?;
+    if args.has_flag("--stdout") {
+        config.write_to_stdout = Some(true);
+    }
+    config.validate()?;
+    if config.output_path.is_none() && config.record_all_events {
+        return Err(Error::InvalidArgument("record_all_events requires an output path".into()));
     }
-    else {
-        read_data_from_stdin()?
-    };
     
-    // Read config from JSON data
-    let config: Config = serde_json::from_str(&json_data).unwrap();
-    let (
-        states,
-        susceptible_state_id,
-        initial_infected_state_id,
-        initial_counts
-    ) = parse_states(&config);
-    let (t_change, beta_t, C_t) = parse_contact_parameters(config.contact_parameters);
+    // If conditioning on establishment, find a seed that establishes before recording anything
+    let establishment = match &config.establishment {
+        Some(establishment) => {
+            let result = SimulationBuilder::new(config.clone()).find_established_seed(establishment)?;
+            eprintln!("Establishment: {} attempt(s), established = {}", result.n_attempts(), result.established);
+            if !result.established {
+                return Err(Error::EstablishmentFailed { n_attempts: result.n_attempts() });
+            }
+            config.rng_seed = result.seeds.last().copied();
+            Some(result)
+        },
+        None => None,
+    };
+    let builder = SimulationBuilder::new(config.clone());
     
-    // If we were given a config file, use its parent as our working directory
-    if args.len() > 1 {
-        std::env::set_current_dir(&Path::new(&args[1]).parent().unwrap()).unwrap();
-    }
+    set_config_working_directory(&args)?;
     
     // Write to DB file specified in config file
     // (or use in-memory database if not specified)
-    let mut db_connection = match config.output_path {
-        Some(output_path) => {
-            let db_path: PathBuf = output_path.into();
-            assert!(!db_path.exists());
-            rusqlite::Connection::open(db_path).unwrap()
-        },
-        None => {
-            assert!(!config.record_all_events);
-            rusqlite::Connection::open_in_memory().unwrap()
-        }
+    let db_connection = match &config.output_path {
+        Some(output_path) => create_db(output_path)?,
+        None => rusqlite::Connection::open_in_memory().unwrap(),
     };
+    let recorder = Rc::new(RefCell::new(
+        db::SqliteRecorder::new(db_connection, config.record_all_events, config.strata.clone())
+    ));
     
-    let mut sim = {
-        let mut db_transaction = db_connection.transaction().unwrap();
-        let sim = Simulation::new(
-            config.n_ageclasses,
-            states,
-            susceptible_state_id,
-            initial_infected_state_id,
-            t_change,
-            beta_t,
-            C_t,
-            initial_counts,
-            config.rng_seed,
-            &mut db_transaction,
-            config.record_all_events,
-        );
-        sim.write_counts(&mut db_transaction);
-        db_transaction.commit().unwrap();
-        sim
-    };
+    let mut sim = builder
+        .observer(recorder.clone())
+        .observer(Rc::new(RefCell::new(InterventionLogger)))
+        .build()?;
+    if let Some(result) = &establishment {
+        let conn = recorder.borrow();
+        let conn = conn.connection();
+        db::write_meta_entry(conn, "master_rng_seed", db::seed_to_sql(result.master_rng_seed));
+        db::write_meta_entry(conn, "establishment_attempts", to_i64(result.n_attempts()));
+        db::write_meta_entry(conn, "establishment_seeds", serde_json::to_string(&result.seeds).unwrap());
+    }
+    db::write_provenance(
+        recorder.borrow().connection(), &Config { rng_seed: Some(sim.rng_seed()), ..config.clone() }
+    );
+    db::write_ageclasses(recorder.borrow().connection(), &config);
+    recorder.borrow_mut().start(&sim);
     
     let start = Instant::now();
     let t_final = config.t_final.unwrap_or(INFINITY);
     eprintln!("t = {}", sim.t);
     let mut done = false;
     while sim.t < t_final && !done {
-        let mut db_transaction = db_connection.transaction().unwrap();
-        done = sim.simulate(sim.t + 1.0, &mut db_transaction, config.record_all_events);
-        sim.write_counts(&mut db_transaction);
-        db_transaction.commit().unwrap();
+        done = sim.simulate(sim.t + 1.0);
         eprintln!("t = {}", sim.t);
     }
+    recorder.borrow_mut().finish(&sim);
     eprintln!("elapsed time: {} s", start.elapsed().as_secs_f64());
     
     eprintln!("...done.");
@@ -132,14 +318,25 @@ fn main() -> Result<(), Error> {
     if config.write_to_stdout.unwrap_or(false) {
         eprintln!("Writing DB to stdout in JSON format...");
         
+        let mut count_columns = vec!["time".to_string(), "state".into()];
+        count_columns.extend(db::stratum_column_names(config.strata.as_deref()));
+        count_columns.push("count".into());
+        let mut ageclass_columns = vec!["ageclass".to_string(), "label".into(), "lower_bound".into(), "upper_bound".into()];
+        if config.strata.is_some() {
+            ageclass_columns.extend(db::stratum_column_names(config.strata.as_deref()));
+        }
         let db_json_data = serde_json::Map::from_iter(vec![
             ("Meta", vec!["key", "value"]),
-            ("Counts", vec!["time", "state", "ageclass", "count"]),
+            ("Ageclasses", ageclass_columns.iter().map(|c| c.as_str()).collect()),
+            ("Counts", count_columns.iter().map(|c| c.as_str()).collect()),
+            ("AttributeCounts", vec!["time", "attribute", "value", "state", "count"]),
             ("RtSufficientStatistics", vec!["time_discrete", "n_primary", "n_secondary"]),
+            ("GenerationIntervals", vec!["time_discrete", "interval", "count"]),
+            ("SerialIntervals", vec!["time_discrete", "interval", "count"]),
         ].iter().map(|(table_name, col_names)| {
             (
                 String::from(*table_name),
-                db_table_to_json_object(&db_connection, table_name, col_names)
+                db_table_to_json_object(recorder.borrow().connection(), table_name, col_names)
             )
         }).collect::<Vec<_>>());
         
@@ -149,103 +346,257 @@ fn main() -> Result<(), Error> {
     Ok(())
 }
 
-fn parse_states(config: &Config) -> (Vec<State>, usize, usize, Counts) {
-    let mut states = Vec::new();
-    let mut n_states: usize = 0;
-    let mut name_id_map = HashMap::new();
+/// Reports changes of intervention period to stderr.
+struct InterventionLogger;
+
+impl SimulationObserver for InterventionLogger {
+    fn on_intervention_change(&mut self, t: f64, intervention_index: usize) {
+        eprintln!("Updated intervention to {} at t = {}", intervention_index, t);
+    }
+}
+
+/// Checks a config, printing `{"Ok": null}` if it is valid:
+///
+/// sirsim validate [<config_path>] [<config options>]
+fn validate(args: &[String]) -> Result<(), Error> {
+    let args = Args::parse(args, CONFIG_OPTIONS, &[])?;
+    args.check_positional(0, 1)?;
+    read_config(&args)?.validate()?;
+    println!("{}", serde_json::to_string_pretty(&Ok::<(), Error>(())).unwrap());
+    Ok(())
+}
+
+/// Runs replicate simulations with seeds derived from the config's seed, writing
+/// counts and statistics for all replicates to a single DB, keyed by replicate (from 1):
+///
+/// sirsim ensemble [<config_path>] --replicates <n> [<config options>]
+fn ensemble(args: &[String]) -> Result<(), Error> {
+    let mut option_names = CONFIG_OPTIONS.to_vec();
+    option_names.push("--replicates");
+    let args = Args::parse(args, &option_names, &[])?;
+    args.check_positional(0, 1)?;
+    let n_replicates: usize = args.parsed_value("--replicates")?.ok_or_else(
+        || Error::InvalidArgument("--replicates is required".into())
+    )?;
+    let config = read_config(&args)?;
+    config.validate()?;
+    let output_path = config.output_path.clone().ok_or_else(
+        || Error::InvalidArgument("ensemble requires an output path".into())
+    )?;
     
-    // Susceptible state
-    let susceptible_state_id = n_states;
-    name_id_map.insert(config.susceptible_state.clone(), susceptible_state_id);
-    states.push(State::new_susceptible(susceptible_state_id, config.susceptible_state.clone()));
-    n_states += 1;
+    let strata = config.strata.clone();
+    let builder = SimulationBuilder::new(config).record_all_events(false);
+    let (master_rng_seed, rng_seeds) = builder.replicate_rng_seeds(n_replicates);
     
-    // Final states
-    for name in &config.final_states {
-        name_id_map.insert(name.clone(), n_states);
-        states.push(State::new_final(n_states, name.clone()));
-        n_states += 1;
-    }
+    set_config_working_directory(&args)?;
+    let conn = create_db(&output_path)?;
+    conn.execute_batch("BEGIN;").unwrap();
+    db::create_multi_run_tables(&conn, &["replicate"], strata.as_deref());
+    db::write_provenance(&conn, builder.clone_with(master_rng_seed).config());
+    db::write_ageclasses(&conn, builder.config());
+    db::write_meta_entry(&conn, "master_rng_seed", db::seed_to_sql(master_rng_seed));
+    db::write_meta_entry(&conn, "n_replicates", to_i64(n_replicates));
+    conn.execute_batch("COMMIT;").unwrap();
     
-    // Infected states, without transitions
-    for state_config in &config.infected_states {
-        name_id_map.insert(state_config.name.clone(), n_states);
-        states.push(State::new_infected(
-            n_states, state_config.name.clone(),
-        ));
-        n_states += 1;
+    let start = Instant::now();
+    for (i, rng_seed) in rng_seeds.into_iter().enumerate() {
+        let output = builder.clone_with(rng_seed).run()?;
+        if let Some(establishment) = &output.establishment {
+            if !establishment.established {
+                return Err(Error::EstablishmentFailed { n_attempts: establishment.n_attempts() });
+            }
+        }
+        conn.execute_batch("BEGIN;").unwrap();
+        db::write_multi_run_output(&conn, &[to_i64(i + 1)], &output, strata.as_deref());
+        conn.execute_batch("COMMIT;").unwrap();
+        eprintln!("replicate {} (rng_seed = {})", i + 1, output.rng_seed);
     }
+    db::write_end_time(&conn);
+    eprintln!("elapsed time: {} s", start.elapsed().as_secs_f64());
     
-    // Add transitions, resolving to state IDs
-    for state_config in &config.infected_states {
-        let id = name_id_map[&state_config.name];
-        
-        let next_state_ids = state_config.next_states.iter().map(
-            |name| name_id_map[name]
-        ).collect();
-        
-        let transition_probabilities = match &state_config.probabilities {
-            Some(probabilities) => {
-                probabilities.clone()
-            },
-            None => {
-                std::iter::repeat(Vec::new()).take(config.n_ageclasses).collect()
-            }
-        };
-        
-        let transition_cdfs = transition_probabilities.iter().map(|tprobs| {
-            cumulative_sum(tprobs)
-        }).collect();
-        
-        states[id].detail = StateDetail::Infected(Some(InfectedState {
-            infectious: state_config.infectious,
-            mean_duration: state_config.mean_duration,
-            gamma_shape: state_config.gamma_shape,
-            next_state_ids: next_state_ids,
-            transition_cdfs: transition_cdfs,
-        }))
+    Ok(())
+}
+
+/// Runs a parameter sweep, writing a `Design` table and outputs keyed by design point
+/// and replicate (from 1) to a single DB. The sweep spec may be JSON, YAML or TOML:
+///
+/// sirsim sweep [<config_path>] --spec <sweep_path> [--replicates <n>] [--threads <n>]
+///     [<config options>]
+fn sweep(args: &[String]) -> Result<(), Error> {
+    let mut option_names = CONFIG_OPTIONS.to_vec();
+    option_names.extend(&["--spec", "--replicates", "--threads"]);
+    let args = Args::parse(args, &option_names, &[])?;
+    args.check_positional(0, 1)?;
+    let spec_path = args.value("--spec").ok_or_else(
+        || Error::InvalidArgument("--spec is required".into())
+    )?;
+    let mut spec: SweepSpec = read_input(Some(spec_path), None)?;
+    if let Some(replicates) = args.parsed_value("--replicates")? {
+        spec.replicates = Some(replicates);
     }
+    let n_threads = args.parsed_value("--threads")?;
+    let config = read_config(&args)?;
+    config.validate()?;
+    let output_path = config.output_path.clone().ok_or_else(
+        || Error::InvalidArgument("sweep requires an output path".into())
+    )?;
     
-    let initial_infected_state_id = name_id_map[&config.initial_infected_state];
+    set_config_working_directory(&args)?;
+    let conn = create_db(&output_path)?;
+    let start = Instant::now();
+    run_sweep(&config, &spec, n_threads, &conn, &mut |n_done, n_total| {
+        eprintln!("{} / {} runs", n_done, n_total);
+    })?;
+    eprintln!("elapsed time: {} s", start.elapsed().as_secs_f64());
     
-    let initial_counts = parse_initial_counts(
-        states.len(), config.n_ageclasses,
-        &name_id_map, &config.initial_counts
-    );
+    Ok(())
+}
+
+/// Fits the config to observed time series by ABC-SMC, printing weighted posterior particles
+/// and per-generation diagnostics as JSON. Nothing is written to the config's output path.
+///
+/// sirsim abc [<config_path>] --spec <abc_path> [--threads <n>] [<config options>]
+fn abc(args: &[String]) -> Result<(), Error> {
+    let mut option_names = CONFIG_OPTIONS.to_vec();
+    option_names.extend(&["--spec", "--threads"]);
+    let args = Args::parse(args, &option_names, &[])?;
+    args.check_positional(0, 1)?;
+    let spec_path = args.value("--spec").ok_or_else(
+        || Error::InvalidArgument("--spec is required".into())
+    )?;
+    let spec: AbcSpec = read_input(Some(spec_path), None)?;
+    let n_threads = args.parsed_value("--threads")?;
+    let config = read_config(&args)?;
+    config.validate()?;
+    
+    let start = Instant::now();
+    let result = run_abc(&config, &spec, n_threads, &mut |g| {
+        eprintln!(
+            "generation {}: tolerance = {}, {} simulations", g.generation, g.tolerance, g.n_simulations
+        );
+    })?;
+    eprintln!("elapsed time: {} s", start.elapsed().as_secs_f64());
+    println!("{}", serde_json::to_string_pretty(&result).unwrap());
     
-    (states, susceptible_state_id, initial_infected_state_id, initial_counts)
+    Ok(())
 }
 
-fn parse_initial_counts(
-    n_states: usize, n_ageclasses: usize,
-    name_id_map: &HashMap<String, usize>, counts_raw: &HashMap<String, Vec<usize>>
-) -> Counts {
-    let mut counts = Counts::new(n_states, n_ageclasses);
-    for (state_name, counts_for_state) in counts_raw {
-        for i in 0..n_ageclasses {
-            counts.increment(name_id_map[state_name], i, counts_for_state[i]);
-        }
+/// Estimates the likelihood of observed time series with a bootstrap particle filter,
+/// printing the log-likelihood and filtered trajectories as JSON. Particles are seeded
+/// from the config's rng_seed (or --seed).
+///
+/// sirsim particle-filter [<config_path>] --spec <filter_path> [<config options>]
+fn particle_filter(args: &[String]) -> Result<(), Error> {
+    let mut option_names = CONFIG_OPTIONS.to_vec();
+    option_names.push("--spec");
+    let args = Args::parse(args, &option_names, &[])?;
+    args.check_positional(0, 1)?;
+    let spec_path = args.value("--spec").ok_or_else(
+        || Error::InvalidArgument("--spec is required".into())
+    )?;
+    let spec: ParticleFilterSpec = read_input(Some(spec_path), None)?;
+    let config = read_config(&args)?;
+    config.validate()?;
+    
+    let start = Instant::now();
+    let result = run_particle_filter(&config, &spec)?;
+    eprintln!("elapsed time: {} s", start.elapsed().as_secs_f64());
+    println!("{}", serde_json::to_string_pretty(&result).unwrap());
+    
+    Ok(())
+}
+
+/// Prints summary statistics for each run in a DB as JSON:
+///
+/// sirsim summarize <db_path>
+fn summarize(args: &[String]) -> Result<(), Error> {
+    let args = Args::parse(args, &[], &[])?;
+    args.check_positional(1, 1)?;
+    let conn = open_db_read_only(&args.positional[0])?;
+    let summary = DbSummary::read_from_db(&conn)?;
+    println!("{}", serde_json::to_string_pretty(&summary).unwrap());
+    Ok(())
+}
+
+/// Exports tables of a DB (all of them by default) as CSV files named
+/// `<output_prefix>_<table>.csv`:
+///
+/// sirsim export <db_path> <output_prefix> [--table <name>]...
+fn export(args: &[String]) -> Result<(), Error> {
+    let args = Args::parse(args, &["--table"], &[])?;
+    args.check_positional(2, 2)?;
+    let db_connection = open_db_read_only(&args.positional[0])?;
+    for path in db::export_tables(&db_connection, &args.positional[1], &args.values("--table"))? {
+        eprintln!("wrote {}", path);
     }
-    counts
+    Ok(())
 }
 
-fn parse_contact_parameters(cp_vec: Vec<ContactParameters>) -> (Vec<f64>, Vec<f64>, Vec<Vec<Vec<f64>>>) {
-    let mut t_change = Vec::new();
-    let mut beta_t = Vec::new();
-    let mut C_t = Vec::new();
+/// Exports the transmission tree from a run DB:
+///
+/// sirsim export-tree <db_path> <output_prefix>
+///     [--format newick|graphml|csv]... [--sample-fraction <fraction>] [--seed <seed>]
+fn export_tree(args: &[String]) -> Result<(), Error> {
+    let args = Args::parse(args, &["--format", "--sample-fraction", "--seed"], &[])?;
+    args.check_positional(2, 2)?;
+    let db_path = &args.positional[0];
+    let output_prefix = &args.positional[1];
     
-    for i in 0..cp_vec.len() {
-        beta_t.push(cp_vec[i].beta);
-        C_t.push(cp_vec[i].C.clone());
-        
-        if let Some(t_end) = cp_vec[i].t_end {
-            assert!(i < cp_vec.len() - 1);
-            t_change.push(t_end);
-        }
-        else {
-            assert!(i == cp_vec.len() - 1);
+    let mut formats = args.values("--format").into_iter().map(
+        TreeFormat::parse
+    ).collect::<Result<Vec<_>, _>>()?;
+    if formats.is_empty() {
+        formats = TreeFormat::all();
+    }
+    let sample_fraction: Option<f64> = args.parsed_value("--sample-fraction")?;
+    if let Some(fraction) = sample_fraction {
+        if !(fraction > 0.0 && fraction <= 1.0) {
+            return Err(Error::InvalidArgument(format!("sample fraction must be in (0, 1]: {}", fraction)));
         }
     }
+    let seed = args.parsed_value("--seed")?.unwrap_or(0);
+    
+    let db_connection = open_db_read_only(db_path)?;
+    let mut tree = TransmissionTree::read_from_db(&db_connection)?;
+    if let Some(fraction) = sample_fraction {
+        tree = tree.subsample(fraction, seed);
+    }
+    tree.write_files(output_prefix, &formats)?;
+    
+    Ok(())
+}
+
+/// Prints the branching-process approximation for a config as JSON:
+///
+/// sirsim branching-process [<config_path>] [--contact-period <index>] [<config options>]
+fn branching_process(args: &[String]) -> Result<(), Error> {
+    let mut option_names = CONFIG_OPTIONS.to_vec();
+    option_names.push("--contact-period");
+    let args = Args::parse(args, &option_names, &[])?;
+    args.check_positional(0, 1)?;
+    let contact_period = args.parsed_value("--contact-period")?.unwrap_or(0);
     
-    (t_change, beta_t, C_t)
+    let config = read_config(&args)?;
+    let result = BranchingProcess::from_config(&config, contact_period)?.solve();
+    println!("{}", serde_json::to_string_pretty(&result).unwrap());
+    
+    Ok(())
+}
+
+/// Prints the dominant eigenvalue, reciprocity violations and R0 of each contact matrix
+/// as JSON, with the beta that would give a target R0:
+///
+/// sirsim contact-matrices [<config_path>] [--target-r0 <R0>] [<config options>]
+fn contact_matrices(args: &[String]) -> Result<(), Error> {
+    let mut option_names = CONFIG_OPTIONS.to_vec();
+    option_names.push("--target-r0");
+    let args = Args::parse(args, &option_names, &[])?;
+    args.check_positional(0, 1)?;
+    let target_R0: Option<f64> = args.parsed_value("--target-r0")?;
+    
+    let config = read_config(&args)?;
+    let reports = config.contact_matrix_reports(target_R0)?;
+    println!("{}", serde_json::to_string_pretty(&reports).unwrap());
+    
+    Ok(())
 }
@@ -5,6 +5,13 @@ use std::io::Read;
 use sirtools::spec::*;
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() > 1 && args[1] == "schema" {
+        let schema = sirtools::stan::InputData::json_schema();
+        println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+        return;
+    }
+    
     let result = sirtools::stan::run();
     
     let output_json = serde_json::to_string_pretty(&result).unwrap();